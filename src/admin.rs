@@ -0,0 +1,108 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_http::h1;
+use actix_web::body::BoxBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::web::BytesMut;
+use actix_web::{http::header, web, HttpMessage, HttpRequest, HttpResponse};
+use futures_util::StreamExt;
+use log::{error, info, warn};
+use serde_json::json;
+use tokio::time;
+
+use crate::regenerate::Regenerator;
+
+// Control requests carry no meaningful body, so anything larger is refused
+const MAX_BODY_SIZE: usize = 4096;
+// How long a client may take to send the body of a control request
+const BODY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Token-protected control endpoints, enabled in `host` mode with `--admin-token`.
+#[derive(Clone)]
+pub struct AdminApi {
+    token: String,
+    regenerator: Arc<Regenerator>,
+}
+
+impl AdminApi {
+    pub fn new(token: String, regenerator: Arc<Regenerator>) -> Self {
+        AdminApi { token, regenerator }
+    }
+
+    fn is_authorized(&self, req: &HttpRequest) -> bool {
+        let provided = req.headers().get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        // Compare without short-circuiting so timing does not reveal the matching prefix
+        provided.len() == self.token.len()
+            && provided.bytes().zip(self.token.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+}
+
+/// `POST /admin/regenerate`: runs generation now and returns the entry count per category.
+///
+/// Answers 202 if a run is already in progress; that run picks up this request.
+pub async fn regenerate(admin: web::Data<AdminApi>, req: HttpRequest) -> HttpResponse {
+    if !admin.is_authorized(&req) {
+        warn!("Rejected unauthorized admin request from {:?}", req.peer_addr());
+        return HttpResponse::Unauthorized().body("401 - Unauthorized");
+    }
+
+    match admin.regenerator.trigger().await {
+        Some(Ok(counts)) => {
+            info!("Regenerated JSON files on admin request");
+            HttpResponse::Ok().json(json!({ "counts": counts }))
+        }
+        Some(Err(e)) => {
+            error!("Admin-triggered regeneration failed: {:?}", e);
+            HttpResponse::InternalServerError().json(json!({ "error": format!("{:#}", e) }))
+        }
+        None => HttpResponse::Accepted().json(json!({ "status": "in_progress" })),
+    }
+}
+
+/// Middleware for the admin routes: reads the request body up front, answering 413 when it exceeds
+/// `MAX_BODY_SIZE` and 408 when the client does not send it within `BODY_TIMEOUT`, so a control request
+/// cannot hold a connection open. Other routes (e.g. large downloads) are not wrapped.
+pub async fn limit_request(mut req: ServiceRequest, next: Next<BoxBody>) -> actix_web::Result<ServiceResponse<BoxBody>> {
+    let declared = req.headers().get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if declared.is_some_and(|length| length > MAX_BODY_SIZE) {
+        warn!("Rejected oversized admin request from {:?}", req.peer_addr());
+        return Ok(req.into_response(HttpResponse::PayloadTooLarge().body("413 - Payload Too Large")));
+    }
+
+    let mut payload = req.take_payload();
+    let read = time::timeout(BODY_TIMEOUT, async {
+        let mut body = BytesMut::new();
+        while let Some(chunk) = payload.next().await {
+            body.extend_from_slice(&chunk?);
+            if body.len() > MAX_BODY_SIZE {
+                return Ok(None);
+            }
+        }
+        Ok::<_, actix_web::error::PayloadError>(Some(body.freeze()))
+    }).await;
+    let body = match read {
+        Ok(Ok(Some(body))) => body,
+        Ok(Ok(None)) => {
+            warn!("Rejected oversized admin request from {:?}", req.peer_addr());
+            return Ok(req.into_response(HttpResponse::PayloadTooLarge().body("413 - Payload Too Large")));
+        }
+        Ok(Err(e)) => return Err(e.into()),
+        Err(_) => {
+            warn!("Timed out reading admin request from {:?}", req.peer_addr());
+            return Ok(req.into_response(HttpResponse::RequestTimeout().body("408 - Request Timeout")));
+        }
+    };
+
+    // Hand the buffered body on, in case a handler reads it
+    let (_, mut buffered) = h1::Payload::create(true);
+    buffered.unread_data(body);
+    req.set_payload(buffered.into());
+    next.call(req).await
+}
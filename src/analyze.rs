@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use serde_json::json;
+use tokio_util::sync::CancellationToken;
+
+use crate::args::GenerateArgs;
+use crate::index::{FpkgiIndex, IndexedPackage};
+
+/// A package with a known size, as listed among the largest and smallest.
+struct SizedPackage {
+    name: String,
+    url: String,
+    size: u64,
+}
+
+/// Composition of a library, computed from the entries `generate` produces.
+#[derive(Default)]
+struct Stats {
+    total: usize,
+    total_size: u64,
+    by_category: BTreeMap<String, usize>,
+    by_region: BTreeMap<String, usize>,
+    largest: Option<SizedPackage>,
+    smallest: Option<SizedPackage>,
+    missing_icons: usize,
+    missing_min_fw: usize,
+}
+
+impl Stats {
+    fn add(&mut self, package: IndexedPackage) {
+        let entry = package.entry;
+        self.total += 1;
+        *self.by_category.entry(package.category.to_string()).or_default() += 1;
+        *self.by_region.entry(entry.region.clone().unwrap_or_else(|| "UNK".to_string())).or_default() += 1;
+        if entry.cover_url.is_none() {
+            self.missing_icons += 1;
+        }
+        if entry.min_fw.is_none() {
+            self.missing_min_fw += 1;
+        }
+
+        let Some(size) = entry.size.as_ref().and_then(|size| size.bytes()) else { return };
+        self.total_size += size;
+        let sized = || SizedPackage {
+            name: entry.name.clone().unwrap_or_else(|| package.url.to_string()),
+            url: package.url.to_string(),
+            size,
+        };
+        if self.largest.as_ref().is_none_or(|largest| size > largest.size) {
+            self.largest = Some(sized());
+        }
+        if self.smallest.as_ref().is_none_or(|smallest| size < smallest.size) {
+            self.smallest = Some(sized());
+        }
+    }
+}
+
+/// Indexes the library like `generate` and prints its composition: counts per category and region, total size,
+/// the largest and smallest packages, and how many entries lack an icon or firmware info.
+///
+/// Nothing is written: the category files are not generated and icons go to a temporary directory
+/// (only to tell which packages have one) that is removed afterwards.
+pub async fn run_analyze(args: GenerateArgs, as_json: bool) -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let args = GenerateArgs {
+        icons: Some((temp_dir.path().to_path_buf(), "icons".to_string())),
+        icon_cache: None,
+        icon_max_size: None,
+        clean_icons: false,
+        dedup_icons: false,
+        report: None,
+        ..args
+    };
+    let index = FpkgiIndex::generate(args, CancellationToken::new()).await;
+    drop(temp_dir);
+
+    let mut stats = Stats::default();
+    for package in index?.entries() {
+        stats.add(package);
+    }
+
+    if as_json {
+        let package = |package: &Option<SizedPackage>| {
+            package.as_ref().map(|package| json!({"name": package.name, "url": package.url, "size": package.size}))
+        };
+        let report = json!({
+            "packages": stats.total,
+            "total_size": stats.total_size,
+            "by_category": stats.by_category,
+            "by_region": stats.by_region,
+            "largest": package(&stats.largest),
+            "smallest": package(&stats.smallest),
+            "missing_icons": stats.missing_icons,
+            "missing_min_fw": stats.missing_min_fw,
+        });
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    println!("Packages: {} ({} bytes)", stats.total, stats.total_size);
+    for (title, counts) in [("By category", &stats.by_category), ("By region", &stats.by_region)] {
+        println!("{}:", title);
+        for (name, count) in counts {
+            println!("  {:<14} {}", name, count);
+        }
+    }
+    for (title, package) in [("Largest", &stats.largest), ("Smallest", &stats.smallest)] {
+        if let Some(package) = package {
+            println!("{}: {} ({} bytes) {}", title, package.name, package.size, package.url);
+        }
+    }
+    println!("Missing icons: {}", stats.missing_icons);
+    println!("Missing firmware info: {}", stats.missing_min_fw);
+    Ok(())
+}
@@ -1,28 +1,427 @@
-use std::path::PathBuf;
+use std::path::{Component, PathBuf};
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+
+/// Placeholders accepted by `--package-url-template`.
+pub const URL_TEMPLATE_PLACEHOLDERS: &[&str] = &["base_url", "rel_path", "filename", "title_id", "region", "content_id"];
+
+/// Character set percent-encoded in generated package and icon URLs.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum UrlEncodeMode {
+    /// Encode everything except alphanumerics, `-._~` and `/`
+    Strict,
+    /// Encode only control characters and spaces
+    Minimal,
+    /// Encode characters outside the RFC 3986 path set
+    Rfc3986,
+}
+
+/// Which side wins when `--external` JSON and generated data set the same field.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum MergePrecedence {
+    /// Freshly parsed values win; external JSON only fills fields that are missing or null
+    Generated,
+    /// External JSON overrides generated values
+    External,
+}
+
+/// Top-level structure of the generated category JSON files.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum JsonWrapper {
+    /// Entries at the top level, keyed by package URL
+    None,
+    /// Entries under `DATA`, as FPKGi expects
+    Data,
+    /// Entries under the key given with `--json-wrapper-key`
+    Custom,
+}
+
+#[derive(Debug, Parser, Clone)]
+pub struct ServerArgs {
+    /// Port to run server on (default: 8000); 0 lets the OS pick a free port, which is logged once bound
+    #[arg(long, env = "FPKGI_PORT", default_value_t = 8000)]
+    pub port: u16,
+
+    /// Listen on a Unix domain socket instead of a TCP port
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["port", "tls_cert"])]
+    pub unix_socket: Option<PathBuf>,
+
+    /// Maximum concurrent .pkg downloads per client IP (default: unlimited)
+    #[arg(long, value_name = "N")]
+    pub max_concurrent_downloads: Option<usize>,
+
+    /// Seconds an idle keep-alive connection stays open between requests (0 disables keep-alive)
+    #[arg(long, value_name = "SECS", default_value_t = 75)]
+    pub keep_alive: u64,
+
+    /// Seconds a client has to send its request headers (0 disables); does not limit download time
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    pub client_timeout: u64,
+
+    /// HTML template for the index and directory listing pages (placeholders: {{title}}, {{path}},
+    /// and a {{#entries}}...{{/entries}} block with {{name}}, {{href}}, {{size}})
+    #[arg(long, value_name = "FILE")]
+    pub template: Option<PathBuf>,
+
+    /// After binding, print one JSON line to stdout describing the listening address and directories
+    #[arg(long)]
+    pub print_listen_info: bool,
+
+    /// Serve `/favicon.ico` and `/_assets/<file>` from this directory, falling back to the built-in assets
+    #[arg(long, value_name = "DIR")]
+    pub assets_dir: Option<PathBuf>,
+
+    /// PEM certificate chain; together with `--tls-key`, serves HTTPS on `--port` (or `--tls-port`)
+    #[arg(long, value_name = "FILE", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for `--tls-cert`
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Serve HTTPS on this port while keeping plain HTTP on `--port` (requires `--tls-cert`)
+    #[arg(long, value_name = "PORT", requires = "tls_cert")]
+    pub tls_port: Option<u16>,
+
+    /// Only offer HTTP/1.1 over TLS; by default HTTP/2 is negotiated via ALPN when the client supports it
+    #[arg(long, requires = "tls_cert")]
+    pub no_http2: bool,
+
+    /// External path prefix under which a reverse proxy exposes the server (e.g. `/fpkgi`), used in listing
+    /// links and redirects; requests are accepted with or without it
+    #[arg(long, value_name = "PREFIX", value_parser = parse_path_prefix)]
+    pub path_prefix: Option<String>,
+
+    /// Use actix-files' built-in directory listing instead of the custom listing pages and redirects
+    #[arg(long)]
+    pub native_listing: bool,
+
+    /// Subdirectory to leave out of the listing pages, relative to each served directory (repeatable);
+    /// its files are still served by direct URL
+    #[arg(long = "hide-dir", value_name = "RELPATH", value_parser = parse_hidden_dir, conflicts_with = "native_listing")]
+    pub hidden_dirs: Vec<PathBuf>,
+}
 
 #[derive(Debug, Parser, Clone)]
 pub struct GenerateArgs {
     /// Packages directory in format "fs_path:url_path"
-    #[arg(long, value_parser = split_path_arg)]
+    #[arg(long, env = "FPKGI_PACKAGES", value_parser = split_path_arg)]
     pub packages: (PathBuf, String),
 
+    /// Package file extensions to index, case-insensitive (repeatable or comma-separated)
+    #[arg(long = "ext", value_name = "EXT", value_delimiter = ',', default_values_t = ["pkg".to_string(), "fpkg".to_string(), "vpk".to_string()])]
+    pub extensions: Vec<String>,
+
+    /// File extensions to skip even if listed in `--ext` (repeatable or comma-separated)
+    #[arg(long = "exclude-ext", value_name = "EXT", value_delimiter = ',')]
+    pub exclude_extensions: Vec<String>,
+
     /// Base URL for package links
-    #[arg(long)]
+    #[arg(long, env = "FPKGI_URL", required_unless_present = "local_paths", default_value = "")]
     pub url: String,
 
+    /// Reference packages and icons by local path instead of URL, optionally under PREFIX
+    /// (e.g. for FPKGi reading JSON and packages from a USB drive)
+    #[arg(long, value_name = "PREFIX", num_args = 0..=1, conflicts_with = "url")]
+    pub local_paths: Option<Option<String>>,
+
+    /// Sign package links under `--url` with an expiring HMAC (`expires` and `sig` query parameters);
+    /// in `host` mode the server then refuses package requests without a valid signature
+    #[arg(long, value_name = "SECRET", env = "FPKGI_SIGN_SECRET", hide_env_values = true, conflicts_with = "local_paths")]
+    pub sign_secret: Option<String>,
+
+    /// Seconds signed links stay valid: at least this long and at most twice as long after generation
+    #[arg(long, value_name = "SECS", default_value_t = 86400, value_parser = clap::value_parser!(u64).range(2..))]
+    pub sign_ttl: u64,
+
     /// Output directory in format "fs_path:url_path"
-    #[arg(long, value_parser = split_path_arg)]
+    #[arg(long, env = "FPKGI_OUT", value_parser = split_path_arg)]
     pub out: (PathBuf, String),
 
     /// Optional icons directory in format "fs_path:url_path"
-    #[arg(long, value_parser = split_path_arg)]
+    #[arg(long, env = "FPKGI_ICONS", value_parser = split_path_arg)]
     pub icons: Option<(PathBuf, String)>,
 
+    /// Downscale extracted icons to fit within this many pixels (aspect ratio preserved)
+    #[arg(long, value_name = "PX", requires = "icons")]
+    pub icon_max_size: Option<u32>,
+
+    /// When resizing icons, also keep the full-size original as `<name>.full.png`
+    #[arg(long, requires = "icon_max_size")]
+    pub keep_full_icons: bool,
+
+    /// Replace byte-identical extracted icons with hardlinks to a single copy
+    #[arg(long, requires = "icons")]
+    pub dedup_icons: bool,
+
+    /// Delete `<package>.png` icons under the icons directory whose package is no longer indexed
+    #[arg(long, requires = "icons")]
+    pub clean_icons: bool,
+
+    /// Reuse extracted icons across runs from this cache directory, keyed by content id and header digest
+    #[arg(long, value_name = "DIR", requires = "icons", conflicts_with = "keep_full_icons")]
+    pub icon_cache: Option<PathBuf>,
+
+    /// Write `cover_url` relative to the package URL instead of absolute (e.g. for icons stored next to packages)
+    #[arg(long, requires = "icons")]
+    pub relative_icon_urls: bool,
+
+    /// Also index remote packages listed in this file (one URL per line), reading only the needed byte ranges
+    #[arg(long, value_name = "FILE")]
+    pub remote_manifest: Option<PathBuf>,
+
+    /// Base URL that relative `--remote-manifest` entries are resolved against
+    #[arg(long, value_name = "URL", requires = "remote_manifest")]
+    pub remote_base: Option<String>,
+
     /// Optional external directory containing JSON files to merge
     #[arg(long)]
     pub external: Option<PathBuf>,
+
+    /// Whether generated or `--external` values win when both set a field
+    #[arg(long, value_enum, default_value_t = MergePrecedence::External, requires = "external")]
+    pub merge_precedence: MergePrecedence,
+
+    /// Point the `url`s and `cover_url`s of `--external` entries at the scheme, host and port of `--url`,
+    /// keeping their paths (for external JSON generated for another address)
+    #[arg(long, requires = "external", conflicts_with = "local_paths")]
+    pub rewrite_external_hosts: bool,
+
+    /// Copy an extra SFO value into each entry, as `KEY` or `KEY:json_name` (default name: lowercased key; repeatable)
+    #[arg(long = "sfo-key", value_name = "KEY[:NAME]", value_parser = parse_sfo_key)]
+    pub sfo_keys: Vec<(String, String)>,
+
+    /// Emit the `size` field as a JSON string instead of a number, for FPKGi builds that expect one
+    #[arg(long)]
+    pub size_as_string: bool,
+
+    /// Take the name from the SFO's localized `TITLE_<NN>` for this locale index (0-29) when present, else `TITLE`
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u8).range(0..=29))]
+    pub title_locale: Option<u8>,
+
+    /// Collapse whitespace in titles, keeping the original under `raw_name`
+    #[arg(long)]
+    pub clean_titles: bool,
+
+    /// With `--clean-titles`, also strip ™, ® and © symbols
+    #[arg(long, requires = "clean_titles")]
+    pub strip_title_symbols: bool,
+
+    /// Warn about packages larger than this many bytes (default: the 32-bit size limit of some FPKGi builds)
+    #[arg(long, value_name = "BYTES", default_value_t = u32::MAX as u64)]
+    pub warn_size_over: u64,
+
+    /// Warn about packages whose URL is longer than this many characters, which some console HTTP clients
+    /// fail to download (0 disables)
+    #[arg(long, value_name = "CHARS", default_value_t = 1024)]
+    pub warn_url_length: usize,
+
+    /// Skip package files smaller than this many bytes (e.g. truncated downloads) without parsing them
+    #[arg(long, value_name = "BYTES")]
+    pub min_size: Option<u64>,
+
+    /// Skip package files larger than this many bytes without parsing them
+    #[arg(long, value_name = "BYTES")]
+    pub max_size: Option<u64>,
+
+    /// Measure and open up to this many local packages at once; indexing is IO-bound, so more threads than
+    /// the disk can serve in parallel only add seeking
+    #[arg(long, value_name = "N", default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..))]
+    pub concurrency: u32,
+
+    /// Build local package links from this template instead of `<url>/<packages url path>/<path>`
+    /// (placeholders: {base_url}, {rel_path}, {filename}, {title_id}, {region}, {content_id})
+    #[arg(long, value_name = "TEMPLATE", value_parser = parse_url_template)]
+    pub package_url_template: Option<String>,
+
+    /// Which characters to percent-encode in package and icon URLs
+    #[arg(long, value_enum, default_value_t = UrlEncodeMode::Strict)]
+    pub url_encode_mode: UrlEncodeMode,
+
+    /// Only index packages whose title id matches (repeatable, supports `*` and `?` wildcards)
+    #[arg(long = "include-title-id", value_name = "TITLE_ID")]
+    pub include_title_ids: Vec<String>,
+
+    /// Skip packages whose title id matches (repeatable, supports wildcards; wins over include)
+    #[arg(long = "exclude-title-id", value_name = "TITLE_ID")]
+    pub exclude_title_ids: Vec<String>,
+
+    /// Warn when two indexed packages share a content id (e.g. duplicate dumps), naming both files
+    #[arg(long)]
+    pub warn_duplicate_content_id: bool,
+
+    /// When entries of a category share a name, append their region and version to it, e.g. `Some Game (EUR) [1.00]`
+    #[arg(long)]
+    pub rename_duplicate_titles: bool,
+
+    /// Skip packages without a param.sfo instead of synthesizing an entry from the content id and filename
+    #[arg(long)]
+    pub require_sfo: bool,
+
+    /// Skip packages whose header content id disagrees with their SFO `CONTENT_ID` instead of only warning
+    #[arg(long)]
+    pub strict: bool,
+
+    /// When the SFO has no `TITLE_ID`, take it from a title-id-shaped token in the filename (e.g. `CUSA12345`)
+    #[arg(long)]
+    pub title_id_from_filename: bool,
+
+    /// When the SFO has no `APP_VER` or `VERSION`, take the version from the package's `changeinfo/changeinfo.xml`
+    #[arg(long)]
+    pub homebrew_version_fallback: bool,
+
+    /// Attach the full SFO key/value map to each entry as a nested `sfo` object
+    #[arg(long)]
+    pub include_sfo: bool,
+
+    /// Show a progress bar with counts and ETA (only when stderr is a terminal)
+    #[arg(long)]
+    pub progress: bool,
+
+    /// Remove merged entries whose package URL points at a file that no longer exists
+    #[arg(long)]
+    pub prune: bool,
+
+    /// Write category JSON files per package subfolder (mirrored under the output directory) instead of one flat set
+    #[arg(long)]
+    pub per_folder_json: bool,
+
+    /// Write a category under a different file name, as `CATEGORY=NAME` (e.g. `games=ps4_games` for `ps4_games.json`; repeatable)
+    #[arg(long = "rename-output", value_name = "CATEGORY=NAME", value_parser = parse_rename_output)]
+    pub rename_outputs: Vec<(String, String)>,
+
+    /// Force packages into categories: a file of `PATTERN = CATEGORY` lines matched against title ids and content ids
+    #[arg(long, value_name = "FILE")]
+    pub override_category: Option<PathBuf>,
+
+    /// Print all categories as one JSON document (`{"games": {"DATA": ...}, ...}`, shaped by `--json-wrapper`) to stdout instead of writing files
+    #[arg(long, conflicts_with = "per_folder_json")]
+    pub stdout: bool,
+
+    /// Also write all categories to this one JSON file for store frontends, keyed by category name with each
+    /// category's entries (its `DATA` object) as the value
+    #[arg(long, value_name = "FILE")]
+    pub store_json: Option<PathBuf>,
+
+    /// Stream entries to `<category>.ndjson` files, one JSON object per line, as packages are indexed instead of
+    /// collecting them for `.json` files (for very large libraries)
+    #[arg(long, conflicts_with_all = ["external", "prune", "dedup_icons", "per_folder_json", "stdout", "store_json", "report_orphans", "rename_duplicate_titles"])]
+    pub ndjson: bool,
+
+    /// Number of spaces to indent the generated JSON with
+    #[arg(long, value_name = "N", default_value_t = 2)]
+    pub json_indent: usize,
+
+    /// Indent the generated JSON with tabs instead of spaces
+    #[arg(long, conflicts_with = "json_indent")]
+    pub json_tab: bool,
+
+    /// Top-level structure of the category JSON files: entries under `DATA`, at the top level, or under a custom key
+    #[arg(long, value_enum, default_value_t = JsonWrapper::Data)]
+    pub json_wrapper: JsonWrapper,
+
+    /// Key to put the entries under with `--json-wrapper custom`
+    #[arg(long, value_name = "KEY", required_if_eq("json_wrapper", "custom"))]
+    pub json_wrapper_key: Option<String>,
+
+    /// Add a top-level field next to the entries, as `KEY=VALUE`; `VALUE` is JSON if it parses as such, else a
+    /// string, and `{count}` in it is replaced by the number of entries (repeatable)
+    #[arg(long = "json-meta", value_name = "KEY=VALUE", value_parser = parse_json_meta)]
+    pub json_meta: Vec<(String, String)>,
+
+    /// Add `TOTAL_COUNT` (number of entries) and `TOTAL_SIZE` (their summed size in bytes) next to the entries
+    #[arg(long, conflicts_with = "ndjson")]
+    pub include_totals: bool,
+
+    /// Log updates/DLC whose base game is not indexed, optionally writing them to a JSON report file
+    #[arg(long, value_name = "FILE", num_args = 0..=1)]
+    pub report_orphans: Option<Option<PathBuf>>,
+
+    /// Write every per-package error and warning of the run (path, stage, message) to this JSON file
+    #[arg(long, value_name = "FILE")]
+    pub report: Option<PathBuf>,
+
+    /// Keep a SHA-256 of every local package in this JSON file, hashing new and replaced packages
+    #[arg(long, value_name = "FILE")]
+    pub content_hashes: Option<PathBuf>,
+
+    /// Re-hash packages whose size and modification time are unchanged, leaving out any whose contents no
+    /// longer match the stored hash (silent corruption)
+    #[arg(long, requires = "content_hashes")]
+    pub verify_hashes: bool,
+}
+
+fn parse_sfo_key(value: &str) -> Result<(String, String), String> {
+    let (key, json_name) = value.split_once(':').unwrap_or((value, ""));
+    if key.is_empty() {
+        return Err("SFO key must not be empty".to_string());
+    }
+    let json_name = if json_name.is_empty() { key.to_lowercase() } else { json_name.to_string() };
+    Ok((key.to_string(), json_name))
+}
+
+fn parse_rename_output(value: &str) -> Result<(String, String), String> {
+    let (category, name) = value.split_once('=').ok_or("Expected CATEGORY=NAME")?;
+    let name = name.strip_suffix(".json").unwrap_or(name);
+    if category.is_empty() || name.is_empty() {
+        return Err("Category and file name must not be empty".to_string());
+    }
+    if name.contains(['/', '\\']) || name.starts_with('.') {
+        return Err(format!("Invalid output file name '{}'", name));
+    }
+    Ok((category.to_string(), name.to_string()))
+}
+
+fn parse_json_meta(value: &str) -> Result<(String, String), String> {
+    let (key, value) = value.split_once('=').ok_or("Expected KEY=VALUE")?;
+    if key.is_empty() {
+        return Err("Metadata key must not be empty".to_string());
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Normalizes `--path-prefix` to a leading `/` and no trailing one (`fpkgi/` -> `/fpkgi`).
+fn parse_path_prefix(value: &str) -> Result<String, String> {
+    let trimmed = value.trim_matches('/');
+    if trimmed.is_empty() {
+        return Err("Path prefix must not be empty".to_string());
+    }
+    if trimmed.contains(['?', '#']) {
+        return Err("Path prefix must be a plain path".to_string());
+    }
+    Ok(format!("/{}", trimmed))
+}
+
+/// Parses an output category name such as `games` or `DLC`, ignoring case.
+pub fn parse_category(value: &str) -> Result<String, String> {
+    crate::json_builder::category_name(value.trim())
+        .map(str::to_string)
+        .ok_or_else(|| format!("Unknown category '{}'", value))
+}
+
+fn parse_hidden_dir(value: &str) -> Result<PathBuf, String> {
+    let path = PathBuf::from(value.trim_matches('/'));
+    if path.as_os_str().is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err("Hidden directory must be a relative path inside the served directory".to_string());
+    }
+    Ok(path)
+}
+
+fn parse_url_template(value: &str) -> Result<String, String> {
+    let mut rest = value;
+    while let Some((_, tail)) = rest.split_once('{') {
+        let (name, tail) = tail.split_once('}').ok_or("Unclosed '{' in URL template")?;
+        if !URL_TEMPLATE_PLACEHOLDERS.contains(&name) {
+            return Err(format!("Unknown placeholder {{{}}} (expected one of: {})", name,
+                               URL_TEMPLATE_PLACEHOLDERS.iter().map(|p| format!("{{{}}}", p)).collect::<Vec<_>>().join(", ")));
+        }
+        rest = tail;
+    }
+    if !value.contains("{filename}") && !value.contains("{rel_path}") {
+        return Err("URL template must reference {filename} or {rel_path}".to_string());
+    }
+    Ok(value.to_string())
 }
 
 fn split_path_arg(value: &str) -> Result<(PathBuf, String), String> {
@@ -0,0 +1,54 @@
+use std::path::PathBuf;
+
+use actix_files::NamedFile;
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::debug;
+
+// Built-in assets: (name, content type, data)
+const EMBEDDED: &[(&str, &str, &[u8])] = &[
+    ("favicon.ico", "image/x-icon", include_bytes!("../assets/favicon.ico")),
+    ("style.css", "text/css; charset=utf-8", include_bytes!("../assets/style.css")),
+];
+
+/// Static assets for the browse pages, served from `--assets-dir` when a file exists there
+/// and from the copies built into the binary otherwise.
+#[derive(Clone, Debug)]
+pub struct Assets {
+    dir: Option<PathBuf>,
+}
+
+impl Assets {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Assets { dir }
+    }
+
+    async fn respond(&self, name: &str, req: &HttpRequest) -> HttpResponse {
+        // Route parameters never contain '/', so only hidden files and ".." need rejecting
+        if name.starts_with('.') {
+            return HttpResponse::NotFound().body("404 - Not Found");
+        }
+        if let Some(dir) = &self.dir {
+            let path = dir.join(name);
+            if path.is_file() {
+                match NamedFile::open_async(&path).await {
+                    Ok(file) => return file.into_response(req),
+                    Err(e) => debug!("Cannot open asset '{}': {}", path.display(), e),
+                }
+            }
+        }
+        match EMBEDDED.iter().find(|(embedded, _, _)| *embedded == name) {
+            Some((_, content_type, data)) => HttpResponse::Ok().content_type(*content_type).body(*data),
+            None => HttpResponse::NotFound().body("404 - Not Found"),
+        }
+    }
+}
+
+/// `GET /_assets/{name}`
+pub async fn asset(assets: web::Data<Assets>, name: web::Path<String>, req: HttpRequest) -> HttpResponse {
+    assets.respond(&name, &req).await
+}
+
+/// `GET /favicon.ico`
+pub async fn favicon(assets: web::Data<Assets>, req: HttpRequest) -> HttpResponse {
+    assets.respond("favicon.ico", &req).await
+}
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use image::ImageFormat;
+use serde_json::{json, Value as JsonValue};
+
+use crate::ps4_package::PS4Package;
+use crate::sfo_processor::SFOProcessor;
+use crate::vita::{is_vita_package, VitaPackage};
+
+/// Outcome of one pipeline stage; `None` when an earlier failure kept it from running.
+struct Stage {
+    name: &'static str,
+    result: Option<Result<String, String>>,
+}
+
+impl Stage {
+    fn status(&self) -> &'static str {
+        match self.result {
+            Some(Ok(_)) => "PASS",
+            Some(Err(_)) => "FAIL",
+            None => "SKIP",
+        }
+    }
+}
+
+/// Runs the parse, SFO and icon stages over `path` and prints a report; fails if any stage failed.
+///
+/// The icon is written to a temporary directory that is removed afterwards, so nothing persists.
+pub fn run_check(path: &Path, as_json: bool) -> Result<()> {
+    let (parse, sfo, icon) = if is_vita_package(path) {
+        check_vita(path)
+    } else {
+        check_ps4(path)
+    };
+    let stages = [
+        Stage { name: "parse", result: Some(parse) },
+        Stage { name: "sfo", result: sfo },
+        Stage { name: "icon", result: icon },
+    ];
+
+    if as_json {
+        let report: Vec<JsonValue> = stages.iter().map(|stage| {
+            let (detail, error) = match &stage.result {
+                Some(Ok(detail)) => (Some(detail), None),
+                Some(Err(error)) => (None, Some(error)),
+                None => (None, None),
+            };
+            json!({"stage": stage.name, "status": stage.status(), "detail": detail, "error": error})
+        }).collect();
+        println!("{}", serde_json::to_string_pretty(&json!({"package": path, "stages": report}))?);
+    } else {
+        println!("{}", path.display());
+        for stage in &stages {
+            let message = match &stage.result {
+                Some(Ok(message)) | Some(Err(message)) => message.as_str(),
+                None => "not run",
+            };
+            println!("  {}  {:<5}  {}", stage.status(), stage.name, message);
+        }
+    }
+
+    if stages.iter().any(|stage| matches!(stage.result, Some(Err(_)))) {
+        bail!("Check failed for {}", path.display());
+    }
+    Ok(())
+}
+
+type StageResults = (Result<String, String>, Option<Result<String, String>>, Option<Result<String, String>>);
+
+fn check_ps4(path: &Path) -> StageResults {
+    let mut pkg = match PS4Package::new(path.to_path_buf()) {
+        Ok(pkg) => pkg,
+        Err(e) => return (Err(format!("{:#}", e)), None, None),
+    };
+    let parse = Ok(format!("content id {} ({:?}, {} entries)", pkg.content_id, pkg.content_type, pkg.file_entries.len()));
+
+    let sfo = SFOProcessor::new().read(&mut pkg, "param.sfo")
+        .map(|sfo| describe_sfo(&sfo))
+        .map_err(|e| format!("{:#}", e));
+    let icon = pkg.get_file("icon0.png").map_err(|e| format!("{:#}", e)).and_then(|data| check_icon(&data));
+    (parse, Some(sfo), Some(icon))
+}
+
+fn check_vita(path: &Path) -> StageResults {
+    let vita = match VitaPackage::open(path) {
+        Ok(vita) => vita,
+        Err(e) => return (Err(format!("{:#}", e)), None, None),
+    };
+    let parse = Ok(format!("Vita content id {}", vita.content_id));
+    let sfo = match &vita.sfo {
+        Some(sfo) => Ok(describe_sfo(sfo)),
+        None => Err("param.sfo not readable (encrypted in retail packages)".to_string()),
+    };
+    let icon = vita.icon().map_err(|e| format!("{:#}", e)).and_then(|data| check_icon(&data));
+    (parse, Some(sfo), Some(icon))
+}
+
+fn describe_sfo(sfo: &HashMap<String, String>) -> String {
+    let field = |key: &str| sfo.get(key).map(|value| value.trim().to_string()).unwrap_or_else(|| "-".to_string());
+    format!("{} keys, title id {}, category {}, title '{}'", sfo.len(), field("TITLE_ID"), field("CATEGORY"), field("TITLE"))
+}
+
+/// Writes the icon to a temporary directory and decodes it back, as generation would.
+fn check_icon(data: &[u8]) -> Result<String, String> {
+    let result = (|| -> Result<String> {
+        let temp_dir = tempfile::tempdir()?;
+        let icon_path = temp_dir.path().join("icon0.png");
+        fs::write(&icon_path, data)?;
+        let icon = image::load_from_memory_with_format(&fs::read(&icon_path)?, ImageFormat::Png)?;
+        Ok(format!("{}x{} PNG, {} bytes", icon.width(), icon.height(), data.len()))
+    })();
+    result.map_err(|e| format!("{:#}", e))
+}
@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::{Context, Result};
+use log::warn;
+use ring::digest::{Context as DigestContext, SHA256};
+use serde::{Deserialize, Serialize};
+
+use crate::split::{is_split_package, split_parts, SplitReader};
+use crate::utils::write_atomic;
+
+/// SHA-256 of each local package, with the size and modification time it was computed at (`--content-hashes`).
+///
+/// A package whose size or modification time changed was replaced and is simply hashed again; one whose
+/// contents no longer match while both stayed the same has rotted on disk, which `--verify-hashes` detects.
+pub struct ContentHashes {
+    path: PathBuf,
+    previous: BTreeMap<String, HashRecord>,
+}
+
+/// Stored hash of one package, keyed by its path relative to the packages directory.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HashRecord {
+    size: u64,
+    mtime: String,
+    sha256: String,
+    /// Set once the contents were found not to match `sha256`, so the package stays left out until replaced
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    corrupt: bool,
+}
+
+/// Outcome of checking one package against the stored hashes.
+pub enum HashCheck {
+    /// Hashed now, being new, replaced, or verified to still match
+    Hashed(HashRecord),
+    /// Unchanged size and modification time; the stored record is kept without reading the file
+    Kept(HashRecord),
+    /// The contents no longer match the stored hash although size and modification time did not change;
+    /// carries the record to keep (marked corrupt), the stored hash and the current one
+    Corrupt(HashRecord, String, String),
+}
+
+impl ContentHashes {
+    /// Loads the hashes stored in `path`, starting empty if it is missing or unreadable.
+    pub fn open(path: &Path) -> Self {
+        let previous = match fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!("Ignoring corrupt content hash file '{}': {}", path.display(), e);
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        };
+        ContentHashes { path: path.to_path_buf(), previous }
+    }
+
+    /// Checks the package at `package`, stored under `key`, hashing it unless its size and modification time
+    /// are unchanged and `verify` is off.
+    pub fn check(&self, key: &str, package: &Path, verify: bool) -> Result<HashCheck> {
+        let (size, mtime) = stamp(package)?;
+        let previous = self.previous.get(key).filter(|record| record.size == size && record.mtime == mtime);
+        match previous {
+            Some(record) if !verify => Ok(HashCheck::Kept(record.clone())),
+            Some(record) => {
+                let sha256 = hash_package(package)?;
+                if sha256 == record.sha256 {
+                    Ok(HashCheck::Hashed(HashRecord { corrupt: false, ..record.clone() }))
+                } else {
+                    Ok(HashCheck::Corrupt(HashRecord { corrupt: true, ..record.clone() }, record.sha256.clone(), sha256))
+                }
+            }
+            None => Ok(HashCheck::Hashed(HashRecord { size, mtime, sha256: hash_package(package)?, corrupt: false })),
+        }
+    }
+
+    /// Replaces the stored hashes with `current`, the records of the packages seen in this run.
+    pub fn save(&self, current: &BTreeMap<String, HashRecord>) -> Result<()> {
+        if *current != self.previous {
+            write_atomic(&self.path, &serde_json::to_vec_pretty(current)?)
+                .with_context(|| format!("Failed to write content hashes to {}", self.path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+impl HashRecord {
+    pub fn is_corrupt(&self) -> bool {
+        self.corrupt
+    }
+}
+
+/// Combined size and latest modification time of the file, or of all parts of a split package.
+fn stamp(package: &Path) -> Result<(u64, String)> {
+    let parts = if is_split_package(package) { split_parts(package) } else { vec![package.to_path_buf()] };
+    let mut size = 0;
+    let mut latest = 0;
+    for part in parts {
+        let metadata = fs::metadata(&part)?;
+        size += metadata.len();
+        latest = latest.max(metadata.modified()?.duration_since(UNIX_EPOCH)?.as_nanos());
+    }
+    Ok((size, latest.to_string()))
+}
+
+/// Hex SHA-256 of the file as stored (compressed for `.pkg.gz`), or of the joined parts of a split package.
+fn hash_package(package: &Path) -> Result<String> {
+    let mut reader: Box<dyn Read> = if is_split_package(package) {
+        Box::new(SplitReader::open(package)?)
+    } else {
+        Box::new(File::open(package)?)
+    };
+    let mut context = DigestContext::new(&SHA256);
+    let mut buffer = vec![0u8; 1024 * 1024];
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => context.update(&buffer[..read]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e).with_context(|| format!("Failed to hash '{}'", package.display())),
+        }
+    }
+    Ok(hex::encode(context.finish()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{flip_byte, game};
+
+    #[test]
+    fn detects_contents_changed_at_the_same_size_and_mtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let (package, hashes_file) = (dir.path().join("game.pkg"), dir.path().join("hashes.json"));
+        fs::write(&package, game("CUSA00001")).unwrap();
+        let Ok(HashCheck::Hashed(record)) = ContentHashes::open(&hashes_file).check("game.pkg", &package, false) else {
+            panic!("new package not hashed");
+        };
+        ContentHashes::open(&hashes_file).save(&BTreeMap::from([("game.pkg".to_string(), record.clone())])).unwrap();
+
+        flip_byte(&package, 0x2E0);
+        let hashes = ContentHashes::open(&hashes_file);
+        assert!(matches!(hashes.check("game.pkg", &package, false), Ok(HashCheck::Kept(kept)) if kept == record));
+        match hashes.check("game.pkg", &package, true) {
+            Ok(HashCheck::Corrupt(corrupt, expected, actual)) => {
+                assert!(corrupt.is_corrupt());
+                assert_eq!(expected, record.sha256);
+                assert_ne!(actual, record.sha256);
+            }
+            _ => panic!("corruption not detected"),
+        }
+    }
+
+    #[test]
+    fn rehashes_replaced_packages() {
+        let dir = tempfile::tempdir().unwrap();
+        let (package, hashes_file) = (dir.path().join("game.pkg"), dir.path().join("hashes.json"));
+        fs::write(&package, game("CUSA00001")).unwrap();
+        let Ok(HashCheck::Hashed(record)) = ContentHashes::open(&hashes_file).check("game.pkg", &package, false) else {
+            panic!("new package not hashed");
+        };
+        ContentHashes::open(&hashes_file).save(&BTreeMap::from([("game.pkg".to_string(), record.clone())])).unwrap();
+
+        fs::write(&package, game("CUSA00002")).unwrap();
+        File::options().write(true).open(&package).unwrap()
+            .set_modified(UNIX_EPOCH + std::time::Duration::from_secs(1)).unwrap();
+        let Ok(HashCheck::Hashed(replaced)) = ContentHashes::open(&hashes_file).check("game.pkg", &package, true) else {
+            panic!("replaced package not hashed");
+        };
+        assert_ne!(replaced.sha256, record.sha256);
+        assert!(!replaced.is_corrupt());
+    }
+}
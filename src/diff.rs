@@ -0,0 +1,145 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde_json::{json, Map, Value as JsonValue};
+
+use crate::entry::document_entries;
+
+type Entries = Map<String, JsonValue>;
+
+/// An entry present in both sets whose version changed, possibly under a new URL.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Change {
+    old_url: String,
+    new_url: String,
+    title_id: Option<String>,
+    old_version: Option<String>,
+    new_version: Option<String>,
+}
+
+/// Differences for one category between two generated JSON sets.
+#[derive(Default)]
+struct CategoryDiff {
+    added: Vec<String>,
+    removed: Vec<String>,
+    changed: Vec<Change>,
+}
+
+impl CategoryDiff {
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Loads every `<category>.json` in `dir` as category -> `DATA` entries.
+pub(crate) fn load_categories(dir: &Path) -> Result<BTreeMap<String, Entries>> {
+    let mut categories = BTreeMap::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to read directory {}", dir.display()))? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let data = fs::read(&path)?;
+        let json: JsonValue = serde_json::from_slice(&data)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        if let Some(entries) = document_entries(&json) {
+            let category = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+            categories.insert(category, entries.clone());
+        }
+    }
+    Ok(categories)
+}
+
+fn field(entry: &JsonValue, name: &str) -> Option<String> {
+    entry.get(name).and_then(JsonValue::as_str).map(str::to_string)
+}
+
+/// Compares entries by URL, then pairs leftover removals and additions that share a title id
+/// (e.g. an update replaced by a newer file) as changes.
+fn diff_category(old: &Entries, new: &Entries) -> CategoryDiff {
+    let mut diff = CategoryDiff::default();
+    for (url, new_entry) in new {
+        if let Some(old_entry) = old.get(url) {
+            let (old_version, new_version) = (field(old_entry, "version"), field(new_entry, "version"));
+            if old_version != new_version {
+                diff.changed.push(Change {
+                    old_url: url.clone(), new_url: url.clone(), title_id: field(new_entry, "title_id"), old_version, new_version,
+                });
+            }
+        }
+    }
+
+    let mut added: BTreeSet<&String> = new.keys().filter(|url| !old.contains_key(*url)).collect();
+    for (url, old_entry) in old.iter().filter(|(url, _)| !new.contains_key(*url)) {
+        let title_id = field(old_entry, "title_id");
+        let replacement = title_id.as_ref()
+            .and_then(|id| added.iter().find(|new_url| field(&new[new_url.as_str()], "title_id").as_ref() == Some(id)))
+            .copied();
+        match replacement {
+            Some(new_url) => {
+                added.remove(new_url);
+                diff.changed.push(Change {
+                    old_url: url.clone(), new_url: new_url.clone(), title_id,
+                    old_version: field(old_entry, "version"), new_version: field(&new[new_url.as_str()], "version"),
+                });
+            }
+            None => diff.removed.push(url.clone()),
+        }
+    }
+    diff.added = added.into_iter().cloned().collect();
+    diff.changed.sort();
+    diff
+}
+
+/// Prints added, removed and changed entries per category between two output directories.
+pub fn run_diff(old_dir: &Path, new_dir: &Path, as_json: bool) -> Result<()> {
+    let old = load_categories(old_dir)?;
+    let new = load_categories(new_dir)?;
+    let empty = Entries::new();
+
+    let categories: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    let diffs: Vec<(&String, CategoryDiff)> = categories.into_iter()
+        .map(|category| {
+            let diff = diff_category(old.get(category).unwrap_or(&empty), new.get(category).unwrap_or(&empty));
+            (category, diff)
+        })
+        .filter(|(_, diff)| !diff.is_empty())
+        .collect();
+
+    if as_json {
+        let report: Map<String, JsonValue> = diffs.iter().map(|(category, diff)| {
+            let changed: Vec<JsonValue> = diff.changed.iter().map(|change| json!({
+                "old_url": change.old_url, "new_url": change.new_url, "title_id": change.title_id,
+                "old_version": change.old_version, "new_version": change.new_version,
+            })).collect();
+            (category.to_string(), json!({"added": diff.added, "removed": diff.removed, "changed": changed}))
+        }).collect();
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    if diffs.is_empty() {
+        println!("No differences");
+    }
+    for (category, diff) in &diffs {
+        println!("[{}]", category);
+        for url in &diff.added {
+            println!("  + {}", url);
+        }
+        for url in &diff.removed {
+            println!("  - {}", url);
+        }
+        for change in &diff.changed {
+            let location = if change.old_url == change.new_url {
+                change.old_url.clone()
+            } else {
+                format!("{} -> {}", change.old_url, change.new_url)
+            };
+            println!("  ~ {} ({}: {} -> {})", location, change.title_id.as_deref().unwrap_or("unknown"),
+                     change.old_version.as_deref().unwrap_or("none"), change.new_version.as_deref().unwrap_or("none"));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::web::Bytes;
+use log::debug;
+
+/// Caps the number of in-flight package downloads per client IP.
+#[derive(Clone, Debug)]
+pub struct DownloadLimiter {
+    max_per_ip: usize,
+    active: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl DownloadLimiter {
+    pub fn new(max_per_ip: usize) -> Self {
+        DownloadLimiter { max_per_ip, active: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Reserves a download slot for `ip`, or returns `None` if its limit is reached.
+    pub fn try_acquire(&self, ip: IpAddr) -> Option<DownloadGuard> {
+        let mut active = self.active.lock().unwrap();
+        let count = active.entry(ip).or_insert(0);
+        if *count >= self.max_per_ip {
+            debug!("Rejecting download from {}: {} already in flight", ip, count);
+            return None;
+        }
+        *count += 1;
+        Some(DownloadGuard { active: self.active.clone(), ip })
+    }
+}
+
+/// Releases its download slot when dropped.
+pub struct DownloadGuard {
+    active: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    ip: IpAddr,
+}
+
+impl Drop for DownloadGuard {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Response body that holds a `DownloadGuard` until the body is fully sent or dropped.
+pub struct GuardedBody {
+    inner: BoxBody,
+    _guard: DownloadGuard,
+}
+
+impl GuardedBody {
+    pub fn new<B: MessageBody + 'static>(body: B, guard: DownloadGuard) -> Self {
+        GuardedBody { inner: BoxBody::new(body), _guard: guard }
+    }
+}
+
+impl MessageBody for GuardedBody {
+    type Error = Box<dyn std::error::Error>;
+
+    fn size(&self) -> BodySize {
+        self.inner.size()
+    }
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        Pin::new(&mut self.get_mut().inner).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 1, 20));
+    const OTHER_CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 1, 21));
+
+    #[test]
+    fn rejects_downloads_over_the_limit_per_ip() {
+        let limiter = DownloadLimiter::new(2);
+        let guards: Vec<_> = (0..2).map(|_| limiter.try_acquire(CLIENT).unwrap()).collect();
+        assert!(limiter.try_acquire(CLIENT).is_none());
+        assert!(limiter.try_acquire(OTHER_CLIENT).is_some());
+
+        drop(guards);
+        assert!(limiter.active.lock().unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn dropping_the_body_releases_the_slot() {
+        let limiter = DownloadLimiter::new(1);
+        let body = GuardedBody::new("package data", limiter.try_acquire(CLIENT).unwrap());
+        assert!(limiter.try_acquire(CLIENT).is_none());
+
+        // Sending the body to the end does not release the slot; the server drops it afterwards
+        let mut body = Box::pin(body);
+        assert_eq!(std::future::poll_fn(|cx| body.as_mut().poll_next(cx)).await.unwrap().unwrap(), "package data");
+        assert!(std::future::poll_fn(|cx| body.as_mut().poll_next(cx)).await.is_none());
+        assert!(limiter.try_acquire(CLIENT).is_none());
+
+        drop(body);
+        assert!(limiter.try_acquire(CLIENT).is_some());
+    }
+}
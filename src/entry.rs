@@ -0,0 +1,163 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+
+/// One package in a category's `DATA` map, keyed there by its download URL.
+///
+/// The standard FPKGi fields are always written, as `null` when unknown; anything else
+/// (`--sfo-key` projections, `raw_name`, `sfo`, merged external fields) is kept in `extra`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PackageEntry {
+    #[serde(default)]
+    pub title_id: Option<String>,
+    #[serde(default)]
+    pub region: Option<String>,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub version: Option<String>,
+    #[serde(default)]
+    pub release: Option<String>,
+    #[serde(default)]
+    pub size: Option<PackageSize>,
+    #[serde(default)]
+    pub min_fw: Option<String>,
+    #[serde(default)]
+    pub cover_url: Option<String>,
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, JsonValue>,
+}
+
+impl PackageEntry {
+    /// Builds an entry from a JSON object such as one read from an external file, or `None` for other values.
+    ///
+    /// Unlike deserializing, this never fails: fields are converted as by `set`.
+    pub fn from_json(value: JsonValue) -> Option<Self> {
+        let JsonValue::Object(fields) = value else { return None };
+        let mut entry = PackageEntry::default();
+        for (key, value) in fields {
+            entry.set(&key, value);
+        }
+        Some(entry)
+    }
+
+    /// Sets the field named `key`, or adds it to `extra` when it is not a standard one.
+    ///
+    /// `null` clears a standard field; other non-string values are stored as their JSON text.
+    pub fn set(&mut self, key: &str, value: JsonValue) {
+        let text = |value: JsonValue| match value {
+            JsonValue::Null => None,
+            JsonValue::String(text) => Some(text),
+            other => Some(other.to_string()),
+        };
+        match key {
+            "title_id" => self.title_id = text(value),
+            "region" => self.region = text(value),
+            "name" => self.name = text(value),
+            "version" => self.version = text(value),
+            "release" => self.release = text(value),
+            "min_fw" => self.min_fw = text(value),
+            "cover_url" => self.cover_url = text(value),
+            "size" => self.size = match value {
+                JsonValue::Number(number) => number.as_u64().map(PackageSize::Number),
+                other => text(other).map(PackageSize::Text),
+            },
+            _ => {
+                self.extra.insert(key.to_string(), value);
+            }
+        }
+    }
+}
+
+/// Package size in bytes, written as a number or, with `--size-as-string`, as a string.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PackageSize {
+    Number(u64),
+    Text(String),
+}
+
+impl PackageSize {
+    pub fn bytes(&self) -> Option<u64> {
+        match self {
+            PackageSize::Number(bytes) => Some(*bytes),
+            PackageSize::Text(text) => text.parse().ok(),
+        }
+    }
+}
+
+/// The entries map of a category JSON document in any `--json-wrapper` form: the `DATA` member, else the
+/// document itself when it holds nothing but entries, else its only member that does.
+///
+/// Documents with several such members, like the `--store-json` file, are not category documents.
+pub fn document_entries(document: &JsonValue) -> Option<&serde_json::Map<String, JsonValue>> {
+    // Entries are objects that always carry the standard fields
+    let all_entries = |map: &serde_json::Map<String, JsonValue>| {
+        map.values().all(|value| value.get("title_id").is_some())
+    };
+    let JsonValue::Object(document) = document else { return None };
+    if let Some(JsonValue::Object(data)) = document.get("DATA") {
+        return Some(data);
+    }
+    if all_entries(document) {
+        return Some(document);
+    }
+    let mut members = document.values().filter_map(|value| match value {
+        JsonValue::Object(map) if all_entries(map) => Some(map),
+        _ => None,
+    });
+    match (members.next(), members.next()) {
+        (Some(map), None) => Some(map),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn serializes_the_standard_fields_in_order_then_extras() {
+        let mut entry = PackageEntry {
+            title_id: Some("CUSA00001".to_string()),
+            region: Some("USA".to_string()),
+            name: Some("Some Game".to_string()),
+            version: Some("01.00".to_string()),
+            size: Some(PackageSize::Number(1234)),
+            cover_url: Some("http://localhost:8000/icons/game.pkg.png".to_string()),
+            ..PackageEntry::default()
+        };
+        entry.extra.insert("raw_name".to_string(), json!("Some  Game"));
+        entry.extra.insert("content_id".to_string(), json!("UP0000-CUSA00001_00-GAME000000000000"));
+        assert_eq!(
+            serde_json::to_string(&entry).unwrap(),
+            r#"{"title_id":"CUSA00001","region":"USA","name":"Some Game","version":"01.00","release":null,"size":1234,"#.to_string()
+                + r#""min_fw":null,"cover_url":"http://localhost:8000/icons/game.pkg.png","#
+                + r#""content_id":"UP0000-CUSA00001_00-GAME000000000000","raw_name":"Some  Game"}"#
+        );
+    }
+
+    #[test]
+    fn writes_unknown_fields_as_null_and_sizes_as_strings_on_request() {
+        let entry = PackageEntry { size: Some(PackageSize::Text("1234".to_string())), ..PackageEntry::default() };
+        assert_eq!(serde_json::to_value(&entry).unwrap(), json!({
+            "title_id": null, "region": null, "name": null, "version": null, "release": null,
+            "size": "1234", "min_fw": null, "cover_url": null,
+        }));
+    }
+
+    #[test]
+    fn external_entries_round_trip() {
+        let external = json!({
+            "title_id": "CUSA00001", "region": "EUR", "name": "Game", "version": "1.05", "release": "2020-01-01",
+            "size": 42, "min_fw": "9.00", "cover_url": null, "description": {"en": "A game"},
+        });
+        let entry = PackageEntry::from_json(external.clone()).unwrap();
+        assert_eq!(entry.size.as_ref().and_then(PackageSize::bytes), Some(42));
+        assert_eq!(serde_json::to_value(&entry).unwrap(), external);
+        assert_eq!(serde_json::from_value::<PackageEntry>(external).unwrap(), entry);
+    }
+}
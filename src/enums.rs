@@ -5,6 +5,7 @@ pub enum DRMCategory {
 }
 
 #[derive(Debug, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum ContentCategory {
     Game,
     DLC,
@@ -17,3 +18,49 @@ pub enum IROCategory {
     SFTheme,
     SysTheme,
 }
+
+/// Decoded PKG header content flags.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct ContentFlags {
+    pub first_patch: bool,
+    pub patchgo: bool,
+    pub remaster: bool,
+    pub ps_cloud: bool,
+    pub gd_ac: bool,
+    pub non_game: bool,
+    pub subsequent_patch: bool,
+    pub delta_patch: bool,
+    pub cumulative_patch: bool,
+}
+
+impl ContentFlags {
+    const FIRST_PATCH: u32 = 0x0010_0000;
+    const PATCHGO: u32 = 0x0020_0000;
+    const REMASTER: u32 = 0x0040_0000;
+    const PS_CLOUD: u32 = 0x0080_0000;
+    const DELTA: u32 = 0x0100_0000;
+    const GD_AC: u32 = 0x0200_0000;
+    const NON_GAME: u32 = 0x0400_0000;
+    const CUMULATIVE: u32 = 0x2000_0000;
+    const SUBSEQUENT_PATCH: u32 = 0x4000_0000;
+
+    pub fn from_bits(bits: u32) -> Self {
+        let subsequent_patch = bits & Self::SUBSEQUENT_PATCH != 0;
+        ContentFlags {
+            first_patch: bits & Self::FIRST_PATCH != 0,
+            patchgo: bits & Self::PATCHGO != 0,
+            remaster: bits & Self::REMASTER != 0,
+            ps_cloud: bits & Self::PS_CLOUD != 0,
+            gd_ac: bits & Self::GD_AC != 0,
+            non_game: bits & Self::NON_GAME != 0,
+            subsequent_patch,
+            delta_patch: subsequent_patch && bits & Self::DELTA != 0,
+            cumulative_patch: subsequent_patch && bits & Self::CUMULATIVE != 0,
+        }
+    }
+
+    /// Whether the package is a standalone patch rather than an app with a patch included.
+    pub fn is_patch(&self) -> bool {
+        self.subsequent_patch || self.delta_patch || self.cumulative_patch
+    }
+}
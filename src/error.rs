@@ -0,0 +1,70 @@
+use std::io;
+use std::path::PathBuf;
+
+use serde_json::{json, Value as JsonValue};
+
+use crate::ps4_package::PackageError;
+
+/// A command failure of a kind scripts need to tell apart, reported as `code` by `--error-format json`.
+///
+/// Attached as the error itself or as context; other failures are classified by their causes in `error_code`.
+#[derive(Debug)]
+pub enum CommandError {
+    /// The command line could not be parsed.
+    InvalidArguments(String),
+    /// Option values that do not fit together, or an invalid configuration file.
+    InvalidConfig(String),
+    /// The server could not listen on an address or socket.
+    BindFailed(String),
+    /// The packages directory does not exist.
+    NoPackages(PathBuf),
+}
+
+impl CommandError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            CommandError::InvalidArguments(_) => "invalid_arguments",
+            CommandError::InvalidConfig(_) => "invalid_config",
+            CommandError::BindFailed(_) => "bind_failed",
+            CommandError::NoPackages(_) => "no_packages",
+        }
+    }
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::InvalidArguments(message) | CommandError::InvalidConfig(message) => write!(f, "{}", message),
+            CommandError::BindFailed(address) => write!(f, "Failed to listen on {}", address),
+            CommandError::NoPackages(path) => write!(f, "Packages directory {} does not exist", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+/// Stable code for `error`: that of a `CommandError` in it, else one derived from its underlying cause.
+pub fn error_code(error: &anyhow::Error) -> &'static str {
+    if let Some(error) = error.downcast_ref::<CommandError>() {
+        return error.code();
+    }
+    if error.chain().any(|cause| cause.is::<PackageError>()) {
+        return "invalid_package";
+    }
+    match error.chain().find_map(|cause| cause.downcast_ref::<io::Error>()).map(io::Error::kind) {
+        Some(io::ErrorKind::NotFound) => "not_found",
+        Some(io::ErrorKind::PermissionDenied) => "permission_denied",
+        Some(io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable) => "bind_failed",
+        Some(_) => "io_error",
+        None => "failed",
+    }
+}
+
+/// `error` as printed by `--error-format json`: its message, the messages of its causes and its code.
+pub fn error_json(error: &anyhow::Error) -> JsonValue {
+    json!({
+        "error": error.to_string(),
+        "causes": error.chain().skip(1).map(ToString::to_string).collect::<Vec<_>>(),
+        "code": error_code(error),
+    })
+}
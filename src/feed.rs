@@ -0,0 +1,105 @@
+use std::fs;
+use std::time::SystemTime;
+
+use actix_web::{web, HttpResponse};
+use log::{debug, error};
+use serde_json::Value as JsonValue;
+use walkdir::WalkDir;
+
+use crate::args::GenerateArgs;
+use crate::entry::document_entries;
+use crate::json_builder::{existing_package_file, local_package_path};
+
+// Number of packages listed in the feed
+const FEED_ITEMS: usize = 50;
+
+/// RSS feed of the most recently added packages, enabled in `host` mode with `--feed`.
+///
+/// Built per request from the generated JSON files, ordered by the package files' modification times.
+#[derive(Clone)]
+pub struct Feed {
+    args: GenerateArgs,
+}
+
+struct FeedItem {
+    title: String,
+    link: String,
+    size: Option<u64>,
+    modified: SystemTime,
+}
+
+impl Feed {
+    pub fn new(args: GenerateArgs) -> Self {
+        Feed { args }
+    }
+
+    /// Collects entries from every generated category JSON file under the output directory.
+    fn items(&self) -> Vec<FeedItem> {
+        let mut items = Vec::new();
+        for entry in WalkDir::new(&self.args.out.0).into_iter().filter_map(Result::ok) {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let Ok(json @ JsonValue::Object(_)) = fs::read(path).map_err(anyhow::Error::from)
+                .and_then(|data| serde_json::from_slice(&data).map_err(anyhow::Error::from)) else {
+                debug!("Skipping unreadable JSON in feed: {}", path.display());
+                continue;
+            };
+            let Some(data) = document_entries(&json) else { continue };
+
+            for (link, package) in data {
+                let modified = local_package_path(&self.args, link)
+                    .and_then(|pkg_path| existing_package_file(&pkg_path))
+                    .and_then(|file| fs::metadata(file).and_then(|meta| meta.modified()).ok());
+                // Entries without a local file (external, remote) have no meaningful added date
+                let Some(modified) = modified else { continue };
+                items.push(FeedItem {
+                    title: package.get("name").and_then(JsonValue::as_str).unwrap_or(link).to_string(),
+                    link: link.clone(),
+                    size: package.get("size").and_then(|size| size.as_u64().or_else(|| size.as_str()?.parse().ok())),
+                    modified,
+                });
+            }
+        }
+        items.sort_by(|a, b| b.modified.cmp(&a.modified).then_with(|| a.link.cmp(&b.link)));
+        items.truncate(FEED_ITEMS);
+        items
+    }
+
+    fn render(&self, items: &[FeedItem]) -> String {
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<rss version=\"2.0\">\n<channel>\n");
+        xml.push_str("<title>FPKGi Server</title>\n");
+        xml.push_str(&format!("<link>{}</link>\n", escape_xml(&self.args.url)));
+        xml.push_str("<description>Recently added packages</description>\n");
+        for item in items {
+            xml.push_str("<item>\n");
+            xml.push_str(&format!("<title>{}</title>\n", escape_xml(&item.title)));
+            xml.push_str(&format!("<link>{}</link>\n", escape_xml(&item.link)));
+            xml.push_str(&format!("<guid>{}</guid>\n", escape_xml(&item.link)));
+            if let Some(size) = item.size {
+                xml.push_str(&format!("<description>{} bytes</description>\n", size));
+            }
+            xml.push_str(&format!("<pubDate>{}</pubDate>\n", httpdate::fmt_http_date(item.modified)));
+            xml.push_str("</item>\n");
+        }
+        xml.push_str("</channel>\n</rss>\n");
+        xml
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// `GET /feed.xml`: the most recently added packages as RSS 2.0.
+pub async fn feed(feed: web::Data<Feed>) -> HttpResponse {
+    let feed = feed.get_ref().clone();
+    match web::block(move || feed.render(&feed.items())).await {
+        Ok(xml) => HttpResponse::Ok().content_type("application/rss+xml; charset=utf-8").body(xml),
+        Err(e) => {
+            error!("Failed to build feed: {}", e);
+            HttpResponse::InternalServerError().body("Error building feed")
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use log::{debug, warn};
+use serde_json::{json, Value as JsonValue};
+
+use crate::utils::write_atomic;
+
+const INDEX_FILE: &str = "index.json";
+
+/// Persistent store of extracted icons keyed by package content id.
+///
+/// Each cached icon is tagged with the package header digest and the thumbnail size it was
+/// produced with, so a moved or renamed package reuses its icon while a rebuilt one is re-extracted.
+pub struct IconCache {
+    dir: PathBuf,
+    index: HashMap<String, JsonValue>,
+    dirty: bool,
+}
+
+impl IconCache {
+    /// Opens the cache in `dir`, starting empty if the index is missing or unreadable.
+    pub fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let index_path = dir.join(INDEX_FILE);
+        let index = match fs::read(&index_path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!("Ignoring corrupt icon cache index '{}': {}", index_path.display(), e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        Ok(IconCache { dir: dir.to_path_buf(), index, dirty: false })
+    }
+
+    fn icon_file(content_id: &str) -> String {
+        format!("{}.png", content_id)
+    }
+
+    /// Copies the cached icon for `content_id` to `destination` if it is still valid for `hash`.
+    pub fn restore(&self, content_id: &str, hash: &str, max_size: Option<u32>, destination: &Path) -> bool {
+        let Some(entry) = self.index.get(content_id) else {
+            return false;
+        };
+        if entry["hash"] != hash || entry["max_size"] != json!(max_size) {
+            debug!("Icon cache entry for {} is stale", content_id);
+            return false;
+        }
+        let Some(file) = entry["file"].as_str() else {
+            return false;
+        };
+        match fs::read(self.dir.join(file)).and_then(|data| write_atomic(destination, &data).map_err(io::Error::other)) {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("Icon cache entry for {} unusable: {}", content_id, e);
+                false
+            }
+        }
+    }
+
+    /// Stores a freshly extracted icon at `source` under `content_id`.
+    pub fn store(&mut self, content_id: &str, hash: &str, max_size: Option<u32>, source: &Path) -> Result<()> {
+        let file = Self::icon_file(content_id);
+        fs::copy(source, self.dir.join(&file))?;
+        self.index.insert(content_id.to_string(), json!({ "hash": hash, "max_size": max_size, "file": file }));
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Writes the index back to disk if anything changed.
+    pub fn save(&self) -> Result<()> {
+        if self.dirty {
+            write_atomic(&self.dir.join(INDEX_FILE), &serde_json::to_vec_pretty(&self.index)?)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::Result;
+use log::warn;
+use serde_json::{json, Value as JsonValue};
+
+use crate::utils::write_atomic;
+
+const STATE_FILE: &str = ".icon-state.json";
+
+/// Size and modification time of the package each extracted icon was written from, kept in the icons directory.
+///
+/// Lets a regeneration skip re-extracting the icon of a package whose file did not change, e.g. after a
+/// metadata-only `chmod` or `touch -a` triggered the watcher. Only icons seen in the current run are saved.
+pub struct IconState {
+    dir: PathBuf,
+    previous: HashMap<String, JsonValue>,
+    current: HashMap<String, JsonValue>,
+    dirty: bool,
+}
+
+impl IconState {
+    /// Loads the state of the icons in `dir`, starting empty if it is missing or unreadable.
+    pub fn open(dir: &Path) -> Self {
+        let state_path = dir.join(STATE_FILE);
+        let previous = match fs::read(&state_path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!("Ignoring corrupt icon state '{}': {}", state_path.display(), e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        IconState { dir: dir.to_path_buf(), previous, current: HashMap::new(), dirty: false }
+    }
+
+    /// Whether `icon` exists and was written from `package` as it is now, with the same `max_size`/`keep_full` settings.
+    pub fn is_current(&mut self, icon: &Path, package: &Path, max_size: Option<u32>, keep_full: bool) -> bool {
+        let Some(key) = self.key(icon) else { return false };
+        let Some(stamp) = stamp(package, max_size, keep_full) else { return false };
+        let full_present = !keep_full || max_size.is_none() || icon.with_extension("full.png").is_file();
+        if self.previous.get(&key) != Some(&stamp) || !icon.is_file() || !full_present {
+            return false;
+        }
+        self.current.insert(key, stamp);
+        true
+    }
+
+    /// Notes that `icon` was just written from `package`.
+    pub fn record(&mut self, icon: &Path, package: &Path, max_size: Option<u32>, keep_full: bool) {
+        if let (Some(key), Some(stamp)) = (self.key(icon), stamp(package, max_size, keep_full)) {
+            self.current.insert(key, stamp);
+            self.dirty = true;
+        }
+    }
+
+    /// Writes the state of the icons seen in this run back to disk if it changed.
+    pub fn save(&self) -> Result<()> {
+        if self.dirty || self.current.len() != self.previous.len() {
+            fs::create_dir_all(&self.dir)?;
+            write_atomic(&self.dir.join(STATE_FILE), &serde_json::to_vec(&self.current)?)?;
+        }
+        Ok(())
+    }
+
+    fn key(&self, icon: &Path) -> Option<String> {
+        Some(icon.strip_prefix(&self.dir).ok()?.to_string_lossy().replace(std::path::MAIN_SEPARATOR, "/"))
+    }
+}
+
+/// Identifies the current version of `package` and the icon settings it is extracted with.
+fn stamp(package: &Path, max_size: Option<u32>, keep_full: bool) -> Option<JsonValue> {
+    let metadata = fs::metadata(package).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(json!({
+        "size": metadata.len(),
+        "mtime": modified.as_nanos().to_string(),
+        "max_size": max_size,
+        "keep_full": keep_full,
+    }))
+}
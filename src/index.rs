@@ -0,0 +1,87 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::Result;
+use tokio_util::sync::CancellationToken;
+
+use crate::entry::PackageEntry;
+use crate::{CategoryData, GenerateArgs};
+
+/// Generated entries per category, queryable in-process without writing or re-parsing the JSON files.
+#[derive(Debug, Clone, Default)]
+pub struct FpkgiIndex {
+    categories: BTreeMap<String, BTreeMap<String, PackageEntry>>,
+}
+
+/// An entry of the index along with where it is listed.
+#[derive(Debug, Clone, Copy)]
+pub struct IndexedPackage<'a> {
+    /// Output category, e.g. `games` or `vita_DLC`
+    pub category: &'a str,
+    /// Download URL, the entry's key in the category's `DATA` map
+    pub url: &'a str,
+    pub entry: &'a PackageEntry,
+}
+
+impl FpkgiIndex {
+    /// Indexes the packages described by `args`, like `generate`.
+    pub async fn generate(args: GenerateArgs, cancel: CancellationToken) -> Result<Self> {
+        Ok(Self::from_categories(crate::generate(args, cancel).await?))
+    }
+
+    /// Builds the index from `generate`'s entries per category.
+    pub fn from_categories(data: HashMap<String, CategoryData>) -> Self {
+        let categories = data.into_iter()
+            .map(|(category, entries)| (category, entries.into_iter().collect()))
+            .collect();
+        FpkgiIndex { categories }
+    }
+
+    /// Names of the categories in the index, including empty ones.
+    pub fn categories(&self) -> impl Iterator<Item = &str> {
+        self.categories.keys().map(String::as_str)
+    }
+
+    /// Entries of one category, ordered by URL.
+    pub fn category<'a>(&'a self, category: &str) -> impl Iterator<Item = IndexedPackage<'a>> + 'a {
+        self.categories.get_key_value(category).into_iter()
+            .flat_map(|(category, entries)| entries.iter().map(move |(url, entry)| IndexedPackage { category, url, entry }))
+    }
+
+    /// All entries, ordered by category and URL.
+    pub fn entries(&self) -> impl Iterator<Item = IndexedPackage<'_>> {
+        self.categories.iter()
+            .flat_map(|(category, entries)| entries.iter().map(move |(url, entry)| IndexedPackage { category, url, entry }))
+    }
+
+    pub fn games(&self) -> impl Iterator<Item = IndexedPackage<'_>> {
+        self.category("games")
+    }
+
+    pub fn updates(&self) -> impl Iterator<Item = IndexedPackage<'_>> {
+        self.category("updates")
+    }
+
+    pub fn dlc(&self) -> impl Iterator<Item = IndexedPackage<'_>> {
+        self.category("DLC")
+    }
+
+    pub fn homebrew(&self) -> impl Iterator<Item = IndexedPackage<'_>> {
+        self.category("homebrew")
+    }
+
+    pub fn demos(&self) -> impl Iterator<Item = IndexedPackage<'_>> {
+        self.category("demos")
+    }
+
+    /// Entries whose region (`USA`, `EUR`, `JAP` or `UNK`) matches, case-insensitively.
+    pub fn by_region<'a>(&'a self, region: &'a str) -> impl Iterator<Item = IndexedPackage<'a>> + 'a {
+        self.entries()
+            .filter(move |package| package.entry.region.as_deref().is_some_and(|r| r.eq_ignore_ascii_case(region)))
+    }
+
+    /// Entries with this title id (e.g. `CUSA00001`) across all categories: the game, its updates and DLC.
+    pub fn by_title_id<'a>(&'a self, title_id: &'a str) -> impl Iterator<Item = IndexedPackage<'a>> + 'a {
+        self.entries()
+            .filter(move |package| package.entry.title_id.as_deref().is_some_and(|id| id.eq_ignore_ascii_case(title_id)))
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use tokio::task;
+use tokio_util::sync::CancellationToken;
+
+use crate::args::GenerateArgs;
+use crate::entry::PackageEntry;
+use crate::error::CommandError;
+use crate::index::FpkgiIndex;
+use crate::json_builder::{base_link, existing_package_file, local_package_link, local_package_path, package_content_id};
+use crate::utils::write_atomic;
+use crate::CategoryData;
+
+// Marks index bundle files, and the version of their layout, bumped whenever older builds could not read it
+const BUNDLE_FORMAT: &str = "fpkgi-index";
+const BUNDLE_VERSION: u64 = 1;
+
+/// The parsed entries of a library (metadata only, no packages or icons), as written by `export-index`.
+#[derive(Serialize, Deserialize)]
+struct IndexBundle {
+    format: String,
+    version: u64,
+    /// What `--url` links started with on the exporting machine, moved to the importing one's
+    base_url: String,
+    /// Every category, including empty ones, so an import writes the same set of files
+    categories: Vec<String>,
+    packages: Vec<BundledPackage>,
+}
+
+/// One entry of the bundle along with where it is listed.
+#[derive(Serialize, Deserialize)]
+struct BundledPackage {
+    category: String,
+    /// Download URL on the exporting machine
+    url: String,
+    /// Path below the packages directory (`/`-separated) of local packages, linked under the importing `--url`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rel_path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_id: Option<String>,
+    entry: PackageEntry,
+}
+
+/// Indexes the library like `generate` and writes its entries to the bundle `file` instead of category files.
+pub async fn run_export_index(args: GenerateArgs, file: &Path) -> Result<()> {
+    let index = FpkgiIndex::generate(args.clone(), CancellationToken::new()).await?;
+    // Content ids come from the package headers, which are read again
+    let bundle = task::spawn_blocking(move || bundle_index(&args, &index)).await?;
+    write_atomic(file, &serde_json::to_vec_pretty(&bundle)?)
+        .with_context(|| format!("Failed to write index bundle to {}", file.display()))?;
+    info!("Exported {} packages in {} categories to {}", bundle.packages.len(), bundle.categories.len(), file.display());
+    Ok(())
+}
+
+/// Writes the category files (and other outputs) of `args` from the bundle `file`, without reading any package.
+///
+/// Local packages are linked under `--url` and `--packages` of `args`, so the packages may live elsewhere
+/// than on the exporting machine as long as their relative paths are kept.
+pub async fn run_import_index(args: GenerateArgs, file: &Path) -> Result<()> {
+    crate::check_output_options(&args)?;
+    let data = fs::read(file).with_context(|| format!("Failed to read index bundle {}", file.display()))?;
+    let bundle = parse_bundle(&data)?;
+    let base_url = base_link(&args, "");
+
+    let mut processed_data: HashMap<String, CategoryData> =
+        bundle.categories.into_iter().map(|category| (category, HashMap::new())).collect();
+    let count = bundle.packages.len();
+    for mut package in bundle.packages {
+        let url = match &package.rel_path {
+            // Bundled contents keep the `#<content id>` fragment that tells them apart
+            Some(rel_path) => match package.url.split_once('#') {
+                Some((_, fragment)) => format!("{}#{}", local_package_link(&args, rel_path), fragment),
+                None => local_package_link(&args, rel_path),
+            },
+            None => package.url,
+        };
+        if let Some(cover_url) = &mut package.entry.cover_url {
+            if let Some(path) = cover_url.strip_prefix(&bundle.base_url).filter(|_| !bundle.base_url.is_empty()) {
+                *cover_url = format!("{}{}", base_url, path);
+            }
+        }
+        processed_data.entry(package.category).or_default().insert(url, package.entry);
+    }
+    crate::write_outputs(&args, processed_data, None)?;
+    info!("Imported {} packages from {}", count, file.display());
+    Ok(())
+}
+
+fn bundle_index(args: &GenerateArgs, index: &FpkgiIndex) -> IndexBundle {
+    let packages = index.entries()
+        .map(|package| {
+            let pkg_path = local_package_path(args, package.url);
+            let rel_path = pkg_path.as_ref()
+                .and_then(|path| path.strip_prefix(&args.packages.0).ok())
+                .map(|path| path.to_string_lossy().replace(std::path::MAIN_SEPARATOR, "/"));
+            let content_id = match package.url.split_once('#') {
+                Some((_, content_id)) => Some(content_id.to_string()),
+                None => pkg_path.as_deref().and_then(existing_package_file).and_then(|path| package_content_id(&path)),
+            };
+            BundledPackage {
+                category: package.category.to_string(),
+                url: package.url.to_string(),
+                rel_path,
+                content_id,
+                entry: package.entry.clone(),
+            }
+        })
+        .collect();
+    IndexBundle {
+        format: BUNDLE_FORMAT.to_string(),
+        version: BUNDLE_VERSION,
+        base_url: base_link(args, ""),
+        categories: index.categories().map(str::to_string).collect(),
+        packages,
+    }
+}
+
+/// Reads a bundle, refusing files of another format or layout version.
+fn parse_bundle(data: &[u8]) -> Result<IndexBundle> {
+    let value: JsonValue = serde_json::from_slice(data)
+        .context(CommandError::InvalidConfig("Index bundle is not valid JSON".to_string()))?;
+    if value.get("format").and_then(JsonValue::as_str) != Some(BUNDLE_FORMAT) {
+        return Err(CommandError::InvalidConfig("Not an index bundle written by export-index".to_string()).into());
+    }
+    match value.get("version").and_then(JsonValue::as_u64) {
+        Some(BUNDLE_VERSION) => {}
+        Some(version) => return Err(CommandError::InvalidConfig(format!(
+            "Index bundle version {} is not supported (expected {})", version, BUNDLE_VERSION)).into()),
+        None => return Err(CommandError::InvalidConfig("Index bundle has no version".to_string()).into()),
+    }
+    serde_json::from_value(value).context(CommandError::InvalidConfig("Invalid index bundle".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run_generate;
+    use crate::test_fixtures::{game, generate_args, write_library};
+
+    fn category_files(out: &Path) -> Vec<(String, JsonValue)> {
+        let mut files: Vec<_> = fs::read_dir(out).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+            .map(|path| {
+                let document = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+                (path.file_name().unwrap().to_string_lossy().into_owned(), document)
+            })
+            .collect();
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+        files
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn imports_what_a_generate_on_the_importing_machine_would_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let library = write_library(dir.path(), &[("a.pkg", game("CUSA00001")), ("b.pkg", game("CUSA00002"))]);
+        fs::create_dir(library.join("sub")).unwrap();
+        fs::write(library.join("sub/c.pkg"), game("CUSA00003")).unwrap();
+        let bundle = dir.path().join("bundle.json");
+        run_export_index(generate_args(&library, &dir.path().join("exported"), &[]), &bundle).await.unwrap();
+
+        // The library moves to another machine, served under another URL
+        let moved = dir.path().join("moved");
+        fs::rename(&library, &moved).unwrap();
+        let mut imported = generate_args(&moved, &dir.path().join("imported"), &[]);
+        imported.url = "http://nas:9000".to_string();
+        run_import_index(imported.clone(), &bundle).await.unwrap();
+        let mut generated = imported;
+        generated.out.0 = dir.path().join("generated");
+        run_generate(generated).await.unwrap();
+
+        let imported = category_files(&dir.path().join("imported"));
+        assert_eq!(imported, category_files(&dir.path().join("generated")));
+        let games = &imported.iter().find(|(name, _)| name == "games.json").unwrap().1;
+        let links: Vec<_> = games["DATA"].as_object().unwrap().keys().cloned().collect();
+        assert_eq!(links.len(), 3);
+        assert!(links.iter().all(|link| link.starts_with("http://nas:9000/")), "{:?}", links);
+    }
+
+    #[test]
+    fn refuses_bundles_of_another_format_or_version() {
+        let bundle = |format: &str, version: JsonValue| serde_json::json!({
+            "format": format, "version": version, "base_url": "", "categories": [], "packages": [],
+        }).to_string();
+        assert!(parse_bundle(bundle(BUNDLE_FORMAT, BUNDLE_VERSION.into()).as_bytes()).is_ok());
+
+        let error = |data: &str| parse_bundle(data.as_bytes()).err().unwrap().to_string();
+        assert_eq!(error("{"), "Index bundle is not valid JSON");
+        assert_eq!(error(&bundle("fpkgi-store", 1.into())), "Not an index bundle written by export-index");
+        assert_eq!(error(&bundle(BUNDLE_FORMAT, 2.into())),
+                   "Index bundle version 2 is not supported (expected 1)");
+        assert_eq!(error(&bundle(BUNDLE_FORMAT, JsonValue::Null)), "Index bundle has no version");
+    }
+}
@@ -1,36 +1,77 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::fs;
 use std::fs::File;
-use std::path::Path;
+use std::io::{self, IsTerminal, Read};
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::read::GzDecoder;
+use image::ImageFormat;
+use indicatif::{ProgressBar, ProgressStyle};
+use image::imageops::FilterType;
+use serde::Serialize;
 use serde_json::{Value as JsonValue, from_reader, to_value};
-use log::{info, error, debug};
+use log::{info, warn, error, debug};
+use tokio_util::sync::CancellationToken;
 use walkdir::WalkDir;
-use percent_encoding::{utf8_percent_encode, CONTROLS, AsciiSet};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, CONTROLS, NON_ALPHANUMERIC, AsciiSet};
+use regex::Regex;
 
-use crate::args::GenerateArgs;
-use crate::sfo_processor;
-use crate::ps4_package::PS4Package;
+use crate::args::{GenerateArgs, MergePrecedence, UrlEncodeMode};
+use crate::entry::{PackageEntry, PackageSize};
+use crate::error::CommandError;
+use crate::enums::ContentCategory;
+use crate::icon_cache::IconCache;
+use crate::icon_state::IconState;
+use crate::content_hash::{ContentHashes, HashCheck};
+use crate::ndjson::NdjsonWriter;
+use crate::signing::{strip_signature, UrlSigner};
+use crate::CategoryCounts;
+use crate::remote::{self, HttpRangeReader};
+use crate::vita::{is_vita_package, VitaPackage};
+use crate::sfo_processor::SFOProcessor;
+use crate::utils::{for_each_prepared, relative_url, wildcard_match, write_atomic};
+use crate::ps4_package::{PS4Package, PackageError, is_gzip_package};
+use crate::split::{is_split_package, SplitReader};
 
+// SFO categories to output files; "demo" is assigned from the PKG header, as demos share the "gd" SFO category
 const CATEGORY_MAP: &[(&str, &str)] = &[
-    ("gd", "games"), ("gp", "updates"), ("ac", "DLC"), ("gde", "homebrew")
+    ("gd", "games"), ("gp", "updates"), ("ac", "DLC"), ("gde", "homebrew"), ("demo", "demos")
 ];
 
+// Vita SFO categories to output files, kept apart from the PS4 lists FPKGi reads
+const VITA_CATEGORY_MAP: &[(&str, &str)] = &[
+    ("gd", "vita_games"), ("gp", "vita_updates"), ("ac", "vita_DLC")
+];
+
+// Fields a per-package `<pkg>.meta.json` sidecar may override
+const SIDECAR_FIELDS: &[&str] = &["region", "name", "min_fw", "version"];
+
+// SFO keys consulted when the schema's primary key is missing or blank
+const SFO_FALLBACK_KEYS: &[(&str, &str)] = &[("APP_VER", "VERSION")];
+
+/// Entries of one category, keyed by package URL.
+pub type CategoryData = HashMap<String, PackageEntry>;
+
 // Custom fragment set: CONTROLS plus space
 const CONTROLS_WITH_SPACE: &AsciiSet = &CONTROLS.add(b' ');
+// Everything except alphanumerics, unreserved marks, and the path separator
+const STRICT_PATH: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~').remove(b'/');
+// RFC 3986 path characters: unreserved, sub-delims, ':' and '@' pass through
+const RFC3986_PATH: &AsciiSet = &STRICT_PATH
+    .remove(b'!').remove(b'$').remove(b'&').remove(b'\'').remove(b'(').remove(b')')
+    .remove(b'*').remove(b'+').remove(b',').remove(b';').remove(b'=').remove(b':').remove(b'@');
 
-fn build_json_schema<'a>(icon_link: Option<String>, pkg_bytes: u64) -> Vec<(Option<&'a str>, &'a str, Option<String>, Option<u64>)> {
-    vec![
-        (Some("TITLE_ID"), "title_id", None, None),
-        (None, "region", None, None),
-        (Some("TITLE"), "name", None, None),
-        (Some("APP_VER"), "version", None, None),
-        (None, "release", None, None),
-        (None, "size", None, Some(pkg_bytes)),
-        (None, "min_fw", None, None),
-        (None, "cover_url", icon_link, None),
-    ]
+fn url_encode_set(mode: UrlEncodeMode) -> &'static AsciiSet {
+    match mode {
+        UrlEncodeMode::Strict => STRICT_PATH,
+        UrlEncodeMode::Minimal => CONTROLS_WITH_SPACE,
+        UrlEncodeMode::Rfc3986 => RFC3986_PATH,
+    }
 }
 
 fn parse_region_from_content_id(content_id: &str) -> String {
@@ -43,124 +84,1161 @@ fn parse_region_from_content_id(content_id: &str) -> String {
     }
 }
 
-fn convert_sfo_to_json(base_link: &str, pkg_link: &str, pkg_bytes: u64, icon_path: Option<String>,
-                       sfo_data: HashMap<String, String>, content_id: &str) -> (String, String, HashMap<String, JsonValue>) {
-    let icon_link = icon_path.map(|p| format!("{}/{}", base_link, p));
-    let mut json_output = HashMap::new();
-    let region = parse_region_from_content_id(content_id);
+/// Looks up `key` in the SFO, trimmed, falling back to its alternate key when absent or blank.
+fn sfo_value(sfo_data: &HashMap<String, String>, key: &str) -> Option<String> {
+    let lookup = |k: &str| sfo_data.get(k).map(|v| v.trim()).filter(|v| !v.is_empty());
+    lookup(key)
+        .or_else(|| SFO_FALLBACK_KEYS.iter().find(|&&(primary, _)| primary == key).and_then(|&(_, alt)| lookup(alt)))
+        .map(str::to_string)
+}
 
-    for (source, target, default_str, default_int) in build_json_schema(icon_link, pkg_bytes) {
-        let value = if let Some(sfo_key) = source {
-            sfo_data.get(sfo_key).cloned().map(JsonValue::String)
-        } else if target == "region" {
-            Some(JsonValue::String(region.clone()))
-        } else if target == "size" {
-            default_int.map(|n| JsonValue::Number(serde_json::Number::from(n)))
-        } else if let Some(s) = default_str {
-            Some(JsonValue::String(s))
-        } else {
-            Some(JsonValue::Null)
-        };
-        json_output.insert(target.to_string(), value.unwrap_or(JsonValue::Null));
+/// Joins `path` onto `--url`, or onto the `--local-paths` prefix (if any) in local mode.
+pub fn base_link(args: &GenerateArgs, path: &str) -> String {
+    match &args.local_paths {
+        Some(None) => path.to_string(),
+        Some(Some(prefix)) => format!("{}/{}", prefix.trim_end_matches('/'), path),
+        None => format!("{}/{}", args.url, path),
+    }
+}
+
+/// The `cover_url` of the package at `pkg_link` for the absolute `icon_url`, honoring `--relative-icon-urls`.
+fn cover_link(args: &GenerateArgs, pkg_link: &str, icon_url: &str) -> String {
+    if args.relative_icon_urls {
+        relative_url(pkg_link, icon_url)
+    } else {
+        icon_url.to_string()
+    }
+}
+
+/// Collapses runs of whitespace (including newlines) to single spaces, optionally dropping ™/®/© first.
+fn clean_title(title: &str, strip_symbols: bool) -> String {
+    let title: String = if strip_symbols {
+        title.chars().filter(|c| !matches!(c, '\u{2122}' | '\u{00AE}' | '\u{00A9}')).collect()
+    } else {
+        title.to_string()
+    };
+    title.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn convert_sfo_to_json(args: &GenerateArgs, pkg_link: &str, pkg_bytes: u64, icon_path: Option<String>,
+                       sfo_data: &HashMap<String, String>, content_id: &str) -> (String, String, PackageEntry) {
+    let sfo_field = |key: &str, target: &str| {
+        let value = sfo_value(sfo_data, key);
+        if value.is_none() {
+            debug!("SFO key {} not present for {}, emitting null {}", key, content_id, target);
+        }
+        value
+    };
+    let size = if args.size_as_string { PackageSize::Text(pkg_bytes.to_string()) } else { PackageSize::Number(pkg_bytes) };
+    let mut entry = PackageEntry {
+        title_id: sfo_field("TITLE_ID", "title_id"),
+        region: Some(parse_region_from_content_id(content_id)),
+        name: args.title_locale
+            .and_then(|locale| sfo_value(sfo_data, &format!("TITLE_{:02}", locale)))
+            .or_else(|| sfo_field("TITLE", "name")),
+        version: sfo_field("APP_VER", "version"),
+        release: None,
+        size: Some(size),
+        min_fw: None,
+        cover_url: icon_path.map(|p| cover_link(args, pkg_link, &base_link(args, &p))),
+        extra: BTreeMap::new(),
+    };
+    for (key, json_name) in &args.sfo_keys {
+        entry.set(json_name, sfo_field(key, json_name).map_or(JsonValue::Null, JsonValue::String));
+    }
+
+    if args.clean_titles {
+        if let Some(raw_name) = entry.name.take() {
+            entry.name = Some(clean_title(&raw_name, args.strip_title_symbols));
+            entry.extra.insert("raw_name".to_string(), JsonValue::String(raw_name));
+        }
     }
 
     let category = sfo_data.get("CATEGORY").cloned().unwrap_or_else(|| "gd".to_string());
-    (category, format!("{}/{}", base_link, pkg_link), json_output)
+    (category, pkg_link.to_string(), entry)
+}
+
+/// Whether `path` has one of the `--ext` extensions (or is a `.pkg.gz` or the first `.pkg.0` part of a split
+/// package) and none of the `--exclude-ext` ones.
+fn is_package_file(path: &Path, args: &GenerateArgs) -> bool {
+    let Some(ext) = path.extension().map(|ext| ext.to_string_lossy().to_lowercase()) else {
+        return false;
+    };
+    let listed = |exts: &[String]| exts.iter().any(|e| e.trim_start_matches('.').eq_ignore_ascii_case(&ext));
+    !listed(&args.exclude_extensions) && (listed(&args.extensions) || is_gzip_package(path) || is_split_package(path))
+}
+
+/// Returns the size of the package as downloaded, decompressing `.pkg.gz` files fully to measure them
+/// and summing the parts of split packages.
+fn package_size(path: &Path, is_gzip: bool) -> Result<u64> {
+    if is_split_package(path) {
+        Ok(SplitReader::open(path)?.size())
+    } else if is_gzip {
+        debug!("Measuring decompressed size of '{}'", path.display());
+        Ok(io::copy(&mut GzDecoder::new(File::open(path)?), &mut io::sink())?)
+    } else {
+        Ok(fs::metadata(path)?.len())
+    }
+}
+
+/// Whether `path` is a package that fails to index only because the file ends early, as while it is still
+/// being downloaded or copied: its header, entry table or `param.sfo` lies past the end of the file, or
+/// its gzip stream stops short.
+pub fn is_incomplete_package(args: &GenerateArgs, path: &Path) -> bool {
+    if !path.is_file() || !is_package_file(path, args) {
+        return false;
+    }
+    let truncated = |e: &anyhow::Error| e.chain().any(|cause| {
+        matches!(cause.downcast_ref::<PackageError>(), Some(PackageError::Truncated { .. }))
+            || cause.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::UnexpectedEof)
+    });
+    let is_gzip = is_gzip_package(path);
+    if is_gzip {
+        if gzip_stops_short(path) {
+            return true;
+        }
+    } else if let Err(e) = package_size(path, false) {
+        return truncated(&e);
+    }
+    if !is_gzip && !is_split_package(path) && is_vita_package(path) {
+        return false;
+    }
+    match PS4Package::new(path.to_path_buf()) {
+        Ok(mut pkg) => pkg.has_file("param.sfo") && pkg.get_file_limited("param.sfo", SFOProcessor::MAX_SIZE).is_err_and(|e| truncated(&e)),
+        Err(e) => truncated(&e),
+    }
+}
+
+/// Whether the gzip stream of `path` ends before its end marker. The decoder reports that as a corrupt stream,
+/// so it is told from corruption by the whole file having been read when decoding fails.
+fn gzip_stops_short(path: &Path) -> bool {
+    struct EofReader<R> {
+        inner: R,
+        reached: bool,
+    }
+    impl<R: Read> Read for EofReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let read = self.inner.read(buf)?;
+            self.reached |= read == 0 && !buf.is_empty();
+            Ok(read)
+        }
+    }
+
+    let Ok(file) = File::open(path) else { return false };
+    let mut reader = EofReader { inner: file, reached: false };
+    match io::copy(&mut GzDecoder::new(&mut reader), &mut io::sink()) {
+        Ok(_) => false,
+        Err(e) => e.kind() == io::ErrorKind::UnexpectedEof || reader.reached,
+    }
+}
+
+/// Whether `path` is a `.pkg.gz` package, which `is_incomplete_package` can only tell complete by decompressing
+/// all of it.
+pub fn is_gzip_package_file(args: &GenerateArgs, path: &Path) -> bool {
+    is_gzip_package(path) && path.is_file() && is_package_file(path, args)
+}
+
+/// Creates the `--progress` bar, hidden when disabled or when stderr is not a terminal.
+fn progress_bar(enabled: bool, total: u64) -> ProgressBar {
+    if !enabled || !io::stderr().is_terminal() {
+        return ProgressBar::hidden();
+    }
+    let progress = ProgressBar::new(total);
+    progress.set_style(
+        ProgressStyle::with_template("{bar:40} {pos}/{len} [{elapsed_precise}, ETA {eta}] {wide_msg}")
+            .unwrap_or_else(|_| ProgressStyle::default_bar())
+    );
+    progress
 }
 
-fn merge_json_values(base: &mut JsonValue, external: JsonValue) {
+/// Writes icon PNG `data` to `destination`, downscaling it to `--icon-max-size` when set.
+///
+/// With `--keep-full-icons` the original is also kept next to the thumbnail as `<name>.full.png`.
+fn save_icon(data: &[u8], destination: &Path, args: &GenerateArgs) -> Result<()> {
+    let Some(max_size) = args.icon_max_size else {
+        return write_atomic(destination, data);
+    };
+
+    let icon = image::load_from_memory_with_format(data, ImageFormat::Png)?;
+    if icon.width() <= max_size && icon.height() <= max_size {
+        debug!("Icon {}x{} already within {}px, writing as-is", icon.width(), icon.height(), max_size);
+        return write_atomic(destination, data);
+    }
+
+    let thumbnail = icon.resize(max_size, max_size, FilterType::Lanczos3);
+    let mut encoded = io::Cursor::new(Vec::new());
+    thumbnail.write_to(&mut encoded, ImageFormat::Png)?;
+    write_atomic(destination, encoded.get_ref())?;
+    debug!("Resized icon {}x{} -> {}x{}", icon.width(), icon.height(), thumbnail.width(), thumbnail.height());
+
+    if args.keep_full_icons {
+        write_atomic(&destination.with_extension("full.png"), data)?;
+    }
+    Ok(())
+}
+
+/// Extracts the title id (e.g. `CUSA00001`) embedded in a content id like `UP0001-CUSA00001_00-...`.
+fn title_id_from_content_id(content_id: &str) -> Option<String> {
+    let title_id = content_id.split('-').nth(1)?.split('_').next()?;
+    (title_id.len() == 9).then(|| title_id.to_string())
+}
+
+/// Extracts a title-id-shaped token (four letters and five digits, e.g. `CUSA12345` or `cusa-12345`) from a filename.
+fn title_id_from_filename(file_name: &str) -> Option<String> {
+    static PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"(?i)(?:^|[^a-z0-9])([a-z]{4})[-_ ]?([0-9]{5})(?:[^0-9]|$)").expect("valid title id pattern")
+    });
+    let captures = PATTERN.captures(file_name)?;
+    Some(format!("{}{}", captures[1].to_uppercase(), &captures[2]))
+}
+
+/// With `--title-id-from-filename`, fills a missing SFO `TITLE_ID` from the package's filename.
+fn fill_title_id_from_filename(args: &GenerateArgs, source: &PackageSource, sfo_data: &mut HashMap<String, String>) {
+    if !args.title_id_from_filename || sfo_value(sfo_data, "TITLE_ID").is_some() {
+        return;
+    }
+    match title_id_from_filename(&source.file_name) {
+        Some(title_id) => {
+            info!("SFO of '{}' has no TITLE_ID; using {} from its filename", source.display, title_id);
+            sfo_data.insert("TITLE_ID".to_string(), title_id);
+        }
+        None => debug!("SFO of '{}' has no TITLE_ID and its filename has none either", source.display),
+    }
+}
+
+/// Latest `app_ver` listed in a `changeinfo.xml`, comparing dotted versions numerically.
+fn changeinfo_version(xml: &str) -> Option<String> {
+    static PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r#"app_ver\s*=\s*"([^"]+)""#).expect("valid app_ver pattern")
+    });
+    let numeric = |version: &str| version.split('.').map(|part| part.trim().parse::<u32>().unwrap_or(0)).collect::<Vec<_>>();
+    PATTERN.captures_iter(xml)
+        .map(|captures| captures[1].trim().to_string())
+        .max_by_key(|version| numeric(version))
+}
+
+/// With `--homebrew-version-fallback`, fills an `APP_VER` missing from the SFO (and not covered by its `VERSION`
+/// key) with the latest version in the package's `changeinfo/changeinfo.xml`.
+fn fill_version_fallback(args: &GenerateArgs, source: &PackageSource, pkg: &mut PS4Package, sfo_data: &mut HashMap<String, String>) {
+    if !args.homebrew_version_fallback || sfo_value(sfo_data, "APP_VER").is_some() {
+        return;
+    }
+    let version = pkg.get_file("changeinfo/changeinfo.xml").ok()
+        .and_then(|xml| changeinfo_version(&String::from_utf8_lossy(&xml)));
+    match version {
+        Some(version) => {
+            info!("SFO of '{}' has no APP_VER; using version {} from changeinfo/changeinfo.xml", source.display, version);
+            sfo_data.insert("APP_VER".to_string(), version);
+        }
+        None => debug!("SFO of '{}' has no APP_VER and no changeinfo/changeinfo.xml version", source.display),
+    }
+}
+
+/// Builds minimal SFO data for packages without a param.sfo: name from the filename, title id from the content id.
+fn synthesize_sfo(file_name: &str, content_id: &str) -> HashMap<String, String> {
+    let mut sfo_data = HashMap::new();
+    let file_name = file_name.strip_suffix(".gz").or_else(|| file_name.strip_suffix(".0")).unwrap_or(file_name);
+    let name = Path::new(file_name).file_stem().unwrap_or_default().to_string_lossy();
+    sfo_data.insert("TITLE".to_string(), name.to_string());
+    if let Some(title_id) = title_id_from_content_id(content_id) {
+        sfo_data.insert("TITLE_ID".to_string(), title_id);
+    }
+    sfo_data
+}
+
+/// Applies the `--include-title-id`/`--exclude-title-id` filters; exclusion wins on conflict.
+fn is_title_id_selected(title_id: Option<&str>, include: &[String], exclude: &[String]) -> bool {
+    let matches_any = |patterns: &[String]| {
+        title_id.is_some_and(|id| patterns.iter().any(|pattern| wildcard_match(pattern, id)))
+    };
+    if matches_any(exclude) {
+        return false;
+    }
+    include.is_empty() || matches_any(include)
+}
+
+/// The output category named `name`, ignoring case (e.g. `dlc` for `DLC`), or `None` if there is no such category.
+pub fn category_name(name: &str) -> Option<&'static str> {
+    CATEGORY_MAP.iter().chain(VITA_CATEGORY_MAP)
+        .map(|&(_, category)| category)
+        .find(|category| category.eq_ignore_ascii_case(name))
+}
+
+/// Reads the `--override-category` file: `PATTERN = CATEGORY` lines, first match wins.
+///
+/// Patterns support `*`/`?` wildcards; categories are output names such as `homebrew` or `vita_games`.
+/// Blank lines and `#` comments are skipped.
+fn read_category_overrides(path: &Path) -> Result<Vec<(String, String)>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read category overrides '{}'", path.display()))?;
+    contents.lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| {
+            let (pattern, category) = line.split_once('=')
+                .map(|(pattern, category)| (pattern.trim(), category.trim()))
+                .filter(|(pattern, category)| !pattern.is_empty() && !category.is_empty())
+                .ok_or_else(|| CommandError::InvalidConfig(format!("{}:{}: expected PATTERN = CATEGORY", path.display(), number)))?;
+            let category = category_name(category)
+                .ok_or_else(|| CommandError::InvalidConfig(format!("{}:{}: unknown category '{}'", path.display(), number, category)))?;
+            Ok((pattern.to_string(), category.to_string()))
+        })
+        .collect()
+}
+
+fn apply_sidecar_overrides(pkg_path: &Path, json_entry: &mut PackageEntry) {
+    let sidecar_name = format!("{}.meta.json", pkg_path.file_name().unwrap_or_default().to_string_lossy());
+    let sidecar_path = pkg_path.with_file_name(sidecar_name);
+    if !sidecar_path.is_file() {
+        return;
+    }
+
+    let overrides: JsonValue = match File::open(&sidecar_path).map_err(anyhow::Error::from)
+        .and_then(|file| from_reader(file).map_err(anyhow::Error::from)) {
+        Ok(value) => value,
+        Err(e) => {
+            warn!("Ignoring unreadable sidecar '{}': {}", sidecar_path.display(), e);
+            return;
+        }
+    };
+
+    let JsonValue::Object(overrides) = overrides else {
+        warn!("Ignoring sidecar '{}': expected a JSON object", sidecar_path.display());
+        return;
+    };
+
+    info!("Applying sidecar overrides from: {}", sidecar_path.display());
+    for (key, value) in overrides {
+        if SIDECAR_FIELDS.contains(&key.as_str()) {
+            json_entry.set(&key, value);
+        } else {
+            debug!("Ignoring unsupported sidecar field '{}' in '{}'", key, sidecar_path.display());
+        }
+    }
+}
+
+/// Merges `external` into `base` field by field; on conflicting values `precedence` decides which one is kept.
+fn merge_json_values(base: &mut JsonValue, external: JsonValue, precedence: MergePrecedence) {
     match (base, external) {
         (JsonValue::Object(base_map), JsonValue::Object(ext_map)) => {
             for (key, ext_value) in ext_map {
                 if let Some(base_value) = base_map.get_mut(&key) {
-                    merge_json_values(base_value, ext_value);
+                    merge_json_values(base_value, ext_value, precedence);
                 } else {
                     base_map.insert(key, ext_value);
                 }
             }
         }
         (base, external) => {
-            *base = external;
+            if precedence == MergePrecedence::External || base.is_null() {
+                *base = external;
+            }
         }
     }
 }
 
-pub fn handle_packages(args: &GenerateArgs) -> Result<HashMap<String, HashMap<String, HashMap<String, JsonValue>>>> {
-    let mut output_data: HashMap<String, HashMap<String, HashMap<String, JsonValue>>> =
-        CATEGORY_MAP.iter().map(|(_, v)| (v.to_string(), HashMap::new())).collect();
+/// Splits an absolute URL into its `scheme://host[:port]` origin and the rest (path, query, fragment).
+fn split_origin(url: &str) -> Option<(&str, &str)> {
+    let authority_start = url.find("://")? + 3;
+    let authority_end = url[authority_start..].find(['/', '?', '#']).map_or(url.len(), |end| authority_start + end);
+    (authority_end > authority_start).then(|| url.split_at(authority_end))
+}
 
-    let (pkg_fs_root, pkg_url_root) = &args.packages;
-    let icon_paths = args.icons.as_ref().map(|(fs, url)| (fs, url));
-    let (_json_fs_root, _json_url_root) = &args.out;
+/// With `--rewrite-external-hosts`, moves the `url` keys and `cover_url` of external entries to the origin of
+/// `--url`, keeping their paths. Relative links are left alone.
+fn rewrite_external_hosts(args: &GenerateArgs, data: serde_json::Map<String, JsonValue>) -> serde_json::Map<String, JsonValue> {
+    let Some((origin, _)) = split_origin(&args.url).filter(|_| args.rewrite_external_hosts) else { return data };
+    let rewrite = |link: &str| match split_origin(link) {
+        Some((old_origin, rest)) if old_origin != origin => Some(format!("{}{}", origin, rest)),
+        _ => None,
+    };
+    let mut rewritten = 0;
+    let data = data.into_iter()
+        .map(|(link, mut value)| {
+            if let Some(JsonValue::String(cover_url)) = value.get_mut("cover_url") {
+                if let Some(new_cover_url) = rewrite(cover_url) {
+                    *cover_url = new_cover_url;
+                }
+            }
+            match rewrite(&link) {
+                Some(new_link) => {
+                    debug!("Rewrote external link {} to {}", link, new_link);
+                    rewritten += 1;
+                    (new_link, value)
+                }
+                None => (link, value),
+            }
+        })
+        .collect();
+    if rewritten > 0 {
+        info!("Rewrote the host of {} external links to {}", rewritten, origin);
+    }
+    data
+}
 
-    for entry in WalkDir::new(pkg_fs_root).into_iter().filter_map(Result::ok) {
-        let path = entry.path();
-        if path.extension().map_or(true, |ext| ext != "pkg") {
-            continue;
+/// Converts a `DATA` object into entries, skipping (and logging) values that are not objects.
+fn entries_from_json(data: serde_json::Map<String, JsonValue>) -> CategoryData {
+    data.into_iter()
+        .filter_map(|(link, value)| {
+            let entry = PackageEntry::from_json(value);
+            if entry.is_none() {
+                warn!("Ignoring external entry that is not a JSON object: {}", link);
+            }
+            Some((link, entry?))
+        })
+        .collect()
+}
+
+/// Finds updates and DLC whose title id has no matching entry in the games or demos categories.
+///
+/// Returns `(category, link, title_id)` tuples sorted by category and link.
+pub fn find_orphans(output_data: &HashMap<String, CategoryData>) -> Vec<(String, String, Option<String>)> {
+    let title_id_of = |entry: &PackageEntry| entry.title_id.clone();
+    let game_title_ids: HashSet<String> = ["games", "demos"].iter()
+        .filter_map(|category| output_data.get(*category))
+        .flat_map(|entries| entries.values().filter_map(title_id_of))
+        .collect();
+
+    let mut orphans = Vec::new();
+    for category in ["updates", "DLC"] {
+        let Some(entries) = output_data.get(category) else { continue };
+        for (link, entry) in entries {
+            let title_id = title_id_of(entry);
+            if title_id.as_ref().is_none_or(|id| !game_title_ids.contains(id)) {
+                orphans.push((category.to_string(), link.clone(), title_id));
+            }
         }
+    }
+    orphans.sort();
+    orphans
+}
 
-        let pkg_bytes = fs::metadata(&path)?.len();
-        let pkg_rel_path = path.strip_prefix(pkg_fs_root)?.to_string_lossy().replace(std::path::MAIN_SEPARATOR, "/");
-        let encoded_pkg_rel_path = utf8_percent_encode(&pkg_rel_path, CONTROLS_WITH_SPACE).to_string();
-        let pkg_url_path = format!("{}/{}", pkg_url_root, encoded_pkg_rel_path);
+/// Groups entries by the package subfolder their link points into, for `--per-folder-json`.
+///
+/// Entries whose link is not under the packages URL (e.g. from external JSON) land in the root folder.
+pub fn split_by_folder(args: &GenerateArgs, output_data: HashMap<String, CategoryData>)
+    -> HashMap<PathBuf, HashMap<String, CategoryData>> {
+    let pkg_link_prefix = format!("{}/", base_link(args, &args.packages.1));
+    let mut folders: HashMap<PathBuf, HashMap<String, CategoryData>> = HashMap::new();
+    for (category, entries) in output_data {
+        for (link, entry) in entries {
+            let rel_dir = link.strip_prefix(&pkg_link_prefix)
+                .map(|rel| percent_decode_str(rel).decode_utf8_lossy().to_string())
+                .and_then(|rel| Path::new(&rel).parent().map(Path::to_path_buf))
+                .unwrap_or_default();
+            folders.entry(rel_dir).or_default()
+                .entry(category.clone()).or_default()
+                .insert(link, entry);
+        }
+    }
+    folders
+}
 
-        info!("Processing package: {} ({} bytes)", path.display(), pkg_bytes);
+#[cfg(unix)]
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
 
-        let pkg = match PS4Package::new(path.to_path_buf()) {
-            Ok(pkg) => pkg,
+#[cfg(not(unix))]
+fn is_same_file(_a: &Path, _b: &Path) -> bool {
+    false
+}
+
+/// Collapses byte-identical icons under the icons directory into hardlinks of one canonical file.
+///
+/// Where hardlinking fails (e.g. unsupported filesystem), the duplicate is removed and entries'
+/// `cover_url` are pointed at the canonical copy instead.
+fn dedup_icons(args: &GenerateArgs, output_data: &mut HashMap<String, CategoryData>) -> Result<()> {
+    let Some((icon_fs_root, icon_url_root)) = &args.icons else { return Ok(()) };
+    let encode_set = url_encode_set(args.url_encode_mode);
+    let icon_url = |path: &Path| {
+        let rel_path = path.strip_prefix(icon_fs_root).unwrap_or(path).to_string_lossy().replace(std::path::MAIN_SEPARATOR, "/");
+        base_link(args, &format!("{}/{}", icon_url_root, utf8_percent_encode(&rel_path, encode_set)))
+    };
+
+    let mut icon_paths: Vec<PathBuf> = WalkDir::new(icon_fs_root).into_iter()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_file() && entry.path().extension().is_some_and(|ext| ext == "png"))
+        .map(walkdir::DirEntry::into_path)
+        .collect();
+    icon_paths.sort();
+
+    // Distinct icons seen so far, bucketed by (length, hash); buckets are confirmed byte-for-byte
+    let mut canonical: HashMap<(usize, u64), Vec<PathBuf>> = HashMap::new();
+    let mut redirects: HashMap<String, String> = HashMap::new();
+    let mut collapsed = 0;
+    for path in icon_paths {
+        let data = fs::read(&path)?;
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+        let candidates = canonical.entry((data.len(), hasher.finish())).or_default();
+        let Some(original) = candidates.iter().find(|existing| fs::read(existing).is_ok_and(|other| other == data)) else {
+            candidates.push(path);
+            continue;
+        };
+
+        if is_same_file(original, &path) {
+            continue;
+        }
+
+        let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = path.with_file_name(tmp_name);
+        let _ = fs::remove_file(&tmp_path);
+        match fs::hard_link(original, &tmp_path).and_then(|_| fs::rename(&tmp_path, &path)) {
+            Ok(()) => debug!("Hardlinked duplicate icon '{}' to '{}'", path.display(), original.display()),
             Err(e) => {
-                error!("Failed to process package '{}': {}", path.display(), e);
+                debug!("Cannot hardlink '{}' ({}), pointing its entries at '{}'", path.display(), e, original.display());
+                let _ = fs::remove_file(&tmp_path);
+                fs::remove_file(&path)?;
+                redirects.insert(icon_url(&path), icon_url(original));
+            }
+        }
+        collapsed += 1;
+    }
+
+    for (link, entry) in output_data.values_mut().flat_map(|entries| entries.iter_mut()) {
+        if let Some(cover_url) = &mut entry.cover_url {
+            let target = redirects.iter().find(|(from, _)| cover_link(args, link, from) == *cover_url);
+            if let Some((_, to)) = target {
+                *cover_url = cover_link(args, link, to);
+            }
+        }
+    }
+    info!("Deduplicated {} identical icons", collapsed);
+    Ok(())
+}
+
+/// Deletes icons under the icons directory that are named after a package (`<package>.png`, or
+/// `<package>.full.png` from `--keep-full-icons`) but were not produced for any indexed package.
+fn clean_icons(args: &GenerateArgs, live_icons: &HashSet<PathBuf>) -> Result<()> {
+    let Some((icon_fs_root, _)) = &args.icons else { return Ok(()) };
+    let mut removed = 0;
+    for entry in WalkDir::new(icon_fs_root).into_iter().filter_map(Result::ok) {
+        let path = entry.path();
+        if !entry.file_type().is_file() || live_icons.contains(path) {
+            continue;
+        }
+        let file_name = entry.file_name().to_string_lossy();
+        let Some(stem) = file_name.strip_suffix(".png") else { continue };
+        let pkg_name = stem.strip_suffix(".full").unwrap_or(stem);
+        if !is_package_file(Path::new(pkg_name), args) {
+            continue;
+        }
+        match fs::remove_file(path) {
+            Ok(()) => {
+                debug!("Removed orphaned icon '{}'", path.display());
+                removed += 1;
+            }
+            Err(e) => warn!("Failed to remove orphaned icon '{}': {}", path.display(), e),
+        }
+    }
+    info!("Removed {} orphaned icons", removed);
+    Ok(())
+}
+
+/// Drops entries linking into the packages URL whose file no longer exists on disk.
+///
+/// Entries pointing elsewhere (e.g. external-only JSON) are left untouched.
+fn prune_missing_packages(args: &GenerateArgs, output_data: &mut HashMap<String, CategoryData>) {
+    for (category, entries) in output_data.iter_mut() {
+        entries.retain(|link, _| {
+            let Some(pkg_path) = local_package_path(args, link) else { return true };
+            let exists = existing_package_file(&pkg_path).is_some();
+            if !exists {
+                info!("Pruning stale {} entry for missing package: {}", category, link);
+            }
+            exists
+        });
+    }
+}
+
+/// Appends ` (REGION) [VERSION]` to the names shared by several entries of a category, so FPKGi can tell
+/// regional variants apart; unique names are left unchanged.
+fn rename_duplicate_titles(output_data: &mut HashMap<String, CategoryData>) {
+    for (category, entries) in output_data.iter_mut() {
+        let mut name_counts: HashMap<String, usize> = HashMap::new();
+        for name in entries.values().filter_map(|entry| entry.name.clone()) {
+            *name_counts.entry(name).or_default() += 1;
+        }
+        for entry in entries.values_mut() {
+            let Some(name) = entry.name.as_mut() else { continue };
+            if name_counts.get(name.as_str()).is_none_or(|&count| count < 2) {
                 continue;
             }
-        };
+            let mut renamed = name.clone();
+            if let Some(region) = &entry.region {
+                renamed.push_str(&format!(" ({})", region));
+            }
+            if let Some(version) = &entry.version {
+                renamed.push_str(&format!(" [{}]", version));
+            }
+            debug!("Renamed duplicate {} title '{}' to '{}'", category, name, renamed);
+            *name = renamed;
+        }
+    }
+}
+
+/// Maps a generated package link back to its logical path under the packages directory.
+///
+/// Returns `None` for links outside the packages URL (e.g. external or remote entries).
+pub fn local_package_path(args: &GenerateArgs, link: &str) -> Option<PathBuf> {
+    let pkg_link_prefix = format!("{}/", base_link(args, &args.packages.1));
+    // Bundled contents share their package's file, marked with a `#<content id>` fragment
+    let link = strip_signature(link);
+    let link = link.split_once('#').map_or(link.as_str(), |(file_link, _)| file_link);
+    let rel_path = link.strip_prefix(&pkg_link_prefix)?;
+    Some(args.packages.0.join(percent_decode_str(rel_path).decode_utf8_lossy().as_ref()))
+}
 
-        let sfo_data = match sfo_processor::SFOProcessor::new().process(pkg.get_file("param.sfo").unwrap_or_default()) {
+/// The link of the package at `rel_path` (`/`-separated, below the packages directory), as `local_package_path`
+/// maps back.
+pub fn local_package_link(args: &GenerateArgs, rel_path: &str) -> String {
+    let encoded_rel_path = utf8_percent_encode(rel_path, url_encode_set(args.url_encode_mode));
+    base_link(args, &format!("{}/{}", args.packages.1, encoded_rel_path))
+}
+
+/// Content id in the header of the package file at `path`, or `None` when it does not open.
+pub fn package_content_id(path: &Path) -> Option<String> {
+    if !is_gzip_package(path) && !is_split_package(path) && is_vita_package(path) {
+        VitaPackage::open(path).ok().map(|vita| vita.content_id)
+    } else {
+        PS4Package::new(path.to_path_buf()).ok().map(|pkg| pkg.content_id)
+    }
+}
+
+/// `link` signed with `--sign-secret`, or unchanged without one or when it lies outside `--url` (remote packages).
+pub fn sign_link(args: &GenerateArgs, link: &str) -> String {
+    let Some(secret) = &args.sign_secret else { return link.to_string() };
+    match link.strip_prefix(&format!("{}/", args.url)) {
+        Some(path) => UrlSigner::new(secret, args.sign_ttl).sign(link, path.split('#').next().unwrap_or_default()),
+        None => link.to_string(),
+    }
+}
+
+/// Returns the file actually backing a logical package path: the path itself, its `.gz` sibling,
+/// or the first part of a split package.
+pub fn existing_package_file(pkg_path: &Path) -> Option<PathBuf> {
+    let with_suffix = |suffix: &str| {
+        let mut path = pkg_path.as_os_str().to_os_string();
+        path.push(suffix);
+        PathBuf::from(path)
+    };
+    [pkg_path.to_path_buf(), with_suffix(".gz"), with_suffix(".0")].into_iter().find(|path| path.is_file())
+}
+
+/// State carried across the packages of one generation run.
+struct IndexState {
+    icon_cache: Option<IconCache>,
+    /// Source package stamps of the extracted icons, to skip re-extracting unchanged ones
+    icon_state: Option<IconState>,
+    /// Content id -> first package indexed with it, for `--warn-duplicate-content-id`
+    content_ids: HashMap<String, String>,
+    /// Icon files belonging to indexed packages, kept by `--clean-icons`
+    icon_paths: HashSet<PathBuf>,
+    /// `--override-category` patterns and the categories they force
+    category_overrides: Vec<(String, String)>,
+    /// Per-package problems, counted in the run summary and written by `--report`
+    issues: Vec<PackageIssue>,
+    /// Destination of entries with `--ndjson`, instead of the collected `output_data`
+    ndjson: Option<NdjsonWriter>,
+    timings: StepTimings,
+}
+
+impl IndexState {
+    fn new(args: &GenerateArgs, ndjson: Option<NdjsonWriter>) -> Result<Self> {
+        Ok(IndexState {
+            icon_cache: args.icon_cache.as_deref().map(IconCache::open).transpose()?,
+            icon_state: args.icons.as_ref().map(|(icon_fs_root, _)| IconState::open(icon_fs_root)),
+            content_ids: HashMap::new(),
+            icon_paths: HashSet::new(),
+            category_overrides: args.override_category.as_deref().map(read_category_overrides).transpose()?.unwrap_or_default(),
+            issues: Vec::new(),
+            ndjson,
+            timings: StepTimings::default(),
+        })
+    }
+
+    /// Logs `message` as an error and records it against the package at `path`.
+    fn error(&mut self, path: &str, stage: &'static str, message: String) {
+        error!("{}", message);
+        self.issues.push(PackageIssue { path: path.to_string(), stage, level: "error", message });
+    }
+
+    /// Logs `message` as a warning and records it against the package at `path`.
+    fn warn(&mut self, path: &str, stage: &'static str, message: String) {
+        warn!("{}", message);
+        self.issues.push(PackageIssue { path: path.to_string(), stage, level: "warning", message });
+    }
+}
+
+/// A problem met while indexing one package, as listed in the `--report` file.
+#[derive(Serialize)]
+struct PackageIssue {
+    path: String,
+    /// Step that failed: `size`, `parse`, `hash`, `sfo`, `content_id` or `url`
+    stage: &'static str,
+    /// `error` when the package was left out, `warning` otherwise
+    level: &'static str,
+    message: String,
+}
+
+/// Cumulative time spent in each indexing step, logged at debug level to locate slow IO or parsing.
+#[derive(Default, Clone, Copy)]
+struct StepTimings {
+    /// Opening the package and reading its header and entry table (including size measurement)
+    parse: Duration,
+    /// Reading and decoding `param.sfo`
+    sfo: Duration,
+    /// Extracting, resizing and writing the icon
+    icon: Duration,
+    /// Computing `--content-hashes`
+    hash: Duration,
+}
+
+impl StepTimings {
+    fn since(&self, earlier: StepTimings) -> StepTimings {
+        StepTimings {
+            parse: self.parse - earlier.parse,
+            sfo: self.sfo - earlier.sfo,
+            icon: self.icon - earlier.icon,
+            hash: self.hash - earlier.hash,
+        }
+    }
+}
+
+impl std::fmt::Display for StepTimings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "parse {:.1?}, SFO {:.1?}, icon {:.1?}, hash {:.1?}", self.parse, self.sfo, self.icon, self.hash)
+    }
+}
+
+/// Format-independent facts about a package, gathered before its entry is built.
+struct PackageMetadata<'a> {
+    sfo_data: HashMap<String, String>,
+    content_id: String,
+    /// Header digest validating `--icon-cache` entries; formats without one bypass the cache
+    digest: Option<String>,
+    is_demo: bool,
+    /// SFO category -> output category for the package's platform; the first entry is the fallback
+    category_map: &'a [(&'a str, &'a str)],
+}
+
+/// Reads SFO metadata from an opened PS4 package and adds its entry to `output_data`.
+///
+/// Packages that are skipped (unreadable SFO, filtered title id) are logged and leave `output_data` unchanged.
+fn index_package(args: &GenerateArgs, source: &PackageSource, mut pkg: PS4Package,
+                 state: &mut IndexState, output_data: &mut HashMap<String, CategoryData>) -> Result<()> {
+    let started = Instant::now();
+    let mut sfo_data = if pkg.has_file("param.sfo") {
+        match SFOProcessor::new().read(&mut pkg, "param.sfo") {
             Ok(data) => data,
             Err(e) => {
-                error!("Failed to parse SFO for '{}': {}", path.display(), e);
-                continue;
+                state.error(&source.display, "sfo", format!("Failed to parse SFO for '{}': {}", source.display, e));
+                return Ok(());
             }
-        };
+        }
+    } else if args.require_sfo {
+        state.warn(&source.display, "sfo", format!("Skipping '{}': package has no param.sfo", source.display));
+        return Ok(());
+    } else {
+        state.warn(&source.display, "sfo",
+                   format!("No param.sfo in '{}', synthesizing entry from content id and filename", source.display));
+        synthesize_sfo(&source.file_name, &pkg.content_id)
+    };
+    state.timings.sfo += started.elapsed();
+
+    // The header content id is authoritative; a different SFO one usually means a tampered or mismatched package
+    if let Some(sfo_content_id) = sfo_data.get("CONTENT_ID").filter(|id| !id.is_empty() && **id != pkg.content_id) {
+        if args.strict {
+            state.warn(&source.display, "content_id", format!("Skipping '{}': header content id {} does not match SFO content id {}",
+                                                              source.display, pkg.content_id, sfo_content_id));
+            return Ok(());
+        }
+        state.warn(&source.display, "content_id", format!("Header content id {} of '{}' does not match SFO content id {}; using the header's",
+                                                          pkg.content_id, source.display, sfo_content_id));
+    }
+
+    fill_title_id_from_filename(args, source, &mut sfo_data);
+    fill_version_fallback(args, source, &mut pkg, &mut sfo_data);
+    let source = &with_templated_link(args, source, &sfo_data, &pkg.content_id);
+
+    // Without an SFO category, fall back to the header flags to tell patches from apps
+    if !sfo_data.contains_key("CATEGORY") && pkg.content_flags.is_patch() {
+        debug!("Categorizing '{}' as an update from its content flags", source.display);
+        sfo_data.insert("CATEGORY".to_string(), "gp".to_string());
+    }
+
+    let metadata = PackageMetadata {
+        sfo_data,
+        content_id: pkg.content_id.clone(),
+        digest: Some(pkg.hashes.concat()),
+        is_demo: pkg.content_type == ContentCategory::Demo,
+        category_map: CATEGORY_MAP,
+    };
+    add_entry(args, source, metadata, || pkg.get_file("icon0.png"), state, output_data)?;
+
+    for (sfo_name, icon_name) in pkg.extra_contents() {
+        index_extra_content(args, source, &mut pkg, &sfo_name, &icon_name, state, output_data)?;
+    }
+    Ok(())
+}
+
+/// Adds an entry for an additional content bundled in a package (e.g. a day-one patch next to the game).
+///
+/// The entry links to the same file, told apart by a `#<content id>` fragment, and gets its own icon.
+fn index_extra_content(args: &GenerateArgs, source: &PackageSource, pkg: &mut PS4Package, sfo_name: &str, icon_name: &str,
+                       state: &mut IndexState, output_data: &mut HashMap<String, CategoryData>) -> Result<()> {
+    let sfo_data = match SFOProcessor::new().read(pkg, sfo_name) {
+        Ok(data) => data,
+        Err(e) => {
+            state.error(&source.display, "sfo", format!("Failed to parse bundled SFO '{}' in '{}': {}", sfo_name, source.display, e));
+            return Ok(());
+        }
+    };
+    let content_id = sfo_data.get("CONTENT_ID").cloned().unwrap_or_else(|| sfo_name.to_string());
+    info!("Indexing bundled content {} in '{}'", content_id, source.display);
+
+    let extra_source = PackageSource {
+        display: format!("{} [{}]", source.display, content_id),
+        file_name: format!("{}.{}", source.file_name, content_id),
+        icon_rel_dir: source.icon_rel_dir.clone(),
+        link: format!("{}#{}", source.link, utf8_percent_encode(&content_id, url_encode_set(args.url_encode_mode))),
+        rel_path: None,
+        size: source.size,
+        // Sidecar overrides describe the package's own content
+        local_path: None,
+    };
+    let metadata = PackageMetadata {
+        sfo_data,
+        content_id,
+        digest: Some(pkg.hashes.concat()),
+        is_demo: false,
+        category_map: CATEGORY_MAP,
+    };
+    add_entry(args, &extra_source, metadata, || pkg.get_file(icon_name), state, output_data)
+}
+
+/// Reads metadata from a PS Vita package and adds its entry to the Vita categories of `output_data`.
+///
+/// Retail `.pkg` files keep their SFO encrypted, so their entry is synthesized from the content id and filename.
+fn index_vita_package(args: &GenerateArgs, source: &PackageSource, vita: VitaPackage,
+                      state: &mut IndexState, output_data: &mut HashMap<String, CategoryData>) -> Result<()> {
+    let mut sfo_data = match vita.sfo.clone() {
+        Some(sfo_data) => sfo_data,
+        None if args.require_sfo => {
+            state.warn(&source.display, "sfo", format!("Skipping '{}': package has no readable param.sfo", source.display));
+            return Ok(());
+        }
+        None => synthesize_sfo(&source.file_name, &vita.content_id),
+    };
+    if let (false, Some(category)) = (sfo_data.contains_key("CATEGORY"), vita.category) {
+        sfo_data.insert("CATEGORY".to_string(), category.to_string());
+    }
+    fill_title_id_from_filename(args, source, &mut sfo_data);
+    let source = &with_templated_link(args, source, &sfo_data, &vita.content_id);
+
+    let metadata = PackageMetadata {
+        sfo_data,
+        content_id: vita.content_id.clone(),
+        digest: None,
+        is_demo: false,
+        category_map: VITA_CATEGORY_MAP,
+    };
+    add_entry(args, source, metadata, || vita.icon(), state, output_data)
+}
+
+/// Output category of a package of SFO category `cat` (a demo by its header when `is_demo`), along with the
+/// `--override-category` pattern and category that move it elsewhere, if any.
+fn output_category<'a>(category_map: &'a [(&'a str, &'a str)], cat: &str, is_demo: bool, overrides: &'a [(String, String)],
+                       title_id: Option<&str>, content_id: &str) -> (&'a str, Option<&'a (String, String)>) {
+    let cat = if cat == "gd" && is_demo { "demo" } else { cat };
+    let category = category_map.iter().find(|&&(k, _)| k == cat).unwrap_or(&category_map[0]).1;
+    let forced = overrides.iter()
+        .find(|(pattern, _)| title_id.is_some_and(|id| wildcard_match(pattern, id)) || wildcard_match(pattern, content_id));
+    (category, forced.filter(|(_, forced)| forced != category))
+}
 
-        let icon_path = if let Some((icon_fs_root, icon_url_root)) = icon_paths {
-            let rel_dir = path.parent()
-                .unwrap_or(Path::new(""))
-                .strip_prefix(pkg_fs_root)
-                .unwrap_or(Path::new(""));
-            let icon_name = format!("{}.png", path.file_name().unwrap().to_string_lossy());
-            let icon_rel_path = rel_dir.join(&icon_name);
-            let encoded_icon_rel_path = utf8_percent_encode(&icon_rel_path.to_string_lossy(), CONTROLS_WITH_SPACE).to_string();
-            let icon_fullpath = icon_fs_root.join(&icon_rel_path);
+/// Returns `source` with its link built from `--package-url-template`, when one is set and the package is local.
+fn with_templated_link<'a>(args: &GenerateArgs, source: &PackageSource<'a>, sfo_data: &HashMap<String, String>,
+                           content_id: &str) -> PackageSource<'a> {
+    let (Some(template), Some(rel_path)) = (&args.package_url_template, &source.rel_path) else {
+        return source.clone();
+    };
+    let encode_set = url_encode_set(args.url_encode_mode);
+    let encode = |value: &str| utf8_percent_encode(value, encode_set).to_string();
+    let title_id = sfo_value(sfo_data, "TITLE_ID").or_else(|| title_id_from_content_id(content_id)).unwrap_or_default();
+    let file_name = rel_path.rsplit('/').next().unwrap_or(rel_path);
+    let link = fill_placeholders(template, |name| Some(match name {
+        "base_url" => args.url.clone(),
+        "rel_path" => encode(rel_path),
+        "filename" => encode(file_name),
+        "title_id" => encode(&title_id),
+        "region" => parse_region_from_content_id(content_id),
+        "content_id" => encode(content_id),
+        _ => return None,
+    }));
+    debug!("Templated link for '{}': {}", source.display, link);
+    PackageSource { link, ..source.clone() }
+}
 
-            if let Some(parent) = icon_fullpath.parent() {
-                fs::create_dir_all(parent)?;
+/// Replaces the `{name}` placeholders in `template` with `value(name)` in a single pass, so braces in the
+/// substituted values are never expanded again; unknown names are kept as they are.
+fn fill_placeholders(template: &str, value: impl Fn(&str) -> Option<String>) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some((head, tail)) = rest.split_once('{') {
+        filled.push_str(head);
+        match tail.split_once('}').and_then(|(name, tail)| Some((value(name)?, tail))) {
+            Some((value, tail)) => {
+                filled.push_str(&value);
+                rest = tail;
+            }
+            None => {
+                filled.push('{');
+                rest = tail;
             }
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+/// Builds the JSON entry for a package, extracting its icon via `load_icon`, and inserts it into `output_data`.
+fn add_entry(args: &GenerateArgs, source: &PackageSource, metadata: PackageMetadata,
+             load_icon: impl FnOnce() -> Result<Vec<u8>>, state: &mut IndexState,
+             output_data: &mut HashMap<String, CategoryData>) -> Result<()> {
+    let encode_set = url_encode_set(args.url_encode_mode);
+    let PackageMetadata { sfo_data, content_id, digest, is_demo, category_map } = metadata;
+
+    let title_id = sfo_value(&sfo_data, "TITLE_ID");
+    if !is_title_id_selected(title_id.as_deref(), &args.include_title_ids, &args.exclude_title_ids) {
+        debug!("Skipping '{}': title id {:?} filtered out", source.display, title_id);
+        return Ok(());
+    }
+
+    if source.size > args.warn_size_over {
+        state.warn(&source.display, "size", format!("Package '{}' is {} bytes, over the {} byte threshold; some FPKGi builds may misreport its size",
+                                                    source.display, source.size, args.warn_size_over));
+    }
 
-            if let Err(e) = pkg.save_file("icon0.png", &icon_fullpath) {
-                info!("No icon extracted for '{}': {}", path.display(), e);
+    // Deep folders and long names add up; the console then fails the download without saying why
+    if args.warn_url_length > 0 && source.link.len() > args.warn_url_length {
+        state.warn(&source.display, "url", format!(
+            "Package URL of '{}' is {} characters, over the {} character threshold; some consoles fail to download it. \
+             Move the package to a shallower folder or shorten its name", source.display, source.link.len(), args.warn_url_length));
+    }
+
+    if args.warn_duplicate_content_id && !content_id.is_empty() {
+        if let Some(first) = state.content_ids.get(&content_id) {
+            let message = format!("Duplicate content id {}: '{}' and '{}'", content_id, first, source.display);
+            state.warn(&source.display, "content_id", message);
+        } else {
+            state.content_ids.insert(content_id.clone(), source.display.clone());
+        }
+    }
+
+    let started = Instant::now();
+    let icon_path = if let Some((icon_fs_root, icon_url_root)) = &args.icons {
+        let icon_name = format!("{}.png", source.file_name);
+        let icon_rel_path = source.icon_rel_dir.join(&icon_name);
+        let encoded_icon_rel_path = utf8_percent_encode(&icon_rel_path.to_string_lossy(), encode_set).to_string();
+        let icon_fullpath = icon_fs_root.join(&icon_rel_path);
+        state.icon_paths.insert(icon_fullpath.clone());
+        state.icon_paths.insert(icon_fullpath.with_extension("full.png"));
+
+        if let Some(parent) = icon_fullpath.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // An icon written from the same package file (size and mtime) needs no extraction at all
+        let up_to_date = match (state.icon_state.as_mut(), source.local_path) {
+            (Some(icon_state), Some(pkg_path)) =>
+                icon_state.is_current(&icon_fullpath, pkg_path, args.icon_max_size, args.keep_full_icons),
+            _ => false,
+        };
+        // The cache is keyed by content id and only trusted when the header digest still matches
+        let cache = state.icon_cache.as_mut().zip(digest.as_deref()).filter(|_| !content_id.is_empty());
+        let cached = !up_to_date && cache.as_ref()
+            .is_some_and(|(cache, digest)| cache.restore(&content_id, digest, args.icon_max_size, &icon_fullpath));
+        let record = |icon_state: Option<&mut IconState>| {
+            if let (Some(icon_state), Some(pkg_path)) = (icon_state, source.local_path) {
+                icon_state.record(&icon_fullpath, pkg_path, args.icon_max_size, args.keep_full_icons);
             }
-            debug!("Extracted icon to '{}'", icon_fullpath.display());
-            Some(format!("{}/{}", icon_url_root, encoded_icon_rel_path))
+        };
+        if up_to_date {
+            debug!("Kept icon of unchanged package '{}'", source.display);
+        } else if cached {
+            debug!("Reused cached icon for {} at '{}'", content_id, icon_fullpath.display());
+            record(state.icon_state.as_mut());
+        } else if let Err(e) = load_icon().and_then(|data| save_icon(&data, &icon_fullpath, args)) {
+            info!("No icon extracted for '{}': {}", source.display, e);
         } else {
-            None
+            debug!("Extracted icon to '{}'", icon_fullpath.display());
+            record(state.icon_state.as_mut());
+            if let Some((cache, digest)) = cache {
+                if let Err(e) = cache.store(&content_id, digest, args.icon_max_size, &icon_fullpath) {
+                    warn!("Failed to cache icon for {}: {}", content_id, e);
+                }
+            }
+        }
+        Some(format!("{}/{}", icon_url_root, encoded_icon_rel_path))
+    } else {
+        None
+    };
+    state.timings.icon += started.elapsed();
+
+    let (cat, link, mut json_entry) = convert_sfo_to_json(
+        args,
+        &source.link,
+        source.size,
+        icon_path,
+        &sfo_data,
+        &content_id
+    );
+    if args.include_sfo {
+        json_entry.extra.insert("sfo".to_string(), to_value(&sfo_data)?);
+    }
+    if let Some(local_path) = source.local_path {
+        apply_sidecar_overrides(local_path, &mut json_entry);
+    }
+    let (mut category, forced) = output_category(category_map, &cat, is_demo, &state.category_overrides, title_id.as_deref(), &content_id);
+    if let Some((pattern, forced)) = forced {
+        info!("Overriding category of '{}': {} -> {} (matched '{}')", source.display, category, forced, pattern);
+        category = forced;
+    }
+    match &mut state.ndjson {
+        Some(ndjson) => ndjson.write(category, &sign_link(args, &link), &json_entry)?,
+        None => {
+            output_data.entry(category.to_string()).or_default().insert(link, json_entry);
+        }
+    }
+    Ok(())
+}
+
+/// A package about to be indexed, with everything that depends on where it was read from.
+#[derive(Clone)]
+struct PackageSource<'a> {
+    /// Shown in log messages
+    display: String,
+    /// File name, used to synthesize a title and to name the extracted icon
+    file_name: String,
+    /// Directory of the extracted icon, relative to the icons root
+    icon_rel_dir: PathBuf,
+    /// Full download URL written to the entry
+    link: String,
+    /// Logical path below the packages root (`.gz` stripped) for local packages, filling `--package-url-template`
+    rel_path: Option<String>,
+    size: u64,
+    /// On-disk path for local packages, where sidecar overrides are looked up
+    local_path: Option<&'a Path>,
+}
+
+/// The source of the local package at `path`, below the packages directory, of `size` bytes.
+fn local_source<'a>(args: &GenerateArgs, path: &'a Path, size: u64) -> Result<PackageSource<'a>> {
+    let pkg_fs_root = &args.packages.0;
+    let pkg_rel_path = path.strip_prefix(pkg_fs_root)?.to_string_lossy().replace(std::path::MAIN_SEPARATOR, "/");
+    // Compressed and split packages are linked by their logical .pkg name; the server decompresses
+    // or joins the parts on download
+    let pkg_rel_path = if is_gzip_package(path) {
+        pkg_rel_path[..pkg_rel_path.len() - 3].to_string()
+    } else if is_split_package(path) {
+        pkg_rel_path[..pkg_rel_path.len() - 2].to_string()
+    } else {
+        pkg_rel_path
+    };
+    Ok(PackageSource {
+        display: path.display().to_string(),
+        file_name: path.file_name().unwrap_or_default().to_string_lossy().to_string(),
+        icon_rel_dir: path.parent()
+            .unwrap_or(Path::new(""))
+            .strip_prefix(pkg_fs_root)
+            .unwrap_or(Path::new(""))
+            .to_path_buf(),
+        link: local_package_link(args, &pkg_rel_path),
+        rel_path: Some(pkg_rel_path),
+        size,
+        local_path: Some(path),
+    })
+}
+
+/// Categories the local package at `path` is indexed into, told from its header and SFO category the way
+/// indexing does, without building its entries. Packages the filters would skip still count for their category.
+/// `None` when that cannot be told: the file is gone, not a package, or does not open.
+pub fn package_categories(args: &GenerateArgs, path: &Path) -> Option<BTreeSet<String>> {
+    if !path.is_file() || !is_package_file(path, args) {
+        return None;
+    }
+    let is_gzip = is_gzip_package(path);
+    // Measuring a .pkg.gz means decompressing all of it, so those are not held to --min-size/--max-size here
+    if !is_gzip {
+        let size = package_size(path, false).ok()?;
+        if args.min_size.is_some_and(|min| size < min) || args.max_size.is_some_and(|max| size > max) {
+            return Some(BTreeSet::new());
+        }
+    }
+    let overrides = args.override_category.as_deref().map(read_category_overrides).transpose().ok()?.unwrap_or_default();
+    let file_name = path.file_name()?.to_string_lossy();
+
+    // SFO values, header demo flag and content id of the package and each content bundled in it
+    let mut contents = Vec::new();
+    let category_map = if !is_gzip && !is_split_package(path) && is_vita_package(path) {
+        let vita = VitaPackage::open(path).ok()?;
+        let mut sfo_data = vita.sfo.unwrap_or_else(|| synthesize_sfo(&file_name, &vita.content_id));
+        if let (false, Some(category)) = (sfo_data.contains_key("CATEGORY"), vita.category) {
+            sfo_data.insert("CATEGORY".to_string(), category.to_string());
+        }
+        contents.push((sfo_data, false, vita.content_id));
+        VITA_CATEGORY_MAP
+    } else {
+        let mut pkg = PS4Package::new(path.to_path_buf()).ok()?;
+        let mut sfo_data = match pkg.has_file("param.sfo") {
+            true => SFOProcessor::new().read(&mut pkg, "param.sfo").ok()?,
+            false => synthesize_sfo(&file_name, &pkg.content_id),
         };
+        if !sfo_data.contains_key("CATEGORY") && pkg.content_flags.is_patch() {
+            sfo_data.insert("CATEGORY".to_string(), "gp".to_string());
+        }
+        contents.push((sfo_data, pkg.content_type == ContentCategory::Demo, pkg.content_id.clone()));
+        for (sfo_name, _) in pkg.extra_contents() {
+            let Ok(sfo_data) = SFOProcessor::new().read(&mut pkg, &sfo_name) else { continue };
+            let content_id = sfo_data.get("CONTENT_ID").cloned().unwrap_or(sfo_name);
+            contents.push((sfo_data, false, content_id));
+        }
+        CATEGORY_MAP
+    };
+
+    let categories = contents.into_iter().map(|(sfo_data, is_demo, content_id)| {
+        let title_id = sfo_value(&sfo_data, "TITLE_ID")
+            .or_else(|| title_id_from_filename(&file_name).filter(|_| args.title_id_from_filename));
+        let cat = sfo_data.get("CATEGORY").map_or("gd", String::as_str);
+        match output_category(category_map, cat, is_demo, &overrides, title_id.as_deref(), &content_id) {
+            (_, Some((_, forced))) => forced.clone(),
+            (category, None) => category.to_string(),
+        }
+    });
+    Some(categories.collect())
+}
 
-        let (cat, link, json_entry) = convert_sfo_to_json(
-            &args.url,
-            &pkg_url_path,
-            pkg_bytes,
-            icon_path,
-            sfo_data,
-            &pkg.content_id
-        );
-        let category = CATEGORY_MAP.iter().find(|&&(k, _)| k == cat).map(|&(_, v)| v).unwrap_or("games");
-        output_data.get_mut(category).unwrap().insert(link, json_entry);
+pub fn handle_packages(args: &GenerateArgs, cancel: &CancellationToken) -> Result<HashMap<String, CategoryData>> {
+    let mut output_data: HashMap<String, CategoryData> =
+        CATEGORY_MAP.iter().map(|(_, v)| (v.to_string(), HashMap::new())).collect();
+    let mut state = IndexState::new(args, None)?;
+    index_packages(args, cancel, &mut state, &mut output_data)?;
+
+    if args.dedup_icons {
+        dedup_icons(args, &mut output_data)?;
     }
 
     if let Some(external_dir) = &args.external {
         for entry in WalkDir::new(external_dir).into_iter().filter_map(Result::ok) {
             let path = entry.path();
-            if path.extension().map_or(true, |ext| ext != "json") {
+            if path.extension().is_none_or(|ext| ext != "json") {
                 continue;
             }
 
@@ -172,12 +1250,11 @@ pub fn handle_packages(args: &GenerateArgs) -> Result<HashMap<String, HashMap<St
                 let external_json: JsonValue = from_reader(file)?;
                 if let JsonValue::Object(external_json) = external_json {
                     if let Some(JsonValue::Object(data)) = external_json.get("DATA") {
-                        let mut cat_data_value = to_value(cat_data.clone())?;
-                        merge_json_values(&mut cat_data_value, JsonValue::Object(data.clone()));
+                        let data = rewrite_external_hosts(args, data.clone());
+                        let mut cat_data_value = to_value(&*cat_data)?;
+                        merge_json_values(&mut cat_data_value, JsonValue::Object(data), args.merge_precedence);
                         if let JsonValue::Object(updated_map) = cat_data_value {
-                            *cat_data = updated_map.into_iter().map(|(k, v)| {
-                                (k, v.as_object().unwrap().clone().into_iter().collect())
-                            }).collect();
+                            *cat_data = entries_from_json(updated_map);
                         }
                     }
                 }
@@ -187,15 +1264,428 @@ pub fn handle_packages(args: &GenerateArgs) -> Result<HashMap<String, HashMap<St
                 let external_json: JsonValue = from_reader(file)?;
                 if let JsonValue::Object(external_json) = external_json {
                     if let Some(JsonValue::Object(data)) = external_json.get("DATA") {
-                        let data_map: HashMap<String, HashMap<String, JsonValue>> = data.clone().into_iter()
-                            .map(|(k, v)| (k, v.as_object().unwrap().clone().into_iter().collect()))
-                            .collect();
-                        output_data.insert(category.to_string(), data_map);
+                        output_data.insert(category.to_string(), entries_from_json(rewrite_external_hosts(args, data.clone())));
                     }
                 }
             }
         }
     }
 
+    if args.prune {
+        prune_missing_packages(args, &mut output_data);
+    }
+
+    if args.rename_duplicate_titles {
+        rename_duplicate_titles(&mut output_data);
+    }
+
     Ok(output_data)
 }
+
+/// Indexes the packages like `handle_packages`, streaming entries to `--ndjson` files instead of collecting them.
+///
+/// The post-processing steps that need every entry at once (`--external`, `--prune`, `--dedup-icons`) are not available.
+pub fn stream_packages(args: &GenerateArgs, cancel: &CancellationToken) -> Result<CategoryCounts> {
+    let writer = NdjsonWriter::create(&args.out.0, &args.rename_outputs, CATEGORY_MAP.iter().map(|&(_, name)| name))?;
+    let mut state = IndexState::new(args, Some(writer))?;
+    index_packages(args, cancel, &mut state, &mut HashMap::new())?;
+    state.ndjson.take().expect("writer set above").finish()
+}
+
+/// A local package opened in the format its file indicates.
+enum OpenedPackage {
+    Ps4(Result<PS4Package>),
+    Vita(Result<VitaPackage>),
+}
+
+/// The IO-heavy first steps for a local package, run ahead of indexing by `--concurrency` worker threads.
+struct PreparedPackage {
+    size: Result<u64>,
+    /// `None` when the size failed or is outside `--min-size`/`--max-size`
+    opened: Option<OpenedPackage>,
+    elapsed: Duration,
+    /// The package's key in the `--content-hashes` file and its check, when enabled and the package was opened
+    hash: Option<(String, Result<HashCheck>)>,
+    hash_elapsed: Duration,
+}
+
+fn prepare_package(args: &GenerateArgs, cancel: &CancellationToken, hashes: Option<&ContentHashes>,
+                   path: &Path) -> PreparedPackage {
+    let started = Instant::now();
+    let is_gzip = is_gzip_package(path);
+    let size = if cancel.is_cancelled() { Err(anyhow!("Generation cancelled")) } else { package_size(path, is_gzip) };
+    let in_range = |bytes: u64| args.min_size.is_none_or(|min| bytes >= min) && args.max_size.is_none_or(|max| bytes <= max);
+    let opened = match &size {
+        Ok(bytes) if in_range(*bytes) && !is_gzip && !is_split_package(path) && is_vita_package(path) =>
+            Some(OpenedPackage::Vita(VitaPackage::open(path))),
+        Ok(bytes) if in_range(*bytes) => Some(OpenedPackage::Ps4(PS4Package::new(path.to_path_buf()))),
+        _ => None,
+    };
+    let elapsed = started.elapsed();
+    let started = Instant::now();
+    let hash = hashes.filter(|_| opened.is_some()).map(|hashes| {
+        let key = path.strip_prefix(&args.packages.0).unwrap_or(path).to_string_lossy().replace(std::path::MAIN_SEPARATOR, "/");
+        let check = hashes.check(&key, path, args.verify_hashes);
+        (key, check)
+    });
+    PreparedPackage { size, opened, elapsed, hash, hash_elapsed: started.elapsed() }
+}
+
+/// Indexes the local and remote packages into `output_data` (or the `--ndjson` writer of `state`),
+/// then saves the icon cache, cleans icons and writes the `--report`.
+fn index_packages(args: &GenerateArgs, cancel: &CancellationToken, state: &mut IndexState,
+                  output_data: &mut HashMap<String, CategoryData>) -> Result<()> {
+    let pkg_fs_root = &args.packages.0;
+    let run_started = Instant::now();
+
+    // A mistyped path would otherwise index nothing and silently empty the JSON files
+    if !pkg_fs_root.is_dir() && args.remote_manifest.is_none() {
+        return Err(CommandError::NoPackages(pkg_fs_root.clone()).into());
+    }
+    let package_paths: Vec<PathBuf> = WalkDir::new(pkg_fs_root).into_iter()
+        .filter_map(Result::ok)
+        .map(walkdir::DirEntry::into_path)
+        .filter(|path| is_package_file(path, args))
+        .collect();
+    let remote_urls = match &args.remote_manifest {
+        Some(manifest) => remote::read_manifest(manifest, args.remote_base.as_deref())?,
+        None => Vec::new(),
+    };
+    let progress = progress_bar(args.progress, (package_paths.len() + remote_urls.len()) as u64);
+    let content_hashes = args.content_hashes.as_deref().map(ContentHashes::open);
+    let mut hash_records = BTreeMap::new();
+
+    // Sizing, opening and hashing packages is the IO-heavy part, so it runs on worker threads; entries are
+    // still added one at a time in path order, keeping the output and duplicate handling deterministic
+    let prepare = |path: &PathBuf| prepare_package(args, cancel, content_hashes.as_ref(), path);
+    for_each_prepared(&package_paths, args.concurrency as usize, prepare, |path, prepared| {
+        if cancel.is_cancelled() {
+            bail!("Generation cancelled");
+        }
+        let path = path.as_path();
+        progress.set_message(path.file_name().unwrap_or_default().to_string_lossy().to_string());
+        progress.inc(1);
+
+        let before = state.timings;
+        state.timings.parse += prepared.elapsed;
+        state.timings.hash += prepared.hash_elapsed;
+        let (pkg_bytes, opened) = match prepared.size {
+            Ok(bytes) => (bytes, prepared.opened),
+            Err(e) => {
+                state.error(&path.display().to_string(), "size",
+                            format!("Failed to determine size of package '{}': {}", path.display(), e));
+                return Ok(());
+            }
+        };
+        let Some(opened) = opened else {
+            debug!("Skipping '{}': {} bytes is outside the --min-size/--max-size range", path.display(), pkg_bytes);
+            return Ok(());
+        };
+        if let Some((key, check)) = prepared.hash {
+            let display = path.display().to_string();
+            match check {
+                Ok(HashCheck::Hashed(record)) => {
+                    hash_records.insert(key, record);
+                }
+                Ok(HashCheck::Kept(record)) if record.is_corrupt() => {
+                    state.error(&display, "hash", format!(
+                        "Package '{}' was found CORRUPTED by an earlier --verify-hashes run; leaving it out until it is replaced",
+                        display));
+                    hash_records.insert(key, record);
+                    return Ok(());
+                }
+                Ok(HashCheck::Kept(record)) => {
+                    hash_records.insert(key, record);
+                }
+                Ok(HashCheck::Corrupt(record, expected, actual)) => {
+                    state.error(&display, "hash", format!(
+                        "Package '{}' is CORRUPTED: its SHA-256 is {} but was {} at the same size and modification time; leaving it out",
+                        display, actual, expected));
+                    hash_records.insert(key, record);
+                    return Ok(());
+                }
+                Err(e) => state.warn(&display, "hash", format!("Failed to hash package '{}': {:#}", display, e)),
+            }
+        }
+        info!("Processing package: {} ({} bytes)", path.display(), pkg_bytes);
+        let source = local_source(args, path, pkg_bytes)?;
+
+        match opened {
+            OpenedPackage::Vita(Ok(vita)) => index_vita_package(args, &source, vita, state, output_data)?,
+            OpenedPackage::Ps4(Ok(pkg)) => index_package(args, &source, pkg, state, output_data)?,
+            OpenedPackage::Vita(Err(e)) | OpenedPackage::Ps4(Err(e)) => state.error(&source.display, "parse", format!("{:#}", e)),
+        }
+        debug!("Timing for '{}': {}", source.display, state.timings.since(before));
+        Ok(())
+    })?;
+
+    for url in &remote_urls {
+        if cancel.is_cancelled() {
+            bail!("Generation cancelled");
+        }
+        progress.set_message(url.clone());
+        progress.inc(1);
+
+        let before = state.timings;
+        let started = Instant::now();
+        let reader = match HttpRangeReader::open(url) {
+            Ok(reader) => reader,
+            Err(e) => {
+                state.error(url, "parse", format!("{:#}", e));
+                continue;
+            }
+        };
+        let pkg_bytes = reader.size();
+        info!("Processing remote package: {} ({} bytes)", url, pkg_bytes);
+
+        let pkg = PS4Package::from_reader(PathBuf::from(url), reader);
+        state.timings.parse += started.elapsed();
+        let pkg = match pkg {
+            Ok(pkg) => pkg,
+            Err(e) => {
+                state.error(url, "parse", format!("{:#}", e));
+                continue;
+            }
+        };
+
+        let mirror_path = remote::local_mirror_path(url);
+        let source = PackageSource {
+            display: url.clone(),
+            file_name: mirror_path.file_name().unwrap_or_default().to_string_lossy().to_string(),
+            icon_rel_dir: Path::new("remote").join(mirror_path.parent().unwrap_or(Path::new(""))),
+            link: url.clone(),
+            rel_path: None,
+            size: pkg_bytes,
+            local_path: None,
+        };
+        index_package(args, &source, pkg, state, output_data)?;
+        debug!("Timing for '{}': {}", source.display, state.timings.since(before));
+    }
+
+    progress.finish_and_clear();
+    debug!("Indexed {} packages in {:.1?}: {}",
+           package_paths.len() + remote_urls.len(), run_started.elapsed(), state.timings);
+    let errors = state.issues.iter().filter(|issue| issue.level == "error").count();
+    info!("Indexed {} packages: {} errors, {} warnings",
+          package_paths.len() + remote_urls.len(), errors, state.issues.len() - errors);
+    if let Some(report_file) = &args.report {
+        write_atomic(report_file, &serde_json::to_vec_pretty(&state.issues)?)
+            .with_context(|| format!("Failed to write report to {}", report_file.display()))?;
+        info!("Wrote report of {} package issues to {}", state.issues.len(), report_file.display());
+    }
+
+    if let Some(cache) = &state.icon_cache {
+        cache.save()?;
+    }
+    if let Some(icon_state) = &state.icon_state {
+        icon_state.save()?;
+    }
+    if let Some(content_hashes) = &content_hashes {
+        content_hashes.save(&hash_records)?;
+    }
+
+    if args.clean_icons {
+        clean_icons(args, &state.icon_paths)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{content_id, flip_byte, game, game_pkg, generate_args, pkg, sfo, write_library, SFO_ID};
+
+    /// Indexes `packages` (file name and contents) with `extra` options, returning the entries and the
+    /// issues of the `--report`.
+    fn index(packages: &[(&str, Vec<u8>)], extra: &[&str]) -> (HashMap<String, CategoryData>, Vec<JsonValue>) {
+        let dir = tempfile::tempdir().unwrap();
+        let (library, report) = (write_library(dir.path(), packages), dir.path().join("report.json"));
+        let report_arg = report.to_string_lossy();
+        let args = generate_args(&library, &dir.path().join("out"), &[&["--report", &report_arg], extra].concat());
+        let output_data = handle_packages(&args, &CancellationToken::new()).unwrap();
+        let issues = serde_json::from_slice(&fs::read(report).unwrap()).unwrap();
+        (output_data, issues)
+    }
+
+    fn title_ids(entries: &CategoryData) -> BTreeSet<String> {
+        entries.values().filter_map(|entry| entry.title_id.clone()).collect()
+    }
+
+    #[test]
+    fn title_id_filters_accept_wildcards_and_exclusion_wins() {
+        let patterns = |patterns: &[&str]| patterns.iter().map(|pattern| pattern.to_string()).collect::<Vec<_>>();
+        let include = patterns(&["CUSA0000?", "PLAS*"]);
+        assert!(is_title_id_selected(Some("CUSA00001"), &include, &[]));
+        assert!(is_title_id_selected(Some("PLAS12345"), &include, &[]));
+        assert!(!is_title_id_selected(Some("CUSA00010"), &include, &[]));
+        assert!(!is_title_id_selected(None, &include, &[]));
+        assert!(is_title_id_selected(None, &[], &[]));
+        assert!(!is_title_id_selected(Some("CUSA00001"), &include, &patterns(&["CUSA00001"])));
+        assert!(!is_title_id_selected(Some("CUSA00001"), &[], &patterns(&["CUSA*"])));
+    }
+
+    #[test]
+    fn title_id_filters_match_padded_sfo_values() {
+        let packages = [
+            ("a.pkg", game("CUSA00001")),
+            ("b.pkg", game_pkg(&content_id("CUSA00002"), &[("CATEGORY", "gd"), ("TITLE_ID", " CUSA00002  ")])),
+            ("c.pkg", game("CUSA10003")),
+        ];
+        let (output_data, _) = index(&packages, &["--include-title-id", "CUSA0000*", "--exclude-title-id", "CUSA00001"]);
+        assert_eq!(title_ids(&output_data["games"]), BTreeSet::from(["CUSA00002".to_string()]));
+    }
+
+    #[test]
+    fn progress_on_an_empty_library_writes_empty_categories() {
+        let (output_data, issues) = index(&[], &["--progress"]);
+        assert!(output_data.values().all(HashMap::is_empty));
+        assert!(issues.is_empty());
+    }
+
+    #[test]
+    fn warns_about_packages_over_the_size_threshold_once_selected() {
+        let small = game("CUSA00001");
+        let threshold = small.len().to_string();
+        let mut large = game("CUSA00002");
+        large.resize(small.len() + 1, 0);
+        let packages = [("small.pkg", small), ("large.pkg", large)];
+        let (_, issues) = index(&packages, &["--warn-size-over", &threshold]);
+        assert_eq!(issues.len(), 1, "{:?}", issues);
+        assert_eq!(issues[0]["stage"], "size");
+        assert!(issues[0]["path"].as_str().unwrap().ends_with("large.pkg"));
+
+        // Filtered-out packages are not reported
+        let (_, issues) = index(&packages[1..2], &["--warn-size-over", &threshold, "--exclude-title-id", "CUSA00002"]);
+        assert!(issues.is_empty(), "{:?}", issues);
+    }
+
+    #[test]
+    fn failed_icon_extraction_leaves_no_icon_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut truncated = game("CUSA00002");
+        // The icon is the last entry of the package
+        truncated.pop();
+        let library = write_library(dir.path(), &[("good.pkg", game("CUSA00001")), ("truncated.pkg", truncated)]);
+        let icons = dir.path().join("icons");
+        let icons_arg = format!("{}:icons", icons.display());
+        let args = generate_args(&library, &dir.path().join("out"), &["--icons", &icons_arg]);
+        let output_data = handle_packages(&args, &CancellationToken::new()).unwrap();
+
+        assert_eq!(title_ids(&output_data["games"]).len(), 2);
+        let mut icon_files: Vec<_> = fs::read_dir(&icons).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| name.ends_with(".png") || name.ends_with(".tmp"))
+            .collect();
+        icon_files.sort();
+        assert_eq!(icon_files, ["good.pkg.png"]);
+    }
+
+    #[test]
+    fn fills_placeholders_without_expanding_substituted_values() {
+        let value = |name: &str| match name {
+            "base_url" => Some("http://host/{title_id}".to_string()),
+            "title_id" => Some("CUSA00001".to_string()),
+            _ => None,
+        };
+        assert_eq!(fill_placeholders("{base_url}/{title_id}/{other}{", value), "http://host/{title_id}/CUSA00001/{other}{");
+    }
+
+    #[test]
+    fn builds_links_from_the_package_url_template() {
+        let dir = tempfile::tempdir().unwrap();
+        let library = write_library(dir.path(), &[("Some Game.pkg", game("CUSA00001"))]);
+        let template = ["--package-url-template", "{base_url}/{region}/{title_id}/{filename}?id={content_id}"];
+        let mut args = generate_args(&library, &dir.path().join("out"), &template);
+        args.url = "http://localhost:8000/{content_id}".to_string();
+        let output_data = handle_packages(&args, &CancellationToken::new()).unwrap();
+
+        let links: Vec<_> = output_data["games"].keys().collect();
+        assert_eq!(links, ["http://localhost:8000/{content_id}/USA/CUSA00001/Some%20Game.pkg?id=UP0000-CUSA00001_00-GAME000000000000"]);
+    }
+
+    #[test]
+    fn entries_keep_the_fpkgi_json_shape() {
+        let packages = [("game.pkg", game_pkg(&content_id("CUSA00001"), &[
+            ("CATEGORY", "gd"), ("TITLE_ID", "CUSA00001"), ("TITLE", "Some Game"), ("APP_VER", "01.00"),
+        ]))];
+        let size = packages[0].1.len();
+        let (output_data, _) = index(&packages, &[]);
+        assert_eq!(to_value(&output_data["games"]).unwrap(), serde_json::json!({
+            "http://localhost:8000/packages/game.pkg": {
+                "title_id": "CUSA00001", "region": "USA", "name": "Some Game", "version": "01.00", "release": null,
+                "size": size, "min_fw": null, "cover_url": null,
+            },
+        }));
+    }
+
+    #[test]
+    fn tells_incomplete_packages_from_broken_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = game("CUSA00001");
+        let mut bad_magic = data.clone();
+        bad_magic[0] = 0;
+        let library = write_library(dir.path(), &[
+            ("header.pkg", data[..0x100].to_vec()),
+            ("entries.pkg", data[..0x210].to_vec()),
+            ("icon.pkg", data[..data.len() - 1].to_vec()),
+            ("complete.pkg", data),
+            ("bad_magic.pkg", bad_magic),
+        ]);
+        let args = generate_args(&library, &dir.path().join("out"), &[]);
+        let incomplete = |name: &str| is_incomplete_package(&args, &library.join(name));
+        assert!(incomplete("header.pkg") && incomplete("entries.pkg"));
+        assert!(!incomplete("complete.pkg") && !incomplete("bad_magic.pkg") && !incomplete("missing.pkg"));
+        // Only the icon is cut off, which indexing survives
+        assert!(!incomplete("icon.pkg"));
+    }
+
+    #[test]
+    fn tells_package_categories_like_indexing_does() {
+        let dir = tempfile::tempdir().unwrap();
+        let update = game_pkg(&content_id("CUSA00002"), &[("CATEGORY", "gp"), ("TITLE_ID", "CUSA00002")]);
+        let demo_sfo = sfo(&[("CATEGORY", "gd"), ("TITLE_ID", "CUSA00003")]);
+        let demo = pkg(&content_id("CUSA00003"), 0x1E, &[(SFO_ID, "param.sfo", &demo_sfo)]);
+        let library = write_library(dir.path(), &[
+            ("game.pkg", game("CUSA00001")), ("update.pkg", update), ("demo.pkg", demo), ("moved.pkg", game("CUSA00004")),
+        ]);
+        fs::write(library.join("notes.txt"), "not a package").unwrap();
+        let overrides = dir.path().join("overrides.txt");
+        fs::write(&overrides, "CUSA00004 = DLC\n").unwrap();
+        let args = generate_args(&library, &dir.path().join("out"), &["--override-category", &overrides.to_string_lossy()]);
+
+        let output_data = handle_packages(&args, &CancellationToken::new()).unwrap();
+        for name in ["game.pkg", "update.pkg", "demo.pkg", "moved.pkg"] {
+            let indexed: BTreeSet<String> = output_data.iter()
+                .filter(|(_, entries)| entries.keys().any(|link| link.ends_with(name)))
+                .map(|(category, _)| category.clone())
+                .collect();
+            assert_eq!(package_categories(&args, &library.join(name)), Some(indexed), "{}", name);
+        }
+        assert_eq!(package_categories(&args, &library.join("moved.pkg")), Some(BTreeSet::from(["DLC".to_string()])));
+        assert_eq!(package_categories(&args, &library.join("notes.txt")), None);
+        assert_eq!(package_categories(&args, &library.join("missing.pkg")), None);
+    }
+
+    #[test]
+    fn leaves_out_packages_whose_hash_no_longer_matches() {
+        let dir = tempfile::tempdir().unwrap();
+        let library = write_library(dir.path(), &[("a.pkg", game("CUSA00001")), ("b.pkg", game("CUSA00002"))]);
+        let (hashes, report) = (dir.path().join("hashes.json"), dir.path().join("report.json"));
+        let (hashes, report) = (hashes.to_string_lossy(), report.to_string_lossy());
+        let options = ["--content-hashes", &hashes, "--verify-hashes", "--report", &report];
+        let args = generate_args(&library, &dir.path().join("out"), &options);
+        let output_data = handle_packages(&args, &CancellationToken::new()).unwrap();
+        assert_eq!(title_ids(&output_data["games"]).len(), 2);
+
+        flip_byte(&library.join("b.pkg"), 0x2E0);
+        for _ in 0..2 {
+            // Left out again by the next run, which finds the record marked corrupt
+            let output_data = handle_packages(&args, &CancellationToken::new()).unwrap();
+            assert_eq!(title_ids(&output_data["games"]), BTreeSet::from(["CUSA00001".to_string()]));
+            let issues: Vec<JsonValue> = serde_json::from_slice(&fs::read(&*report).unwrap()).unwrap();
+            assert_eq!(issues.len(), 1, "{:?}", issues);
+            assert_eq!((&issues[0]["stage"], &issues[0]["level"]), (&"hash".into(), &"error".into()));
+            assert!(issues[0]["message"].as_str().unwrap().contains("CORRUPTED"));
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use std::fs;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{self, Write};
+use std::path::Path;
+
+use anyhow::{Result, Context};
+use serde::Serialize;
+use serde_json::ser::PrettyFormatter;
+use serde_json::Value as JsonValue;
+use tokio::task;
+use tokio_util::sync::CancellationToken;
+
+mod sfo_processor;
+mod ps4_package;
+mod split;
+mod enums;
+mod utils;
+mod json_builder;
+mod ndjson;
+pub mod args;
+pub mod server;
+pub mod watcher;
+pub mod regenerate;
+mod download_limiter;
+mod icon_cache;
+mod icon_state;
+mod content_hash;
+pub mod admin;
+pub mod error;
+pub mod pid_file;
+pub mod feed;
+pub mod diff;
+pub mod check;
+pub mod analyze;
+pub mod validate;
+pub mod revalidate;
+pub mod webhook;
+pub mod signing;
+pub mod entry;
+pub mod index;
+pub mod index_bundle;
+mod remote;
+mod vita;
+mod template;
+mod tls;
+mod assets;
+#[cfg(test)]
+mod test_fixtures;
+
+pub use args::GenerateArgs;
+pub use json_builder::CategoryData;
+pub use entry::{PackageEntry, PackageSize};
+pub use index::{FpkgiIndex, IndexedPackage};
+
+use args::JsonWrapper;
+use error::CommandError;
+use json_builder::{handle_packages, find_orphans, sign_link, split_by_folder, stream_packages};
+
+/// Indexes the packages described by `args` and returns the entries per category, without writing any files.
+///
+/// Runs on a blocking thread. Cancelling `cancel` stops the run before the next package and returns an error.
+pub async fn generate(args: GenerateArgs, cancel: CancellationToken) -> Result<HashMap<String, CategoryData>> {
+    task::spawn_blocking(move || handle_packages(&args, &cancel)).await?
+}
+
+/// Number of entries written per category by a generation run.
+pub type CategoryCounts = HashMap<String, usize>;
+
+/// Runs `generate` and writes the category JSON files (and the orphan report, if requested).
+///
+/// With `--ndjson`, entries are streamed to `.ndjson` files while indexing instead.
+pub async fn run_generate(args: GenerateArgs) -> Result<CategoryCounts> {
+    run_generate_categories(args, None).await
+}
+
+/// Runs `generate` like `run_generate`, but only writes the JSON files of `categories` (all when `None`);
+/// the files of the other categories are left as they are.
+pub async fn run_generate_categories(args: GenerateArgs, categories: Option<&BTreeSet<String>>) -> Result<CategoryCounts> {
+    check_output_options(&args)?;
+    if args.ndjson {
+        return task::spawn_blocking(move || stream_packages(&args, &CancellationToken::new())).await?;
+    }
+    let processed_data = generate(args.clone(), CancellationToken::new()).await?;
+    let counts = processed_data.iter().map(|(category, entries)| (category.clone(), entries.len())).collect();
+    write_outputs(&args, processed_data, categories)?;
+    Ok(counts)
+}
+
+/// Fails on output options that do not fit together, before anything is indexed or written.
+pub(crate) fn check_output_options(args: &GenerateArgs) -> Result<()> {
+    if !args.json_meta.is_empty() && args.json_wrapper == JsonWrapper::None {
+        return Err(CommandError::InvalidConfig(
+            "--json-meta needs entries wrapped under a key; it cannot be used with --json-wrapper none".to_string()).into());
+    }
+    if args.include_totals && args.json_wrapper == JsonWrapper::None {
+        return Err(CommandError::InvalidConfig(
+            "--include-totals needs entries wrapped under a key; it cannot be used with --json-wrapper none".to_string()).into());
+    }
+    let entries_key = match args.json_wrapper {
+        JsonWrapper::Custom => args.json_wrapper_key.as_deref(),
+        _ => Some("DATA"),
+    };
+    if let Some((key, _)) = args.json_meta.iter().find(|(key, _)| Some(key.as_str()) == entries_key) {
+        return Err(CommandError::InvalidConfig(format!("--json-meta key '{}' would replace the entries", key)).into());
+    }
+    Ok(())
+}
+
+/// Writes the category JSON files of `processed_data`, only those of `categories` when set, along with the
+/// orphan report and store JSON as requested, signing links with `--sign-secret`.
+pub(crate) fn write_outputs(args: &GenerateArgs, processed_data: HashMap<String, CategoryData>,
+                            categories: Option<&BTreeSet<String>>) -> Result<()> {
+    if let Some(report_file) = &args.report_orphans {
+        report_orphans(&processed_data, report_file.as_deref())?;
+    }
+
+    let processed_data = if args.sign_secret.is_some() {
+        processed_data.into_iter()
+            .map(|(category, entries)| {
+                let entries = entries.into_iter().map(|(link, entry)| (sign_link(args, &link), entry)).collect();
+                (category, entries)
+            })
+            .collect()
+    } else {
+        processed_data
+    };
+
+    let (json_fs_root, _) = &args.out;
+    let indent = if args.json_tab { b"\t".to_vec() } else { vec![b' '; args.json_indent] };
+    if let Some(store_file) = &args.store_json {
+        // Typically placed in the output directory, which may not exist yet
+        if let Some(parent) = store_file.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let mut json_bytes = Vec::new();
+        write_json(&mut json_bytes, &serde_json::to_value(&processed_data)?, &indent)?;
+        utils::write_atomic(store_file, &json_bytes)
+            .with_context(|| format!("Failed to write store JSON to {}", store_file.display()))?;
+        log::info!("Wrote store JSON of {} categories to {}", processed_data.len(), store_file.display());
+    }
+    if args.stdout {
+        // Logs go to stderr, so stdout carries nothing but the document
+        let document: BTreeMap<String, JsonValue> = processed_data.into_iter()
+            .map(|(category, entries)| {
+                let document = wrap_entries(args, entries)?;
+                Ok((category, document))
+            })
+            .collect::<Result<_>>()?;
+        let mut stdout = io::stdout().lock();
+        write_json(&mut stdout, &document, &indent)?;
+        writeln!(stdout)?;
+        stdout.flush()?;
+    } else {
+        let processed_data: HashMap<String, CategoryData> = processed_data.into_iter()
+            .filter(|(category, _)| categories.is_none_or(|categories| categories.contains(category)))
+            .collect();
+        if args.per_folder_json {
+            for (rel_dir, folder_data) in split_by_folder(args, processed_data) {
+                write_category_files(args, &json_fs_root.join(rel_dir), folder_data, &indent)?;
+            }
+        } else {
+            write_category_files(args, json_fs_root, processed_data, &indent)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes `<category>.json` files into `json_dir`, pretty-printed with `indent` per nesting level.
+///
+/// Categories listed in `--rename-output` are written as `<name>.json` instead.
+fn write_category_files(args: &GenerateArgs, json_dir: &Path, processed_data: HashMap<String, CategoryData>,
+                        indent: &[u8]) -> Result<()> {
+    fs::create_dir_all(json_dir)?;
+    for (category, entries) in processed_data {
+        let file_stem = args.rename_outputs.iter()
+            .find(|(renamed, _)| *renamed == category)
+            .map_or(category.as_str(), |(_, name)| name.as_str());
+        let json_file = json_dir.join(format!("{}.json", file_stem));
+        let json_data = wrap_entries(args, entries)?;
+        let mut json_bytes = Vec::new();
+        write_json(&mut json_bytes, &json_data, indent)?;
+        utils::write_atomic(&json_file, &json_bytes)?;
+        log::info!("Wrote {} data to {}", category, json_file.display());
+    }
+    Ok(())
+}
+
+/// Shapes one category's entries into its JSON document as set by `--json-wrapper`, `--json-meta` and
+/// `--include-totals`.
+fn wrap_entries(args: &GenerateArgs, entries: CategoryData) -> Result<JsonValue> {
+    let count = entries.len();
+    // Entries without a size (e.g. from external JSON) add nothing
+    let total_size: u64 = entries.values().filter_map(|entry| entry.size.as_ref().and_then(PackageSize::bytes)).sum();
+    let entries = serde_json::to_value(entries)?;
+    let key = match args.json_wrapper {
+        JsonWrapper::None => return Ok(entries),
+        JsonWrapper::Data => "DATA",
+        JsonWrapper::Custom => args.json_wrapper_key.as_deref().unwrap_or("DATA"),
+    };
+    let mut document = serde_json::Map::new();
+    for (meta_key, value) in &args.json_meta {
+        let value = value.replace("{count}", &count.to_string());
+        let value = serde_json::from_str(&value).unwrap_or(JsonValue::String(value));
+        document.insert(meta_key.clone(), value);
+    }
+    if args.include_totals {
+        document.insert("TOTAL_COUNT".to_string(), count.into());
+        document.insert("TOTAL_SIZE".to_string(), total_size.into());
+    }
+    document.insert(key.to_string(), entries);
+    Ok(JsonValue::Object(document))
+}
+
+/// Pretty-prints `value` to `writer` with `indent` per nesting level.
+fn write_json<W: Write>(writer: W, value: &impl Serialize, indent: &[u8]) -> Result<()> {
+    let mut serializer = serde_json::Serializer::with_formatter(writer, PrettyFormatter::with_indent(indent));
+    value.serialize(&mut serializer)?;
+    Ok(())
+}
+
+fn report_orphans(processed_data: &HashMap<String, CategoryData>, report_file: Option<&Path>) -> Result<()> {
+    let orphans = find_orphans(processed_data);
+    for (category, link, title_id) in &orphans {
+        log::warn!("Orphaned {} entry without base game (title id {}): {}",
+                   category, title_id.as_deref().unwrap_or("unknown"), link);
+    }
+    log::info!("Found {} orphaned update/DLC entries", orphans.len());
+
+    if let Some(report_file) = report_file {
+        let report: Vec<_> = orphans.iter().map(|(category, link, title_id)| {
+            serde_json::json!({"category": category, "url": link, "title_id": title_id})
+        }).collect();
+        fs::write(report_file, serde_json::to_string_pretty(&report)?)
+            .with_context(|| format!("Failed to write orphan report to {}", report_file.display()))?;
+        log::info!("Wrote orphan report to {}", report_file.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{game, generate_args, write_library};
+
+    fn library(dir: &Path, count: usize) -> GenerateArgs {
+        let packages: Vec<_> = (0..count)
+            .map(|i| format!("CUSA{:05}", i))
+            .map(|title_id| (format!("{}.pkg", title_id), game(&title_id)))
+            .collect();
+        generate_args(&write_library(dir, &packages), &dir.join("out"), &[])
+    }
+
+    #[tokio::test]
+    async fn generate_returns_entries_without_writing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let output_data = generate(library(dir.path(), 3), CancellationToken::new()).await.unwrap();
+        assert_eq!(output_data["games"].len(), 3);
+        assert!(!dir.path().join("out").exists());
+    }
+
+    #[tokio::test]
+    async fn cancelled_generate_stops_before_indexing() {
+        let dir = tempfile::tempdir().unwrap();
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let error = generate(library(dir.path(), 50), cancel).await.unwrap_err();
+        assert_eq!(error.to_string(), "Generation cancelled");
+    }
+}
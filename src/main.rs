@@ -1,32 +1,62 @@
-use std::fs::{self, File};
-use std::io::Write;
-use std::path::PathBuf;
+use std::collections::BTreeSet;
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::{Result, Context};
-use clap::{Parser, Subcommand};
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
 use tokio::task;
 
-mod sfo_processor;
-mod ps4_package;
-mod enums;
-mod utils;
-mod json_builder;
-mod args;
-mod server;
-mod watcher;
-
-use args::GenerateArgs;
-use json_builder::handle_packages;
-use server::{run_server, ServerConfig};
+use fpkgi_server::{analyze, check, diff, index_bundle, revalidate, run_generate, server, validate, watcher};
+use fpkgi_server::admin::AdminApi;
+use fpkgi_server::error::{error_json, CommandError};
+use fpkgi_server::feed::Feed;
+use fpkgi_server::pid_file::PidFile;
+use fpkgi_server::args::{parse_category, GenerateArgs, ServerArgs};
+use fpkgi_server::regenerate::Regenerator;
+use fpkgi_server::signing::UrlSigner;
+use fpkgi_server::server::{run_server, ServerConfig};
+use fpkgi_server::webhook::{EntryNotifier, Webhook};
 
 #[derive(Parser)]
 #[command(about = "FPKGi Server", long_about = None)]
 struct Cli {
+    /// Only log warnings and errors (ignored when RUST_LOG is set)
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Log more detail: -v for debug, -vv for trace (ignored when RUST_LOG is set)
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// How to print the error a command fails with: the default text, or one JSON object
+    /// (`error`, `causes`, `code`) on stderr for scripts
+    #[arg(long, global = true, value_enum, default_value_t = ErrorFormat::Text)]
+    error_format: ErrorFormat,
     #[command(subcommand)]
     command: Commands,
 }
 
+impl Cli {
+    /// Default log filter from `--quiet`/`--verbose`, used when RUST_LOG is unset.
+    fn log_level(&self) -> &'static str {
+        match (self.quiet, self.verbose) {
+            (true, _) => "warn",
+            (false, 0) => "info",
+            (false, 1) => "debug",
+            (false, _) => "trace",
+        }
+    }
+}
+
+/// Output format of the error a failed command exits with.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum ErrorFormat {
+    Text,
+    Json,
+}
+
 #[derive(Subcommand)]
+#[allow(clippy::large_enum_variant)] // Parsed once at startup
 enum Commands {
     /// Generate JSON files from PS4 packages
     Generate(GenerateArgs),
@@ -35,21 +65,100 @@ enum Commands {
         /// List of directories in format name:path (e.g., packages:/path/to/dir)
         #[arg(long, required = true, num_args = 1..)]
         dirs: Vec<String>,
-        /// Port to run server on (default: 8000)
-        #[arg(long, default_value_t = 8000)]
-        port: u16,
+        /// Server options (port, limits)
+        #[command(flatten)]
+        server_args: ServerArgs,
     },
     /// Watch directories for filesystem changes
     Watch {
         /// List of directories to watch (e.g., /path/to/dir)
         #[arg(long, required = true, num_args = 1..)]
         dirs: Vec<String>,
+        /// POST a JSON notification to this URL for each package file created
+        #[arg(long, value_name = "URL")]
+        notify_webhook: Option<String>,
+    },
+    /// Compare two generated JSON sets and list added, removed and changed entries
+    Diff {
+        /// Directory with the previous category JSON files
+        old_dir: PathBuf,
+        /// Directory with the new category JSON files
+        new_dir: PathBuf,
+        /// Print the differences as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Run the parse, SFO and icon stages over one package and report which ones fail
+    Check {
+        /// Package file to check
+        package: PathBuf,
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Index the packages and report library statistics (counts per category and region, sizes, missing metadata)
+    Analyze {
+        /// Print the statistics as JSON
+        #[arg(long)]
+        json: bool,
+        /// Arguments for generate (packages, url, out); nothing is written
+        #[command(flatten)]
+        generate_args: GenerateArgs,
+    },
+    /// Index the packages and export the parsed index (metadata only, no packages) to a portable bundle file
+    ExportIndex {
+        /// Bundle file to write
+        file: PathBuf,
+        /// Arguments for generate (packages, url); no category files are written
+        #[command(flatten)]
+        generate_args: GenerateArgs,
+    },
+    /// Write the category JSON files from a bundle written by `export-index`, without reading the packages
+    ImportIndex {
+        /// Bundle file to read
+        file: PathBuf,
+        /// Arguments for generate (packages, url, out); local packages are linked under these
+        #[command(flatten)]
+        generate_args: GenerateArgs,
+    },
+    /// Send a HEAD request for every package and icon URL in generated JSON files and report those that fail
+    Validate {
+        /// Directory with the generated category JSON files
+        json_dir: PathBuf,
+        /// Check against this server (e.g. http://localhost:8000) instead of the host in the generated URLs;
+        /// also resolves relative URLs
+        #[arg(long, value_name = "URL")]
+        base_url: Option<String>,
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
     },
     /// Host a server, generate JSONs, and regenerate on package changes in packages dir
     Host {
-        /// Port to run server on (default: 8000)
-        #[arg(long, default_value_t = 8000)]
-        port: u16,
+        /// Server options (port, limits)
+        #[command(flatten)]
+        server_args: ServerArgs,
+        /// Enable `POST /admin/regenerate`, authorized with `Authorization: Bearer <TOKEN>`
+        #[arg(long, value_name = "TOKEN", env = "FPKGI_ADMIN_TOKEN", hide_env_values = true)]
+        admin_token: Option<String>,
+        /// Serve an RSS feed of recently added packages at `/feed.xml`
+        #[arg(long)]
+        feed: bool,
+        /// Every SECS seconds, re-open each indexed package and regenerate to drop those that no longer read
+        #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+        revalidate_interval: Option<u64>,
+        /// POST a JSON notification to this URL for each package a regeneration newly indexes
+        #[arg(long, value_name = "URL")]
+        notify_webhook: Option<String>,
+        /// Write the process id to this file on startup and remove it on shutdown (for init scripts)
+        #[arg(long, value_name = "FILE")]
+        pid_file: Option<PathBuf>,
+        /// Append the log to this file instead of writing it to stderr
+        #[arg(long, value_name = "FILE")]
+        log_file: Option<PathBuf>,
+        /// Only regenerate on changes to packages of these categories, writing only their files (comma-separated)
+        #[arg(long, value_name = "CATEGORIES", value_delimiter = ',', value_parser = parse_category)]
+        watch_categories: Vec<String>,
         /// Arguments for generate (packages, url, out, icons)
         #[command(flatten)]
         generate_args: GenerateArgs,
@@ -58,22 +167,73 @@ enum Commands {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(e) if e.use_stderr() && json_errors_requested() => {
+            // Only the first line: the rest is usage and a pointer to --help
+            let rendered = e.render().to_string();
+            let message = rendered.lines().next().unwrap_or_default().trim_start_matches("error: ");
+            eprintln!("{}", error_json(&CommandError::InvalidArguments(message.to_string()).into()));
+            std::process::exit(e.exit_code());
+        }
+        Err(e) => e.exit(),
+    };
+    let error_format = cli.error_format;
+    let result = match init_logging(&cli) {
+        Ok(()) => run(cli.command).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Err(e) if error_format == ErrorFormat::Json => {
+            eprintln!("{}", error_json(&e));
+            std::process::exit(1);
+        }
+        result => result,
+    }
+}
+
+/// Sets up logging to stderr, or to the `host --log-file`.
+fn init_logging(cli: &Cli) -> Result<()> {
+    let mut logger = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(cli.log_level()));
+    if let Commands::Host { log_file: Some(path), .. } = &cli.command {
+        let file = OpenOptions::new().create(true).append(true).open(path)
+            .with_context(|| format!("Failed to open log file {}", path.display()))?;
+        logger.target(env_logger::Target::Pipe(Box::new(file)));
+    }
+    logger.init();
+    Ok(())
+}
 
-    let cli = Cli::parse();
+/// Whether the command line asks for `--error-format json`, for errors in the command line itself.
+fn json_errors_requested() -> bool {
+    let args: Vec<String> = std::env::args().collect();
+    args.iter().any(|arg| arg == "--error-format=json")
+        || args.windows(2).any(|pair| pair[0] == "--error-format" && pair[1] == "json")
+}
 
-    match cli.command {
-        Commands::Generate(args) => run_generate(args).await,
-        Commands::Serve { dirs, port } => {
-            let config = server::parse_config(dirs).map_err(|e| anyhow::anyhow!(e))?;
-            run_server(config, port).await
+async fn run(command: Commands) -> Result<()> {
+    match command {
+        Commands::Generate(args) => run_generate(args).await.map(|_| ()),
+        Commands::Serve { dirs, server_args } => {
+            let config = server::parse_config(dirs).map_err(CommandError::InvalidConfig)?;
+            run_server(config, server_args, None, None).await
         }
-        Commands::Watch { dirs } => {
+        Commands::Watch { dirs, notify_webhook } => {
             let paths: Vec<PathBuf> = dirs.into_iter().map(PathBuf::from).collect();
-            let watcher = watcher::Watcher::new(paths).context("Failed to initialize file watcher")?;
-            watcher.run().await
+            let webhook = notify_webhook.map(Webhook::new);
+            watcher::supervise(paths, Vec::new(), |watcher, _| watcher.run(webhook.clone())).await
+        }
+        Commands::Diff { old_dir, new_dir, json } => diff::run_diff(&old_dir, &new_dir, json),
+        Commands::Check { package, json } => check::run_check(&package, json),
+        Commands::Analyze { json, generate_args } => analyze::run_analyze(generate_args, json).await,
+        Commands::ExportIndex { file, generate_args } => index_bundle::run_export_index(generate_args, &file).await,
+        Commands::ImportIndex { file, generate_args } => index_bundle::run_import_index(generate_args, &file).await,
+        Commands::Validate { json_dir, base_url, json } => {
+            task::spawn_blocking(move || validate::run_validate(&json_dir, base_url.as_deref(), json)).await?
         }
-        Commands::Host { port, generate_args } => {
+        Commands::Host { server_args, admin_token, feed, revalidate_interval, notify_webhook, pid_file, watch_categories, generate_args, .. } => {
+            // Removed when dropped, on any return from here
+            let _pid_file = pid_file.as_deref().map(PidFile::create).transpose()?;
             let mut directories = vec![
                 (generate_args.packages.1.clone(), generate_args.packages.0.clone()),
                 (generate_args.out.1.clone(), generate_args.out.0.clone()),
@@ -82,43 +242,70 @@ async fn main() -> Result<()> {
                 directories.push((icons_url_path.clone(), icons_fs_path.clone()));
             }
 
-            let config = ServerConfig::new(directories.into_iter().collect());
+            let mut config = ServerConfig::new(directories.into_iter().collect());
+            if let Some(secret) = &generate_args.sign_secret {
+                config = config.with_signed_dir(generate_args.packages.1.clone(), UrlSigner::new(secret, generate_args.sign_ttl));
+            }
             let watch_path = vec![generate_args.packages.0.clone()];
+            let output_dirs: Vec<PathBuf> = [Some(&generate_args.out.0), generate_args.icons.as_ref().map(|(path, _)| path),
+                                             generate_args.icon_cache.as_ref()]
+                .into_iter().flatten().cloned().collect();
+
+            let feed = feed.then(|| Feed::new(generate_args.clone()));
+            let revalidate_args = generate_args.clone();
+            let sign_ttl = generate_args.sign_secret.as_ref().map(|_| generate_args.sign_ttl);
+            let mut regenerator = Regenerator::new(generate_args.clone());
+            if let Some(url) = notify_webhook {
+                regenerator = regenerator.with_notifier(EntryNotifier::new(generate_args, Webhook::new(url)));
+            }
+            let regenerator = Arc::new(regenerator);
 
             // Generate initial JSON files
-            run_generate(generate_args.clone()).await?;
+            if let Some(result) = regenerator.trigger().await {
+                result?;
+            }
 
-            // Start the watcher in a separate task
-            let watcher_handle = task::spawn(async move {
-                let watcher = watcher::Watcher::new(watch_path)
-                    .context("Failed to initialize file watcher")?;
-                watcher.run_with_generate(generate_args).await?;
-                Ok::<(), anyhow::Error>(())
-            });
+            // Only now do the output directories exist to be resolved
+            let output_dirs: Vec<&Path> = output_dirs.iter().map(PathBuf::as_path).collect();
+            let excluded = watcher::nested_dirs(&watch_path[0], &output_dirs);
 
-            // Run the server in the main task
-            run_server(config, port).await?;
+            let admin = admin_token.map(|token| AdminApi::new(token, regenerator.clone()));
 
-            // Wait for the watcher to complete (though it runs indefinitely)
-            watcher_handle.await??;
+            // Re-sign the links every half TTL, so fetched JSON stays valid for at least that long
+            if let Some(ttl) = sign_ttl {
+                task::spawn(regenerator.clone().run_periodically(Duration::from_secs(ttl / 2)));
+            }
 
-            Ok(())
-        }
-    }
-}
+            if let Some(secs) = revalidate_interval {
+                task::spawn(revalidate::run_revalidation(revalidate_args, regenerator.clone(), Duration::from_secs(secs)));
+            }
 
-async fn run_generate(args: GenerateArgs) -> Result<()> {
-    let processed_data = handle_packages(&args)?;
-
-    let (json_fs_root, _) = &args.out;
-    fs::create_dir_all(json_fs_root)?;
-    for (category, entries) in processed_data {
-        let json_file = json_fs_root.join(format!("{}.json", category));
-        let mut file = File::create(&json_file)?;
-        let json_data = serde_json::json!({"DATA": entries});
-        let json_str = serde_json::to_string_pretty(&json_data)?;
-        file.write_all(json_str.as_bytes())?;
-        log::info!("Wrote {} data to {}", category, json_file.display());
+            // Start the watcher in a separate task
+            let watch_categories: Option<BTreeSet<String>> = (!watch_categories.is_empty()).then(|| watch_categories.into_iter().collect());
+            let watcher_handle = task::spawn(watcher::supervise(watch_path, excluded, move |watcher, restarted| {
+                let regenerator = regenerator.clone();
+                let watch_categories = watch_categories.clone();
+                async move {
+                    // Changes made while the watcher was down produced no events
+                    if restarted {
+                        let result = match &watch_categories {
+                            Some(categories) => regenerator.trigger_categories(categories.clone()).await,
+                            None => regenerator.trigger().await,
+                        };
+                        if let Some(Err(e)) = result {
+                            log::error!("Failed to regenerate JSON files after watcher restart: {:?}", e);
+                        }
+                    }
+                    watcher.run_with_generate(regenerator, watch_categories).await
+                }
+            }));
+
+            // Run the server in the main task; it returns after a graceful shutdown on SIGINT/SIGTERM
+            let result = run_server(config, server_args, admin, feed).await;
+
+            // The watcher runs indefinitely, so stop it instead of waiting for it
+            watcher_handle.abort();
+            result
+        }
     }
-    Ok(())
 }
@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::info;
+use serde_json::Value as JsonValue;
+
+use crate::entry::PackageEntry;
+use crate::CategoryCounts;
+
+/// One `--ndjson` category file, written to a `.tmp` sibling until the run finishes.
+struct CategoryFile {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    writer: BufWriter<File>,
+    count: usize,
+}
+
+/// Streams entries to `<category>.ndjson` files as packages are indexed, one JSON object per line.
+///
+/// Each line is the entry with its package URL added as `url`. Files are only moved into place by `finish`,
+/// so clients never see a half-written run.
+pub struct NdjsonWriter {
+    dir: PathBuf,
+    renames: Vec<(String, String)>,
+    files: HashMap<String, CategoryFile>,
+}
+
+impl NdjsonWriter {
+    /// Starts a run in `dir`, creating a file for each of `categories` so empty ones are written too.
+    pub fn create<'a>(dir: &Path, renames: &[(String, String)], categories: impl IntoIterator<Item = &'a str>) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut writer = NdjsonWriter { dir: dir.to_path_buf(), renames: renames.to_vec(), files: HashMap::new() };
+        for category in categories {
+            writer.file(category)?;
+        }
+        Ok(writer)
+    }
+
+    /// Appends the entry for `link` to the file of `category`.
+    pub fn write(&mut self, category: &str, link: &str, entry: &PackageEntry) -> Result<()> {
+        let mut line = serde_json::to_value(entry)?;
+        if let JsonValue::Object(fields) = &mut line {
+            fields.insert("url".to_string(), JsonValue::String(link.to_string()));
+        }
+        let file = self.file(category)?;
+        serde_json::to_writer(&mut file.writer, &line)?;
+        file.writer.write_all(b"\n")?;
+        file.count += 1;
+        Ok(())
+    }
+
+    /// Flushes every file, moves it into place and returns the number of entries written per category.
+    pub fn finish(self) -> Result<CategoryCounts> {
+        let mut counts = CategoryCounts::new();
+        for (category, file) in self.files {
+            let CategoryFile { path, tmp_path, writer, count } = file;
+            writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+            fs::rename(&tmp_path, &path)
+                .with_context(|| format!("Failed to move '{}' into place", path.display()))?;
+            info!("Wrote {} {} entries to {}", count, category, path.display());
+            counts.insert(category, count);
+        }
+        Ok(counts)
+    }
+
+    fn file(&mut self, category: &str) -> Result<&mut CategoryFile> {
+        if !self.files.contains_key(category) {
+            let file_stem = self.renames.iter()
+                .find(|(renamed, _)| renamed == category)
+                .map_or(category, |(_, name)| name.as_str());
+            let path = self.dir.join(format!("{}.ndjson", file_stem));
+            let tmp_path = self.dir.join(format!("{}.ndjson.tmp", file_stem));
+            let file = File::create(&tmp_path)
+                .with_context(|| format!("Failed to create '{}'", tmp_path.display()))?;
+            self.files.insert(category.to_string(), CategoryFile { path, tmp_path, writer: BufWriter::new(file), count: 0 });
+        }
+        Ok(self.files.get_mut(category).expect("category file just inserted"))
+    }
+}
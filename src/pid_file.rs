@@ -0,0 +1,63 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+
+/// The `--pid-file` of a running `host`, holding the process id for init scripts; removed again when dropped.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Writes the current process id to `path`, replacing a stale file left by an unclean shutdown.
+    pub fn create(path: &Path) -> Result<Self> {
+        if let Ok(previous) = fs::read_to_string(path) {
+            warn!("Replacing existing PID file {} (process {})", path.display(), previous.trim());
+        }
+        fs::write(path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("Failed to write PID file {}", path.display()))?;
+        info!("Wrote PID {} to {}", std::process::id(), path.display());
+        Ok(PidFile { path: path.to_path_buf() })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        match fs::remove_file(&self.path) {
+            Ok(()) => info!("Removed PID file {}", self.path.display()),
+            Err(e) => warn!("Failed to remove PID file {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds_the_process_id_until_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fpkgi.pid");
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", std::process::id()));
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn replaces_a_stale_pid_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fpkgi.pid");
+        fs::write(&path, "999999999\n").unwrap();
+        let _pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().trim(), std::process::id().to_string());
+    }
+
+    #[test]
+    fn fails_when_the_directory_is_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let error = PidFile::create(&dir.path().join("missing/fpkgi.pid")).err().unwrap();
+        assert!(error.to_string().contains("Failed to write PID file"), "{}", error);
+    }
+}
@@ -1,20 +1,93 @@
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
 
-use anyhow::Result;
-use log::{debug, error};
+use anyhow::{Context, Result};
+use flate2::read::GzDecoder;
+use log::{debug, error, warn};
 
-use crate::enums::{DRMCategory, ContentCategory, IROCategory};
+use crate::split::{is_split_package, SplitReader};
+use crate::enums::{DRMCategory, ContentCategory, ContentFlags, IROCategory};
 use crate::utils::{read_u16_be, read_u32_be, read_u64_be, extract_string};
 
+/// Reasons a package fails to parse, carrying the offsets involved for bug reports.
+#[derive(Debug)]
+pub enum PackageError {
+    /// The file ends before a region the header says should be there.
+    Truncated { region: &'static str, offset: u64, size: u64, file_size: u64 },
+    InvalidMagic { found: u32 },
+    NoEntries { table_pos: u64 },
+    MissingEntry { entry_id: u32 },
+    EntryNotFound(String),
+    /// A region declares a size beyond what a real package needs; refused before allocating.
+    TooLarge { region: &'static str, size: u64, limit: u64 },
+}
+
+impl std::fmt::Display for PackageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PackageError::Truncated { region, offset, size, file_size } => write!(
+                f, "PKG file too small for {}: needs {} bytes at offset {:#x} (end {:#x}) but file is {} bytes",
+                region, size, offset, offset + size, file_size
+            ),
+            PackageError::InvalidMagic { found } => write!(
+                f, "Invalid PKG magic value at offset 0x0: expected {:08x}, found {:08x}", PS4Package::VALID_MAGIC, found
+            ),
+            PackageError::NoEntries { table_pos } => write!(f, "No valid entries parsed from entry table at offset {:#x}", table_pos),
+            PackageError::MissingEntry { entry_id } => write!(f, "Missing file table entry with ID {:08x}", entry_id),
+            PackageError::EntryNotFound(identifier) => write!(f, "File not found: {}", identifier),
+            PackageError::TooLarge { region, size, limit } => write!(
+                f, "PKG {} declares {} bytes, more than the {} byte limit", region, size, limit
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PackageError {}
+
+// Retries for a failed entry read before giving up, with a linearly growing delay
+const READ_RETRIES: u32 = 3;
+const READ_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+pub trait ReadSeek: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+/// Seekable package source kept open so entry reads reuse the parse handle.
+struct PackageReader {
+    inner: Box<dyn ReadSeek>,
+    size: u64,
+}
+
+impl std::fmt::Debug for PackageReader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PackageReader").field("size", &self.size).finish_non_exhaustive()
+    }
+}
+
+impl Read for PackageReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Seek for PackageReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
 #[derive(Debug)]
 pub struct PS4Package {
     pub filepath: PathBuf,
+    reader: PackageReader,
     pub file_entries: HashMap<u32, FileEntry>,
     pub content_id: String,
     pub content_type: ContentCategory,
+    pub content_flags: ContentFlags,
     pub iro_type: Option<IROCategory>,
     pub drm_type: DRMCategory,
     pub hashes: Vec<String>,
@@ -39,29 +112,64 @@ impl PS4Package {
     pub const FILE_POS: u32 = 0x0200;
     const HEADER_SIZE: usize = 416;
     const ENTRY_SIZE: usize = 32;
-
+    // Decompressed prefix of a .pkg.gz kept in memory; covers the header and metadata entries
+    const GZIP_BUFFER_LIMIT: u64 = 64 * 1024 * 1024;
+    // Upper bounds for buffers sized from header fields; real name tables and metadata entries
+    // (param.sfo, icons) are far smaller
+    const NAME_BUFFER_LIMIT: u64 = 16 * 1024 * 1024;
+    const FILE_READ_LIMIT: u64 = 64 * 1024 * 1024;
+    // Short names accepted for common entries by `get_file`/`has_file`
+    const NAME_ALIASES: &'static [(&'static str, &'static str)] = &[
+        ("sfo", "param.sfo"),
+        ("icon", "icon0.png"),
+        ("background", "pic1.png"),
+        ("changeinfo", "changeinfo/changeinfo.xml"),
+    ];
+
+    /// Opens a package from disk, transparently decompressing `.pkg.gz` files and joining split `.pkg.0` parts.
     pub fn new(filepath: PathBuf) -> Result<Self> {
+        if is_split_package(&filepath) {
+            let reader = SplitReader::open(&filepath)?;
+            return Self::from_reader(filepath, reader);
+        }
+        let file = File::open(&filepath)?;
+        if is_gzip_package(&filepath) {
+            let mut buffer = Vec::new();
+            GzDecoder::new(file).take(Self::GZIP_BUFFER_LIMIT).read_to_end(&mut buffer)?;
+            debug!("Buffered {} decompressed bytes from '{}'", buffer.len(), filepath.display());
+            Self::from_reader(filepath, Cursor::new(buffer))
+        } else {
+            Self::from_reader(filepath, file)
+        }
+    }
+
+    /// Parses a package from any seekable source; `filepath` is used for identification only.
+    pub fn from_reader<R: ReadSeek + 'static>(filepath: PathBuf, mut reader: R) -> Result<Self> {
+        let size = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
         let mut pkg = PS4Package {
-            filepath,
+            filepath: filepath.clone(),
+            reader: PackageReader { inner: Box::new(reader), size },
             file_entries: HashMap::new(),
             content_id: String::new(),
             content_type: ContentCategory::Game,
+            content_flags: ContentFlags::default(),
             iro_type: None,
             drm_type: DRMCategory::None,
             hashes: Vec::new(),
         };
-        pkg.parse_package()?;
+        pkg.parse_package().with_context(|| format!("Failed to parse package '{}'", filepath.display()))?;
         Ok(pkg)
     }
 
     fn parse_package(&mut self) -> Result<()> {
-        let mut file = File::open(&self.filepath)?;
-        let file_size = file.metadata()?.len();
-        debug!("PKG file size: {} bytes", file_size);
+        let file = &mut self.reader;
+        let file_size = file.size;
+        debug!("PKG file '{}' size: {} bytes", self.filepath.display(), file_size);
 
         if file_size < Self::HEADER_SIZE as u64 {
             error!("PKG file too small for header: {} bytes < {} bytes", file_size, Self::HEADER_SIZE);
-            return Err(anyhow::anyhow!("PKG file too small for header"));
+            return Err(PackageError::Truncated { region: "header", offset: 0, size: Self::HEADER_SIZE as u64, file_size }.into());
         }
 
         let mut header = vec![0u8; Self::HEADER_SIZE];
@@ -72,7 +180,7 @@ impl PS4Package {
         let magic = read_u32_be(&mut cursor)?;
         if magic != Self::VALID_MAGIC {
             error!("Invalid PKG magic value: {:08x}", magic);
-            return Err(anyhow::anyhow!("Invalid PKG magic value"));
+            return Err(PackageError::InvalidMagic { found: magic }.into());
         }
 
         let pkg_type = read_u32_be(&mut cursor)?;
@@ -94,7 +202,7 @@ impl PS4Package {
         cursor.read_exact(&mut padding)?;
         let drm_type = read_u32_be(&mut cursor)?;
         let content_type = read_u32_be(&mut cursor)?;
-        let _content_flags = read_u32_be(&mut cursor)?;
+        let content_flags = read_u32_be(&mut cursor)?;
         let _promote_size = read_u32_be(&mut cursor)?;
         let _version_date = read_u32_be(&mut cursor)?;
         let _version_hash = read_u32_be(&mut cursor)?;
@@ -106,7 +214,7 @@ impl PS4Package {
         debug!("PKG Type: {:08x}, File Count: {}, Entry Count: {}", pkg_type, file_count, entry_count);
         debug!("SC Entry Count: {}, Table Pos: {}, Entry Data Size: {}", sc_entry_count, table_pos, entry_data_size);
         debug!("ID: {}", self.content_id);
-        debug!("DRM Type: {:08x}, Content Type: {:08x}", drm_type, content_type);
+        debug!("DRM Type: {:08x}, Content Type: {:08x}, Content Flags: {:08x}", drm_type, content_type, content_flags);
         debug!("Body Pos: {}, Body Size: {}, Content Pos: {}, Content Size: {}",
                body_pos, body_size, content_pos, content_size);
 
@@ -122,6 +230,7 @@ impl PS4Package {
             0x1E => ContentCategory::Demo,
             _ => ContentCategory::Game,
         };
+        self.content_flags = ContentFlags::from_bits(content_flags);
         self.iro_type = match iro_type {
             0x1 => Some(IROCategory::SFTheme),
             0x2 => Some(IROCategory::SysTheme),
@@ -130,7 +239,7 @@ impl PS4Package {
 
         if file_size < Self::HASH_POS + 128 {
             error!("PKG file too small for hash data: {} bytes < {} bytes", file_size, Self::HASH_POS + 128);
-            return Err(anyhow::anyhow!("PKG file too small for hash data"));
+            return Err(PackageError::Truncated { region: "hash data", offset: Self::HASH_POS, size: 128, file_size }.into());
         }
 
         debug!("Reading hash data at offset {} (size: 128 bytes)", Self::HASH_POS);
@@ -146,17 +255,20 @@ impl PS4Package {
         }
         debug!("Current file position after hash read: {}", file.stream_position()?);
 
-        self.parse_files(&mut file, table_pos, entry_count as usize, entry_data_size)?;
+        self.parse_files(table_pos, entry_count as usize, entry_data_size)?;
         Ok(())
     }
 
-    fn parse_files(&mut self, file: &mut File, table_pos: u64, entry_count: usize, entry_data_size: u64) -> Result<()> {
-        let file_size = file.metadata()?.len();
+    fn parse_files(&mut self, table_pos: u64, entry_count: usize, entry_data_size: u64) -> Result<()> {
+        let file = &mut self.reader;
+        let file_size = file.size;
         let expected_end = table_pos + (entry_count as u64 * Self::ENTRY_SIZE as u64);
         if file_size < expected_end {
             error!("PKG file too small for {} entries: {} bytes < {} bytes",
                    entry_count, file_size, expected_end);
-            return Err(anyhow::anyhow!("PKG file too small for entry table"));
+            return Err(PackageError::Truncated {
+                region: "entry table", offset: table_pos, size: entry_count as u64 * Self::ENTRY_SIZE as u64, file_size,
+            }.into());
         }
 
         debug!("Reading {} PKG entries at offset {} (size: {} bytes)",
@@ -168,7 +280,7 @@ impl PS4Package {
         let mut raw_data = vec![0u8; 64.min(file_size.saturating_sub(table_pos) as usize)];
         if !raw_data.is_empty() {
             file.seek(SeekFrom::Start(table_pos))?;
-            if let Ok(_) = file.read_exact(&mut raw_data) {
+            if file.read_exact(&mut raw_data).is_ok() {
                 debug!("Raw data at table_pos {} (first {} bytes): {}", table_pos, raw_data.len(), hex::encode(&raw_data));
             }
             file.seek(SeekFrom::Start(table_pos))?;
@@ -213,18 +325,26 @@ impl PS4Package {
 
         if self.file_entries.is_empty() {
             error!("No valid entries parsed from entry table");
-            return Err(anyhow::anyhow!("No valid entries parsed"));
+            return Err(PackageError::NoEntries { table_pos }.into());
         }
 
         let file_pos = self.file_entries.get(&Self::FILE_POS).ok_or_else(|| {
             error!("Missing file table entry at ID {:08x}", Self::FILE_POS);
-            anyhow::anyhow!("Missing file table entry")
+            PackageError::MissingEntry { entry_id: Self::FILE_POS }
         })?;
 
+        if entry_data_size > Self::NAME_BUFFER_LIMIT {
+            error!("PKG name buffer size {} exceeds limit {}", entry_data_size, Self::NAME_BUFFER_LIMIT);
+            return Err(PackageError::TooLarge {
+                region: "name buffer", size: entry_data_size, limit: Self::NAME_BUFFER_LIMIT,
+            }.into());
+        }
         if file_size < file_pos.offset + entry_data_size {
             error!("PKG file too small for name buffer: {} bytes < {} bytes",
                    file_size, file_pos.offset + entry_data_size);
-            return Err(anyhow::anyhow!("PKG file too small for name buffer"));
+            return Err(PackageError::Truncated {
+                region: "name buffer", offset: file_pos.offset, size: entry_data_size, file_size,
+            }.into());
         }
 
         debug!("Reading name buffer at offset {} (size: {} bytes)", file_pos.offset, entry_data_size);
@@ -250,39 +370,200 @@ impl PS4Package {
         Ok(())
     }
 
-    pub fn get_file(&self, identifier: &str) -> Result<Vec<u8>> {
-        let file_data = self.locate_file(identifier)?;
-        let mut file = File::open(&self.filepath)?;
-        let file_size = file.metadata()?.len();
+    pub fn get_file(&mut self, identifier: &str) -> Result<Vec<u8>> {
+        self.get_file_limited(identifier, Self::FILE_READ_LIMIT)
+    }
 
-        if file_data.offset + file_data.size > file_size {
+    /// Reads an entry like `get_file`, refusing it before allocating when the entry table gives it more than
+    /// `limit` bytes.
+    pub fn get_file_limited(&mut self, identifier: &str, limit: u64) -> Result<Vec<u8>> {
+        let (offset, size) = self.locate_file(identifier).map(|entry| (entry.offset, entry.size))?;
+        let file = &mut self.reader;
+
+        let limit = limit.min(Self::FILE_READ_LIMIT);
+        if size > limit {
+            error!("File '{}' size {} exceeds limit {}", identifier, size, limit);
+            return Err(PackageError::TooLarge { region: "file data", size, limit }.into());
+        }
+        if offset + size > file.size {
             error!("File data out of bounds: offset {} + size {} > file size {}",
-                   file_data.offset, file_data.size, file_size);
-            return Err(anyhow::anyhow!("File data out of bounds"));
+                   offset, size, file.size);
+            return Err(PackageError::Truncated { region: "file data", offset, size, file_size: file.size }.into());
         }
 
-        debug!("Reading file data for '{}': offset {}, size {}", identifier, file_data.offset, file_data.size);
-        file.seek(SeekFrom::Start(file_data.offset))?;
-        let mut buffer = vec![0u8; file_data.size as usize];
-        file.read_exact(&mut buffer)?;
+        debug!("Reading file data for '{}': offset {}, size {}", identifier, offset, size);
+        let mut buffer = vec![0u8; size as usize];
+        read_resumable(file, offset, &mut buffer)
+            .with_context(|| format!("Failed to read '{}' ({} bytes at offset {:#x})", identifier, size, offset))?;
         Ok(buffer)
     }
 
-    pub fn save_file(&self, identifier: &str, destination: &Path) -> Result<()> {
-        let data = self.get_file(identifier)?;
-        let mut output = File::create(destination)?;
-        output.write_all(&data)?;
-        debug!("Saved file '{}' to '{}'", identifier, destination.display());
-        Ok(())
+    /// Bundled contents beyond the package's own: `<dir>/param.sfo` entries, each with its
+    /// `<dir>/icon0.png`, returned as (SFO entry name, icon entry name) sorted by name.
+    pub fn extra_contents(&self) -> Vec<(String, String)> {
+        let mut contents: Vec<(String, String)> = self.file_entries.values()
+            .filter_map(|entry| entry.name.as_deref()?.strip_suffix("/param.sfo"))
+            .map(|dir| (format!("{}/param.sfo", dir), format!("{}/icon0.png", dir)))
+            .collect();
+        contents.sort();
+        contents
+    }
+
+    pub fn has_file(&self, identifier: &str) -> bool {
+        self.locate_file(identifier).is_ok()
     }
 
+    /// Finds an entry by hex id (`0x1000`) or by name. Names match case-insensitively, preferring an exact
+    /// match, and may be one of the `NAME_ALIASES` (`sfo`, `icon`, ...).
     fn locate_file(&self, identifier: &str) -> Result<&FileEntry> {
         if let Ok(entry_id) = u32::from_str_radix(identifier.trim_start_matches("0x"), 16) {
-            self.file_entries.get(&entry_id).ok_or_else(|| anyhow::anyhow!("File not found: {}", identifier))
-        } else {
-            self.file_entries.values()
-                .find(|entry| entry.name.as_ref().map_or(false, |n| n == identifier))
-                .ok_or_else(|| anyhow::anyhow!("File not found: {}", identifier))
+            return self.file_entries.get(&entry_id).ok_or_else(|| PackageError::EntryNotFound(identifier.to_string()).into());
         }
+        let name = Self::NAME_ALIASES.iter()
+            .find(|(alias, _)| alias.eq_ignore_ascii_case(identifier))
+            .map_or(identifier, |&(_, name)| name);
+        let named = |exact: bool| self.file_entries.iter()
+            .filter(|(_, entry)| entry.name.as_deref().is_some_and(|entry_name| {
+                if exact { entry_name == name } else { entry_name.eq_ignore_ascii_case(name) }
+            }))
+            .min_by_key(|&(entry_id, _)| *entry_id)
+            .map(|(_, entry)| entry);
+        named(true).or_else(|| named(false))
+            .ok_or_else(|| PackageError::EntryNotFound(identifier.to_string()).into())
+    }
+}
+
+/// Fills `buffer` from `offset`, retrying transient read errors (e.g. a flaky USB or network drive)
+/// from where the previous attempt stopped. End of file is not retried.
+fn read_resumable<R: Read + Seek>(reader: &mut R, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+    let mut filled = 0;
+    let mut failures = 0;
+    reader.seek(SeekFrom::Start(offset))?;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) if failures < READ_RETRIES => {
+                failures += 1;
+                warn!("Read error at offset {:#x}, retrying ({}/{}): {}", offset + filled as u64, failures, READ_RETRIES, e);
+                thread::sleep(READ_RETRY_DELAY * failures);
+                reader.seek(SeekFrom::Start(offset + filled as u64))?;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether `path` names a gzip-compressed package (`*.pkg.gz`).
+pub fn is_gzip_package(path: &Path) -> bool {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_lowercase().ends_with(".pkg.gz"))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{game_pkg, sfo, ICON};
+
+    const CONTENT_ID: &str = "UP0000-CUSA00001_00-GAME000000000000";
+
+    fn parse(data: Vec<u8>) -> Result<PS4Package> {
+        PS4Package::from_reader(PathBuf::from("game.pkg"), Cursor::new(data))
+    }
+
+    #[test]
+    fn extracts_several_entries_through_the_parse_handle() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("game.pkg");
+        std::fs::write(&path, game_pkg(CONTENT_ID, &[("TITLE_ID", "CUSA00001")])).unwrap();
+
+        let mut pkg = PS4Package::new(path.clone()).unwrap();
+        // Reads would fail if they reopened the path
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(pkg.get_file("param.sfo").unwrap(), sfo(&[("TITLE_ID", "CUSA00001")]));
+        assert_eq!(pkg.get_file("icon0.png").unwrap(), ICON);
+    }
+
+    #[test]
+    fn truncated_entry_table_error_names_its_offset() {
+        let mut data = game_pkg(CONTENT_ID, &[("TITLE_ID", "CUSA00001")]);
+        data.truncate(0x210);
+        let error = parse(data).unwrap_err();
+        let message = format!("{:#}", error);
+        assert!(message.contains("game.pkg"), "{}", message);
+        assert!(message.contains("entry table: needs 96 bytes at offset 0x200 (end 0x260) but file is 528 bytes"), "{}", message);
+        assert!(matches!(error.downcast_ref::<PackageError>(),
+                         Some(PackageError::Truncated { region: "entry table", offset: 0x200, size: 96, file_size: 0x210 })));
+    }
+
+    #[test]
+    fn truncated_entry_data_error_names_its_offset() {
+        let mut data = game_pkg(CONTENT_ID, &[("TITLE_ID", "CUSA00001")]);
+        data.truncate(data.len() - 1);
+        let mut pkg = parse(data).unwrap();
+        let icon_offset = pkg.locate_file("icon0.png").unwrap().offset;
+        let message = format!("{:#}", pkg.get_file("icon0.png").unwrap_err());
+        assert!(message.contains(&format!("needs {} bytes at offset {:#x}", ICON.len(), icon_offset)), "{}", message);
+    }
+
+    /// Reads `inner`, stopping short before `fail_at` and then failing `failures` reads there, like a flaky drive.
+    struct FlakyReader {
+        inner: Cursor<Vec<u8>>,
+        fail_at: u64,
+        failures: u32,
+    }
+
+    impl Read for FlakyReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let pos = self.inner.position();
+            if self.failures > 0 && pos == self.fail_at {
+                self.failures -= 1;
+                return Err(io::Error::other("simulated read error"));
+            }
+            let len = if self.failures > 0 && pos < self.fail_at {
+                buf.len().min((self.fail_at - pos) as usize)
+            } else {
+                buf.len()
+            };
+            self.inner.read(&mut buf[..len])
+        }
+    }
+
+    impl Seek for FlakyReader {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    fn flaky(failures: u32) -> (Vec<u8>, FlakyReader) {
+        let data: Vec<u8> = (0..4096).map(|i| i as u8).collect();
+        (data.clone(), FlakyReader { inner: Cursor::new(data), fail_at: 1000, failures })
+    }
+
+    #[test]
+    fn resumable_read_retries_from_where_it_failed() {
+        let (data, mut reader) = flaky(READ_RETRIES);
+        let mut buffer = vec![0u8; 2000];
+        read_resumable(&mut reader, 100, &mut buffer).unwrap();
+        assert_eq!(buffer, data[100..2100]);
+        assert_eq!(reader.failures, 0);
+    }
+
+    #[test]
+    fn resumable_read_gives_up_after_its_retries() {
+        let (_, mut reader) = flaky(READ_RETRIES + 1);
+        let mut buffer = vec![0u8; 2000];
+        let error = read_resumable(&mut reader, 100, &mut buffer).unwrap_err();
+        assert_eq!(error.to_string(), "simulated read error");
+    }
+
+    #[test]
+    fn resumable_read_does_not_retry_end_of_file() {
+        let mut buffer = vec![0u8; 20];
+        let error = read_resumable(&mut Cursor::new(vec![0u8; 10]), 0, &mut buffer).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
     }
 }
@@ -0,0 +1,176 @@
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use log::{debug, error};
+use tokio::sync::Mutex;
+
+use crate::args::GenerateArgs;
+use crate::webhook::EntryNotifier;
+use crate::CategoryCounts;
+
+/// Serializes regeneration runs so only one `run_generate` writes the output at a time.
+///
+/// Triggers arriving while a run is in progress are collapsed into a single follow-up run.
+pub struct Regenerator {
+    args: GenerateArgs,
+    running: Mutex<()>,
+    /// Categories whose files the next run has to write, if one was requested
+    pending: std::sync::Mutex<Option<Scope>>,
+    notifier: Option<EntryNotifier>,
+}
+
+/// Categories a run writes the files of: `None` for all of them.
+type Scope = Option<BTreeSet<String>>;
+
+impl Regenerator {
+    pub fn new(args: GenerateArgs) -> Self {
+        Regenerator { args, running: Mutex::new(()), pending: std::sync::Mutex::new(None), notifier: None }
+    }
+
+    /// Reports entries added by each successful run to `notifier` (`--notify-webhook`).
+    pub fn with_notifier(mut self, notifier: EntryNotifier) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    /// Arguments every run is performed with.
+    pub fn args(&self) -> &GenerateArgs {
+        &self.args
+    }
+
+    /// Requests a regeneration.
+    ///
+    /// Returns `None` if a run was already in progress (it will pick up this request),
+    /// otherwise the result (entry counts per category) of the last run performed.
+    pub async fn trigger(&self) -> Option<Result<CategoryCounts>> {
+        self.trigger_scope(None).await
+    }
+
+    /// Requests a regeneration that only writes the files of `categories` (`--watch-categories`), like `trigger`.
+    ///
+    /// When collapsed with other requests, the run writes the files all of them asked for.
+    pub async fn trigger_categories(&self, categories: BTreeSet<String>) -> Option<Result<CategoryCounts>> {
+        self.trigger_scope(Some(categories)).await
+    }
+
+    async fn trigger_scope(&self, scope: Scope) -> Option<Result<CategoryCounts>> {
+        self.collapse(scope, |scope| self.run(scope)).await
+    }
+
+    /// Records `scope` as pending and, unless a run is already in progress, performs the pending runs with `run`.
+    async fn collapse<F: Future<Output = Result<CategoryCounts>>>(&self, scope: Scope, run: impl Fn(Scope) -> F)
+                                                                 -> Option<Result<CategoryCounts>> {
+        {
+            let mut pending = self.pending.lock().unwrap();
+            *pending = Some(match (pending.take(), scope) {
+                (Some(Some(mut requested)), Some(categories)) => {
+                    requested.extend(categories);
+                    Some(requested)
+                }
+                (Some(None), _) | (_, None) => None,
+                (None, scope) => scope,
+            });
+        }
+        let mut result = None;
+        loop {
+            let Ok(guard) = self.running.try_lock() else {
+                debug!("Regeneration already in progress, collapsing trigger");
+                return result;
+            };
+            while let Some(scope) = self.take_pending() {
+                result = Some(run(scope).await);
+            }
+            drop(guard);
+            // A trigger may have arrived between the last check and releasing the lock
+            if self.pending.lock().unwrap().is_none() {
+                return result;
+            }
+        }
+    }
+
+    async fn run(&self, scope: Scope) -> Result<CategoryCounts> {
+        let result = crate::run_generate_categories(self.args.clone(), scope.as_ref()).await;
+        if let (Some(notifier), Ok(_)) = (&self.notifier, &result) {
+            notifier.regenerated().await;
+        }
+        result
+    }
+
+    fn take_pending(&self) -> Option<Scope> {
+        self.pending.lock().unwrap().take()
+    }
+
+    /// Triggers a regeneration every `interval`, e.g. to re-sign `--sign-secret` links before they expire.
+    pub async fn run_periodically(self: Arc<Self>, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            debug!("Running periodic regeneration");
+            if let Some(Err(e)) = self.trigger().await {
+                error!("Failed to regenerate JSON files: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use tokio::sync::Notify;
+
+    use super::*;
+    use crate::test_fixtures::generate_args;
+
+    fn categories(names: &[&str]) -> Scope {
+        Some(names.iter().map(|name| name.to_string()).collect())
+    }
+
+    #[tokio::test]
+    async fn collapses_triggers_during_a_run_into_one_merged_follow_up() {
+        let regenerator = Regenerator::new(generate_args(Path::new("packages"), Path::new("out"), &[]));
+        let runs = std::sync::Mutex::new(Vec::new());
+        let finish_first = Notify::new();
+        let run = |scope: Scope| {
+            let (runs, finish_first) = (&runs, &finish_first);
+            async move {
+                let first = {
+                    let mut runs = runs.lock().unwrap();
+                    runs.push(scope);
+                    runs.len() == 1
+                };
+                if first {
+                    finish_first.notified().await;
+                }
+                Ok(CategoryCounts::new())
+            }
+        };
+
+        let during_first_run = async {
+            while runs.lock().unwrap().is_empty() {
+                tokio::task::yield_now().await;
+            }
+            assert!(regenerator.collapse(categories(&["dlc"]), run).await.is_none());
+            assert!(regenerator.collapse(categories(&["themes"]), run).await.is_none());
+            finish_first.notify_one();
+        };
+        let (result, ()) = tokio::join!(regenerator.collapse(categories(&["games"]), run), during_first_run);
+
+        assert!(result.is_some_and(|result| result.is_ok()));
+        assert_eq!(*runs.lock().unwrap(), [categories(&["games"]), categories(&["dlc", "themes"])]);
+    }
+
+    #[tokio::test]
+    async fn a_full_trigger_widens_collapsed_category_triggers() {
+        let regenerator = Regenerator::new(generate_args(Path::new("packages"), Path::new("out"), &[]));
+        let guard = regenerator.running.try_lock().unwrap();
+        let run = |_: Scope| async { Ok(CategoryCounts::new()) };
+        assert!(regenerator.collapse(categories(&["games"]), run).await.is_none());
+        assert!(regenerator.collapse(None, run).await.is_none());
+        assert!(regenerator.collapse(categories(&["dlc"]), run).await.is_none());
+        drop(guard);
+        assert_eq!(regenerator.take_pending(), Some(None));
+    }
+}
@@ -0,0 +1,218 @@
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use log::debug;
+use percent_encoding::percent_decode_str;
+
+use crate::error::CommandError;
+
+// Minimum bytes fetched per Range request; header parsing does many small reads close together
+const BLOCK_SIZE: u64 = 64 * 1024;
+
+/// Seekable reader over a remote package, fetching only the byte ranges actually read.
+///
+/// The most recently fetched block is kept so nearby small reads don't each cost a request.
+pub struct HttpRangeReader {
+    agent: ureq::Agent,
+    url: String,
+    size: u64,
+    pos: u64,
+    block_start: u64,
+    block: Vec<u8>,
+}
+
+impl HttpRangeReader {
+    /// Opens `url`, learning its size with a HEAD request.
+    pub fn open(url: &str) -> Result<Self> {
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(Duration::from_secs(10))
+            .timeout_read(Duration::from_secs(30))
+            .build();
+        let response = agent.head(url).call()
+            .with_context(|| format!("HEAD request failed for '{}'", url))?;
+        let size = response.header("Content-Length")
+            .and_then(|len| len.parse().ok())
+            .ok_or_else(|| anyhow!("No Content-Length for '{}'", url))?;
+        Ok(HttpRangeReader { agent, url: url.to_string(), size, pos: 0, block_start: 0, block: Vec::new() })
+    }
+
+    /// Size of the remote file in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn fetch(&mut self, start: u64, len: u64) -> io::Result<()> {
+        let end = (start + len).min(self.size) - 1;
+        debug!("Fetching bytes {}-{} of '{}'", start, end, self.url);
+        let response = self.agent.get(&self.url)
+            .set("Range", &format!("bytes={}-{}", start, end))
+            .call()
+            .map_err(io::Error::other)?;
+        // A 200 means the server is sending the whole file, which is only acceptable if that is what was asked for
+        let whole_file = start == 0 && end + 1 == self.size;
+        if response.status() != 206 && !(response.status() == 200 && whole_file) {
+            return Err(io::Error::other(format!("server returned {} instead of 206 Partial Content", response.status())));
+        }
+        let mut block = Vec::with_capacity((end - start + 1) as usize);
+        response.into_reader().take(end - start + 1).read_to_end(&mut block)?;
+        self.block_start = start;
+        self.block = block;
+        Ok(())
+    }
+}
+
+impl Read for HttpRangeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.size {
+            return Ok(0);
+        }
+        let block_end = self.block_start + self.block.len() as u64;
+        if self.pos < self.block_start || self.pos >= block_end {
+            self.fetch(self.pos, BLOCK_SIZE.max(buf.len() as u64))?;
+        }
+        let offset = (self.pos - self.block_start) as usize;
+        let count = buf.len().min(self.block.len() - offset);
+        if count == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "remote range response was short"));
+        }
+        buf[..count].copy_from_slice(&self.block[offset..offset + count]);
+        self.pos += count as u64;
+        Ok(count)
+    }
+}
+
+impl Seek for HttpRangeReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = target.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative position"))?;
+        Ok(self.pos)
+    }
+}
+
+/// Reads package URLs from `--remote-manifest`, one per line; blank lines and `#` comments are skipped.
+///
+/// Relative entries are joined onto `base`; without a base they are rejected.
+pub fn read_manifest(manifest: &Path, base: Option<&str>) -> Result<Vec<String>> {
+    let contents = fs::read_to_string(manifest)
+        .with_context(|| format!("Failed to read remote manifest '{}'", manifest.display()))?;
+    contents.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            if line.starts_with("http://") || line.starts_with("https://") {
+                Ok(line.to_string())
+            } else if let Some(base) = base {
+                Ok(format!("{}/{}", base.trim_end_matches('/'), line.trim_start_matches('/')))
+            } else {
+                Err(CommandError::InvalidConfig(format!("Relative manifest entry '{}' needs --remote-base", line)).into())
+            }
+        })
+        .collect()
+}
+
+/// Local path mirroring a remote package URL (`host/dirs/file`), used to place its extracted icon.
+pub fn local_mirror_path(url: &str) -> PathBuf {
+    let without_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let without_query = without_scheme.split(['?', '#']).next().unwrap_or_default();
+    // `host:port` is not a valid file name everywhere
+    let decoded = percent_decode_str(without_query).decode_utf8_lossy().replace(':', "_");
+    // Only keep plain path segments so a crafted URL cannot escape the icons directory
+    Path::new(&decoded).components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use tokio_util::sync::CancellationToken;
+
+    use super::*;
+    use crate::json_builder::handle_packages;
+    use crate::ps4_package::PS4Package;
+    use crate::test_fixtures::{game_pkg, generate_args, sfo};
+
+    const CONTENT_ID: &str = "UP0000-CUSA00001_00-GAME000000000000";
+
+    /// Serves `data` at any path over HTTP with Range support, recording the bytes sent for each GET.
+    fn serve(data: Vec<u8>) -> (String, Arc<Mutex<Vec<usize>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/library/game.pkg", listener.local_addr().unwrap());
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let sent_clone = sent.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut lines = BufReader::new(stream.try_clone().unwrap()).lines().map(Result::unwrap);
+                let method = lines.next().unwrap().split(' ').next().unwrap().to_string();
+                let mut range = None;
+                for line in lines.by_ref().take_while(|line| !line.is_empty()) {
+                    if let Some(value) = line.to_lowercase().strip_prefix("range: bytes=") {
+                        let (start, end) = value.split_once('-').unwrap();
+                        range = Some((start.parse::<usize>().unwrap(), end.parse::<usize>().unwrap()));
+                    }
+                }
+                let (status, body) = match range {
+                    Some((start, end)) => ("206 Partial Content", &data[start..=end]),
+                    None => ("200 OK", &data[..]),
+                };
+                let mut response = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len())
+                    .into_bytes();
+                if method == "GET" {
+                    sent_clone.lock().unwrap().push(body.len());
+                    response.extend(body);
+                }
+                stream.write_all(&response).unwrap();
+            }
+        });
+        (url, sent)
+    }
+
+    /// A game package followed by enough data that reading all of it would take several blocks.
+    fn large_package() -> Vec<u8> {
+        let mut data = game_pkg(CONTENT_ID, &[("CATEGORY", "gd"), ("TITLE_ID", "CUSA00001")]);
+        data.resize(data.len() + 4 * BLOCK_SIZE as usize, 0);
+        data
+    }
+
+    #[test]
+    fn parses_a_remote_package_from_byte_ranges() {
+        let data = large_package();
+        let (url, sent) = serve(data.clone());
+        let reader = HttpRangeReader::open(&url).unwrap();
+        assert_eq!(reader.size(), data.len() as u64);
+
+        let mut pkg = PS4Package::from_reader(PathBuf::from(&url), reader).unwrap();
+        assert_eq!(pkg.content_id, CONTENT_ID);
+        assert_eq!(pkg.get_file("param.sfo").unwrap(), sfo(&[("CATEGORY", "gd"), ("TITLE_ID", "CUSA00001")]));
+        let sent = sent.lock().unwrap();
+        assert!(sent.iter().sum::<usize>() <= BLOCK_SIZE as usize, "{:?}", sent);
+    }
+
+    #[test]
+    fn links_remote_manifest_packages_to_their_urls() {
+        let (url, _) = serve(large_package());
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = dir.path().join("manifest.txt");
+        fs::write(&manifest, format!("# Remote library\n{}\n", url)).unwrap();
+        let library = dir.path().join("library");
+        fs::create_dir(&library).unwrap();
+
+        let args = generate_args(&library, &dir.path().join("out"), &["--remote-manifest", &manifest.to_string_lossy()]);
+        let output_data = handle_packages(&args, &CancellationToken::new()).unwrap();
+        let links: Vec<_> = output_data["games"].keys().collect();
+        assert_eq!(links, [&url]);
+    }
+}
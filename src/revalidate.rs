@@ -0,0 +1,83 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use log::{debug, error, info, warn};
+use serde_json::Value as JsonValue;
+use tokio::task;
+use walkdir::WalkDir;
+
+use crate::args::GenerateArgs;
+use crate::entry::document_entries;
+use crate::json_builder::{existing_package_file, local_package_path};
+use crate::ps4_package::PS4Package;
+use crate::regenerate::Regenerator;
+use crate::vita::{is_vita_package, VitaPackage};
+
+/// Periodically re-opens every indexed local package, enabled in `host` mode with `--revalidate-interval`.
+///
+/// When a package no longer reads (e.g. a drive developed bad sectors), the JSON files are regenerated,
+/// which drops its entry so consoles don't try to download a dead file.
+pub async fn run_revalidation(args: GenerateArgs, regenerator: Arc<Regenerator>, interval: Duration) -> Result<()> {
+    loop {
+        tokio::time::sleep(interval).await;
+        let scan_args = args.clone();
+        let unreadable = task::spawn_blocking(move || unreadable_packages(&scan_args)).await?;
+        if unreadable.is_empty() {
+            continue;
+        }
+        info!("Regenerating JSON files to drop {} unreadable packages", unreadable.len());
+        match regenerator.trigger().await {
+            Some(Err(e)) => error!("Failed to regenerate JSON files: {:?}", e),
+            Some(Ok(_)) => info!("Regenerated JSON files after revalidation"),
+            None => debug!("Regeneration already in progress; unreadable packages will be dropped by it"),
+        }
+    }
+}
+
+/// Returns the local packages referenced by the generated JSON files whose header can no longer be read.
+fn unreadable_packages(args: &GenerateArgs) -> Vec<PathBuf> {
+    let mut packages = BTreeSet::new();
+    for entry in WalkDir::new(&args.out.0).into_iter().filter_map(Result::ok) {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let Ok(json @ JsonValue::Object(_)) = fs::read(path).map_err(anyhow::Error::from)
+            .and_then(|data| serde_json::from_slice(&data).map_err(anyhow::Error::from)) else {
+            debug!("Skipping unreadable JSON in revalidation: {}", path.display());
+            continue;
+        };
+        let Some(data) = document_entries(&json) else { continue };
+        // Remote and external entries have no local file to check
+        packages.extend(data.keys().filter_map(|link| local_package_path(args, link)));
+    }
+
+    debug!("Revalidating {} indexed packages", packages.len());
+    packages.into_iter()
+        .filter(|pkg_path| {
+            // A package that is gone entirely is left to the watcher
+            let Some(file) = existing_package_file(pkg_path) else { return false };
+            match check_header(&file) {
+                Ok(()) => false,
+                Err(e) => {
+                    warn!("Dropping unreadable package '{}': {:#}", file.display(), e);
+                    true
+                }
+            }
+        })
+        .collect()
+}
+
+/// Re-opens `path` and reads its header and entry table.
+fn check_header(path: &Path) -> Result<()> {
+    if is_vita_package(path) {
+        VitaPackage::open(path)?;
+    } else {
+        PS4Package::new(path.to_path_buf())?;
+    }
+    Ok(())
+}
@@ -1,20 +1,82 @@
-use actix_web::{App, HttpServer, middleware::Logger, HttpResponse, Responder, web, http::header, HttpRequest};
-use actix_files::Files;
-use anyhow::Result;
+use actix_web::{guard, App, HttpServer, middleware::{from_fn, Logger}, HttpResponse, web, http::header, HttpRequest};
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::http::{KeepAlive, Uri};
+use actix_web::dev::{fn_service, AppConfig, Service, ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_http::HttpService;
+use actix_service::map_config;
+use actix_files::{Files, NamedFile};
+use anyhow::{Context, Result};
+use flate2::read::GzDecoder;
 use std::collections::HashMap;
-use std::path::PathBuf;
-use std::fs;
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+use std::path::{Component, Path, PathBuf};
+use std::fs::{self, File};
+use std::time::Duration;
+use futures_util::future::{ready, try_join, Either};
 use log::debug;
-use percent_encoding::percent_decode_str;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde_json::{json, Value as JsonValue};
+use walkdir::WalkDir;
+
+use crate::admin::{self, AdminApi};
+use crate::assets::{self, Assets};
+use crate::error::CommandError;
+use crate::feed::{self, Feed};
+use crate::args::ServerArgs;
+use crate::download_limiter::{DownloadLimiter, GuardedBody};
+use crate::signing::UrlSigner;
+use crate::split::SplitReader;
+use crate::template::{self, escape_html, ListingEntry};
+use crate::tls;
+
+// Content types served for known extensions, regardless of which handler produced the response
+const MIME_TYPES: &[(&str, &str)] = &[
+    ("png", "image/png"),
+    ("json", "application/json"),
+    ("pkg", "application/octet-stream"),
+];
+
+
+// Most packages shown by a `?recursive=1` listing, and how deep it descends
+const RECURSIVE_LISTING_LIMIT: usize = 5000;
+const RECURSIVE_LISTING_DEPTH: usize = 16;
+
+// Characters escaped in a single path segment of a listing href: everything but unreserved marks
+const HREF_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
 
 #[derive(Clone, Debug)]
 pub struct ServerConfig {
     directories: HashMap<String, PathBuf>,
+    /// `--template` contents used for index and listing pages instead of the built-in markup
+    template: Option<String>,
+    /// `--path-prefix` (e.g. `/fpkgi`) prepended to links and redirects, or empty
+    path_prefix: String,
+    /// Directory whose requests need a valid `--sign-secret` signature, with the signer checking it
+    signed_dir: Option<(String, UrlSigner)>,
+    /// `--hide-dir` paths, relative to each served directory, left out of the listing pages
+    hidden_dirs: Vec<PathBuf>,
 }
 
 impl ServerConfig {
     pub fn new(directories: HashMap<String, PathBuf>) -> Self {
-        ServerConfig { directories }
+        ServerConfig { directories, template: None, path_prefix: String::new(), signed_dir: None, hidden_dirs: Vec::new() }
+    }
+
+    /// Requires requests under the directory served as `name` to carry a valid signature from `signer`.
+    pub fn with_signed_dir(mut self, name: String, signer: UrlSigner) -> Self {
+        self.signed_dir = Some((name, signer));
+        self
+    }
+
+    /// Whether `rel_path`, relative to a served directory, is a `--hide-dir` directory or lies inside one.
+    fn is_hidden(&self, rel_path: &Path) -> bool {
+        self.hidden_dirs.iter().any(|hidden| rel_path.starts_with(hidden))
+    }
+
+    /// Stylesheet link of the built-in index and listing pages.
+    fn stylesheet_link(&self) -> String {
+        format!("<link rel=\"stylesheet\" href=\"{}/_assets/style.css\">", self.path_prefix)
     }
 }
 
@@ -41,18 +103,27 @@ pub fn parse_config(dirs: Vec<String>) -> Result<ServerConfig, String> {
         return Err("No valid directories specified".to_string());
     }
 
-    Ok(ServerConfig { directories })
+    Ok(ServerConfig::new(directories))
 }
 
-async fn root_index(config: web::Data<ServerConfig>) -> impl Responder {
+async fn root_index(config: web::Data<ServerConfig>) -> HttpResponse {
     let mut dir_names: Vec<&String> = config.directories.keys().collect();
-    dir_names.sort_by(|a, b| a.to_lowercase().cmp(&b.to_lowercase())); // Case-insensitive sort
+    dir_names.sort_by_key(|name| name.to_lowercase()); // Case-insensitive sort
+
+    if let Some(template) = &config.template {
+        let entries: Vec<ListingEntry> = dir_names.iter()
+            .map(|name| ListingEntry { name: format!("/{}", name), href: format!("{}/{}/", config.path_prefix, name), size: None })
+            .collect();
+        return HttpResponse::Ok()
+            .content_type("text/html")
+            .body(template::render(template, "FPKGi Server Index", "/", &entries));
+    }
 
-    let mut html = String::from("<!DOCTYPE html>\n<html>\n<head><title>FPKGi Server Index</title></head>\n<body>\n<h1>Available Directories</h1>\n<ul>\n");
+    let mut html = format!("<!DOCTYPE html>\n<html>\n<head><title>FPKGi Server Index</title>{}</head>\n<body>\n<h1>Available Directories</h1>\n<ul>\n", config.stylesheet_link());
     for name in dir_names {
         html.push_str(&format!(
-            "<li><a href=\"/{}/\">/{}</a></li>\n",
-            name, name
+            "<li><a href=\"{}/{}/\">/{}</a></li>\n",
+            config.path_prefix, name, name
         ));
     }
     html.push_str("</ul>\n</body>\n</html>");
@@ -61,7 +132,26 @@ async fn root_index(config: web::Data<ServerConfig>) -> impl Responder {
         .body(html)
 }
 
-async fn dir_listing(config: web::Data<ServerConfig>, req: HttpRequest) -> impl Responder {
+/// Returns whether `path` names an existing directory under the served directory `name` that is not hidden.
+fn is_directory_request(config: &ServerConfig, name: &str, root: &Path, path: &str) -> bool {
+    let decoded_path = percent_decode_str(path).decode_utf8_lossy();
+    let Some(rest) = decoded_path.strip_prefix('/').and_then(|p| p.strip_prefix(name)) else { return false };
+    if !(rest.is_empty() || rest.starts_with('/')) {
+        return false;
+    }
+    let subpath = Path::new(rest.trim_start_matches('/'));
+    subpath.components().all(|c| matches!(c, Component::Normal(_))) && !config.is_hidden(subpath) && root.join(subpath).is_dir()
+}
+
+async fn dir_handler(config: web::Data<ServerConfig>, req: HttpRequest) -> HttpResponse {
+    if req.path().ends_with('/') {
+        dir_listing(config, req).await
+    } else {
+        dir_redirect(config, req).await
+    }
+}
+
+async fn dir_listing(config: web::Data<ServerConfig>, req: HttpRequest) -> HttpResponse {
     let path_str = req.path();
     let clean_path = path_str.trim_start_matches('/');
     let decoded_path = percent_decode_str(clean_path).decode_utf8_lossy().to_string();
@@ -75,20 +165,51 @@ async fn dir_listing(config: web::Data<ServerConfig>, req: HttpRequest) -> impl
 
     if let Some(dir_path) = config.directories.get(&base) {
         let full_path = dir_path.join(&subpath);
+        if full_path.is_dir() && is_recursive_query(req.query_string()) {
+            // Hidden directories below this one, relative to it
+            let hidden: Vec<PathBuf> = config.hidden_dirs.iter()
+                .filter_map(|hidden| hidden.strip_prefix(&subpath).ok())
+                .filter(|hidden| !hidden.as_os_str().is_empty())
+                .map(Path::to_path_buf)
+                .collect();
+            return recursive_listing(&config, full_path, hidden, clean_path, &decoded_path).await;
+        }
         if full_path.is_dir() {
             match fs::read_dir(&full_path) {
                 Ok(entries) => {
-                    let mut file_list: Vec<String> = entries
+                    let mut file_list: Vec<(String, Option<u64>, bool)> = entries
                         .filter_map(|entry| entry.ok())
-                        .map(|entry| entry.file_name().to_string_lossy().to_string())
+                        .map(|entry| {
+                            // The file type comes with the directory entry; only symlinks need a stat to resolve
+                            let is_dir = entry.file_type()
+                                .is_ok_and(|file_type| file_type.is_dir() || (file_type.is_symlink() && entry.path().is_dir()));
+                            let size = if is_dir {
+                                None
+                            } else {
+                                entry.metadata().ok().filter(|meta| meta.is_file()).map(|meta| meta.len())
+                            };
+                            (entry.file_name().to_string_lossy().to_string(), size, is_dir)
+                        })
+                        .filter(|(name, _, is_dir)| !(*is_dir && config.is_hidden(&Path::new(&subpath).join(name))))
                         .collect();
-                    file_list.sort_by(|a, b| a.to_lowercase().cmp(&b.to_lowercase())); // Case-insensitive sort
+                    // Directories first, then files, each sorted case-insensitively
+                    file_list.sort_by_cached_key(|(name, _, is_dir)| (!is_dir, name.to_lowercase()));
+
+                    let request_path = format!("{}/{}", config.path_prefix, clean_path); // Use original encoded path for links
+                    // Names may contain `%`, `#`, `?` etc. literally, so escape them in the href
+                    let href = |name: &str| format!("{}/{}", request_path.trim_end_matches('/'), utf8_percent_encode(name, HREF_SEGMENT));
+                    if let Some(template) = &config.template {
+                        let entries: Vec<ListingEntry> = file_list.iter()
+                            .map(|(name, size, _)| ListingEntry { name: name.clone(), href: href(name), size: *size })
+                            .collect();
+                        return HttpResponse::Ok()
+                            .content_type("text/html")
+                            .body(template::render(template, "Directory Listing", &format!("/{}", decoded_path), &entries));
+                    }
 
-                    let request_path = format!("/{}", clean_path); // Use original encoded path for links
-                    let mut html = String::from("<!DOCTYPE html>\n<html>\n<head><title>Directory Listing</title></head>\n<body>\n<h1>Directory Contents</h1>\n<ul>\n");
-                    for name in file_list {
-                        let link_path = format!("{}/{}", request_path.trim_end_matches('/'), name);
-                        html.push_str(&format!("<li><a href=\"{}\">{}</a></li>\n", link_path, name));
+                    let mut html = format!("<!DOCTYPE html>\n<html>\n<head><title>Directory Listing</title>{}</head>\n<body>\n<h1>Directory Contents</h1>\n<p><a href=\"?recursive=1\">All packages in subfolders</a></p>\n<ul>\n", config.stylesheet_link());
+                    for (name, _, _) in file_list {
+                        html.push_str(&format!("<li><a href=\"{}\">{}</a></li>\n", href(&name), escape_html(&name)));
                     }
                     html.push_str("</ul>\n</body>\n</html>");
                     debug!("Rendering directory listing for: {}", clean_path);
@@ -98,7 +219,7 @@ async fn dir_listing(config: web::Data<ServerConfig>, req: HttpRequest) -> impl
                 }
                 Err(e) => {
                     log::warn!("Error reading directory {:?}: {}", full_path, e);
-                    return HttpResponse::InternalServerError().body("Error reading directory");
+                    return io_error_response(&e);
                 }
             }
         }
@@ -108,7 +229,75 @@ async fn dir_listing(config: web::Data<ServerConfig>, req: HttpRequest) -> impl
     HttpResponse::NotFound().body("404 - Not Found")
 }
 
-async fn dir_redirect(config: web::Data<ServerConfig>, req: HttpRequest) -> impl Responder {
+fn is_recursive_query(query: &str) -> bool {
+    query.split('&').any(|param| matches!(param, "recursive=1" | "recursive=true"))
+}
+
+/// `?recursive=1` listing: every `.pkg` below `dir` with its relative path and size on one page.
+///
+/// The walk skips the `hidden` directories (relative to `dir`), is bounded in depth and stops after
+/// `RECURSIVE_LISTING_LIMIT` packages, noting the truncation.
+async fn recursive_listing(config: &ServerConfig, dir: PathBuf, hidden: Vec<PathBuf>, clean_path: &str, decoded_path: &str) -> HttpResponse {
+    let walk = web::block(move || {
+        let mut packages: Vec<(String, Option<u64>)> = Vec::new();
+        let walker = WalkDir::new(&dir).max_depth(RECURSIVE_LISTING_DEPTH).sort_by_file_name();
+        let is_visible = |entry: &walkdir::DirEntry| {
+            entry.path().strip_prefix(&dir).map_or(true, |rel_path| !hidden.iter().any(|hidden| rel_path.starts_with(hidden)))
+        };
+        for entry in walker.into_iter().filter_entry(is_visible).filter_map(Result::ok) {
+            if !entry.file_type().is_file() || !entry.file_name().to_string_lossy().to_lowercase().ends_with(".pkg") {
+                continue;
+            }
+            if packages.len() == RECURSIVE_LISTING_LIMIT {
+                return (packages, true);
+            }
+            let Ok(rel_path) = entry.path().strip_prefix(&dir) else { continue };
+            let rel_path = rel_path.to_string_lossy().replace(std::path::MAIN_SEPARATOR, "/");
+            packages.push((rel_path, entry.metadata().ok().map(|meta| meta.len())));
+        }
+        (packages, false)
+    }).await;
+    let (packages, truncated) = match walk {
+        Ok(result) => result,
+        Err(e) => {
+            log::warn!("Error walking directory for /{}: {}", decoded_path, e);
+            return HttpResponse::InternalServerError().body("Error reading directory");
+        }
+    };
+    if truncated {
+        log::warn!("Recursive listing of /{} truncated at {} packages", decoded_path, RECURSIVE_LISTING_LIMIT);
+    }
+
+    let request_path = format!("{}/{}", config.path_prefix, clean_path.trim_end_matches('/'));
+    let href = |rel_path: &str| {
+        let segments: Vec<String> = rel_path.split('/').map(|segment| utf8_percent_encode(segment, HREF_SEGMENT).to_string()).collect();
+        format!("{}/{}", request_path, segments.join("/"))
+    };
+    if let Some(template) = &config.template {
+        let entries: Vec<ListingEntry> = packages.iter()
+            .map(|(rel_path, size)| ListingEntry { name: rel_path.clone(), href: href(rel_path), size: *size })
+            .collect();
+        return HttpResponse::Ok()
+            .content_type("text/html")
+            .body(template::render(template, "All Packages", &format!("/{}", decoded_path), &entries));
+    }
+
+    let mut html = format!("<!DOCTYPE html>\n<html>\n<head><title>All Packages</title>{}</head>\n<body>\n<h1>All Packages</h1>\n<ul>\n", config.stylesheet_link());
+    for (rel_path, size) in &packages {
+        let size = size.map(|size| format!(" ({} bytes)", size)).unwrap_or_default();
+        html.push_str(&format!("<li><a href=\"{}\">{}</a>{}</li>\n", href(rel_path), escape_html(rel_path), size));
+    }
+    html.push_str("</ul>\n");
+    if truncated {
+        html.push_str(&format!("<p>Listing truncated after {} packages.</p>\n", RECURSIVE_LISTING_LIMIT));
+    }
+    html.push_str("</body>\n</html>");
+    HttpResponse::Ok()
+        .content_type("text/html")
+        .body(html)
+}
+
+async fn dir_redirect(config: web::Data<ServerConfig>, req: HttpRequest) -> HttpResponse {
     let path_str = req.path();
     let clean_path = path_str.trim_start_matches('/');
     let decoded_path = percent_decode_str(clean_path).decode_utf8_lossy().to_string();
@@ -123,7 +312,7 @@ async fn dir_redirect(config: web::Data<ServerConfig>, req: HttpRequest) -> impl
     if let Some(dir_path) = config.directories.get(&base) {
         let full_path = dir_path.join(&subpath);
         if full_path.is_dir() {
-            let redirect_path = format!("/{}", clean_path); // Use original encoded path for redirect
+            let redirect_path = format!("{}/{}", config.path_prefix, clean_path); // Use original encoded path for redirect
             debug!("Redirecting to: {}/", redirect_path);
             return HttpResponse::PermanentRedirect()
                 .append_header((header::LOCATION, format!("{}/", redirect_path)))
@@ -135,54 +324,413 @@ async fn dir_redirect(config: web::Data<ServerConfig>, req: HttpRequest) -> impl
     HttpResponse::NotFound().body("404 - Not Found")
 }
 
-pub async fn run_server(config: ServerConfig, port: u16) -> Result<()> {
-    let addr = format!("0.0.0.0:{}", port);
-    log::info!("Listening on http://{}", addr);
-    display_directories(&config);
+/// Returns the `.json.gz` sibling of a `.json` request under the served directory `name`, if one exists.
+fn precompressed_path(name: &str, root: &Path, path: &str) -> Option<PathBuf> {
+    let decoded_path = percent_decode_str(path).decode_utf8_lossy();
+    let rel_path = decoded_path.strip_prefix('/')?.strip_prefix(name)?.strip_prefix('/')?;
+    let is_safe = Path::new(rel_path).components().all(|c| matches!(c, Component::Normal(_)));
+    if !is_safe || !rel_path.to_lowercase().ends_with(".json") {
+        return None;
+    }
+    let gz_path = root.join(format!("{}.gz", rel_path));
+    gz_path.is_file().then_some(gz_path)
+}
+
+/// Whether the request's `Accept-Encoding` allows gzip (listed, or via `*`, without `q=0`).
+fn accepts_gzip(head: &actix_web::dev::RequestHead) -> bool {
+    let Some(value) = head.headers().get(header::ACCEPT_ENCODING).and_then(|value| value.to_str().ok()) else { return false };
+    value.split(',').any(|coding| {
+        let mut params = coding.split(';').map(str::trim);
+        let name = params.next().unwrap_or_default();
+        let rejected = params.any(|param| param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0));
+        (name.eq_ignore_ascii_case("gzip") || name == "*") && !rejected
+    })
+}
+
+/// Serves a pre-compressed `.json.gz` sibling as-is for a `.json` request from a gzip-capable client.
+async fn precompressed_json(req: HttpRequest, name: String, root: PathBuf) -> actix_web::Result<HttpResponse> {
+    let Some(gz_path) = precompressed_path(&name, &root, req.path()) else {
+        return Ok(HttpResponse::NotFound().body("404 - Not Found"));
+    };
+    debug!("Serving {} pre-compressed from {}", req.path(), gz_path.display());
+    let mut response = NamedFile::open_async(&gz_path).await?
+        .set_content_encoding(header::ContentEncoding::Gzip)
+        .disable_content_disposition()
+        .use_last_modified(true)
+        .use_etag(true)
+        .into_response(&req);
+    response.headers_mut().insert(header::VARY, header::HeaderValue::from_static("accept-encoding"));
+    Ok(response)
+}
+
+/// Fallback for the `Files` service: streams a missing `.pkg` decompressed from its `.pkg.gz` sibling,
+/// or joined from its numbered parts (`.pkg.0`, `.pkg.1`, ...). Files that exist but cannot be opened are
+/// answered by `io_error_response`.
+async fn gzip_package_fallback(req: ServiceRequest, prefix: String, root: PathBuf) -> actix_web::Result<ServiceResponse> {
+    let (req, _) = req.into_parts();
+    let decoded_path = percent_decode_str(req.path()).decode_utf8_lossy().to_string();
+    let rel_path = decoded_path.strip_prefix(&prefix).unwrap_or_default();
+    let is_safe = Path::new(rel_path).components().all(|c| matches!(c, Component::Normal(_)));
+    let gz_path = root.join(format!("{}.gz", rel_path));
+    let split_path = root.join(format!("{}.0", rel_path));
+
+    // Files also hands over files it failed to open; report those by the reason rather than as missing
+    if is_safe {
+        if let Err(e) = File::open(root.join(rel_path)) {
+            if e.kind() != io::ErrorKind::NotFound {
+                log::warn!("Error opening {}: {}", decoded_path, e);
+                return Ok(ServiceResponse::new(req, io_error_response(&e)));
+            }
+        }
+    }
+
+    if !is_safe || !rel_path.to_lowercase().ends_with(".pkg") {
+        return Ok(ServiceResponse::new(req, HttpResponse::NotFound().body("404 - Not Found")));
+    }
+
+    let response = if gz_path.is_file() {
+        debug!("Serving {} decompressed from {}", decoded_path, gz_path.display());
+        HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .streaming(read_stream(GzDecoder::new(File::open(&gz_path)?)))
+    } else if split_path.is_file() {
+        let reader = match web::block(move || SplitReader::open(&split_path)).await? {
+            Ok(reader) => reader,
+            Err(e) => {
+                log::warn!("Error opening split parts for {}: {:#}", decoded_path, e);
+                let response = e.chain().find_map(|cause| cause.downcast_ref::<io::Error>())
+                    .map_or_else(|| HttpResponse::InternalServerError().body("500 - Internal Server Error"), io_error_response);
+                return Ok(ServiceResponse::new(req, response));
+            }
+        };
+        debug!("Serving {} joined from split parts ({} bytes)", decoded_path, reader.size());
+        HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .no_chunking(reader.size())
+            .streaming(read_stream(reader))
+    } else {
+        HttpResponse::NotFound().body("404 - Not Found")
+    };
+    Ok(ServiceResponse::new(req, response))
+}
 
-    let config_clone = config.clone();
-    let directories = config.directories.clone();
-    HttpServer::new(move || {
-        let mut app = App::new()
-            .wrap(Logger::default()) // Access logging middleware
-            .app_data(web::Data::new(config_clone.clone())) // Share config with handlers
-            .route("/", web::get().to(root_index)); // Root index handler
-
-        // Register specific directory routes
-        for name in directories.keys() {
-            app = app.service(web::resource(&format!("/{}/", name)).route(web::get().to(dir_listing)));
-            app = app.service(web::resource(&format!("/{}", name)).route(web::get().to(dir_redirect)));
-            // Register subfolder routes dynamically
-            if let Ok(entries) = fs::read_dir(&directories[name]) {
-                for entry in entries.filter_map(Result::ok) {
-                    if entry.path().is_dir() {
-                        let subpath = entry.file_name().to_string_lossy().to_string();
-                        let dir_with_slash = format!("/{}/{}/", name, subpath);
-                        let dir_without_slash = format!("/{}/{}", name, subpath);
-                        app = app.service(web::resource(dir_with_slash).route(web::get().to(dir_listing)));
-                        app = app.service(web::resource(dir_without_slash).route(web::get().to(dir_redirect)));
+/// Response for a filesystem error while answering a request: `403` when permission is denied, `404` when the
+/// file is gone, `500` otherwise. The body only states the status, as error messages may name paths.
+fn io_error_response(error: &io::Error) -> HttpResponse {
+    match error.kind() {
+        io::ErrorKind::PermissionDenied => HttpResponse::Forbidden().body("403 - Forbidden"),
+        io::ErrorKind::NotFound => HttpResponse::NotFound().body("404 - Not Found"),
+        _ => HttpResponse::InternalServerError().body("500 - Internal Server Error"),
+    }
+}
+
+/// Streams `reader` to the end in 64 KiB chunks, reading on the blocking pool.
+fn read_stream<R: Read + Send + 'static>(reader: R) -> impl futures_util::Stream<Item = io::Result<web::Bytes>> {
+    futures_util::stream::unfold(Some(reader), |state| async move {
+        let mut reader = state?;
+        let chunk = web::block(move || {
+            let mut buf = vec![0u8; 64 * 1024];
+            let read = reader.read(&mut buf)?;
+            buf.truncate(read);
+            Ok::<_, io::Error>((reader, buf))
+        }).await;
+        match chunk {
+            Ok(Ok((_, buf))) if buf.is_empty() => None,
+            Ok(Ok((reader, buf))) => Some((Ok(web::Bytes::from(buf)), Some(reader))),
+            Ok(Err(e)) => Some((Err(e), None)),
+            Err(e) => Some((Err(io::Error::other(e)), None)),
+        }
+    })
+}
+
+/// The application serving the directories of `config`, with the routes and middleware the other options enable.
+fn app(config: &ServerConfig, download_limiter: Option<DownloadLimiter>, assets: &Assets, native_listing: bool,
+       admin: Option<&AdminApi>, feed: Option<&Feed>)
+       -> App<impl ServiceFactory<ServiceRequest, Config = (), Response = ServiceResponse<impl MessageBody>,
+                                  Error = actix_web::Error, InitError = ()>> {
+    let path_prefix = config.path_prefix.clone();
+    let signed_dir = config.signed_dir.clone();
+    let mut app = App::new()
+        .wrap_fn(|req, srv| { // Answer filesystem errors (e.g. an unreadable file served by Files) without paths
+            let fut = srv.call(req);
+            async move {
+                let res = fut.await?;
+                let Some(error) = res.response().error().and_then(|error| error.as_error::<io::Error>()) else {
+                    return Ok(res.map_into_boxed_body());
+                };
+                log::warn!("Error serving {}: {}", res.request().path(), error);
+                let response = io_error_response(error);
+                let (req, _) = res.into_parts();
+                Ok(ServiceResponse::new(req, response))
+            }
+        })
+        .wrap_fn(move |req, srv| { // Cap concurrent package downloads per client IP
+            let mut guard = None;
+            if let (Some(limiter), Some(peer)) = (&download_limiter, req.peer_addr()) {
+                // Files serves the decoded path, so `.p%6Bg` is a package download too
+                if percent_decode_str(req.path()).decode_utf8_lossy().to_lowercase().ends_with(".pkg") {
+                    match limiter.try_acquire(peer.ip()) {
+                        Some(acquired) => guard = Some(acquired),
+                        None => {
+                            let res = req.into_response(HttpResponse::TooManyRequests().body("429 - Too Many Requests"));
+                            return Either::Left(ready(Ok(res)));
+                        }
+                    }
+                }
+            }
+            let fut = srv.call(req);
+            Either::Right(async move {
+                let res = fut.await?;
+                Ok(match guard {
+                    Some(guard) => res.map_body(|_, body| BoxBody::new(GuardedBody::new(body, guard))),
+                    None => res.map_into_boxed_body(),
+                })
+            })
+        })
+        .wrap_fn(|req, srv| { // Pin content types for known extensions
+            let mime = mime_for_path(&percent_decode_str(req.path()).decode_utf8_lossy());
+            let fut = srv.call(req);
+            async move {
+                let mut res = fut.await?;
+                if let (Some(mime), true) = (mime, res.status().is_success()) {
+                    res.headers_mut().insert(header::CONTENT_TYPE, header::HeaderValue::from_static(mime));
+                }
+                Ok(res)
+            }
+        })
+        .wrap_fn(move |req, srv| { // Refuse unsigned or expired requests for `--sign-secret` packages
+            if let Some((name, signer)) = &signed_dir {
+                let path = req.path();
+                // Decoded like the router and Files do, so escapes in the directory name do not skip the check
+                let decoded_path = percent_decode_str(path).decode_utf8_lossy();
+                let in_dir = decoded_path.strip_prefix('/').and_then(|path| path.strip_prefix(name.as_str()))
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'));
+                if in_dir {
+                    if let Err(reason) = signer.verify(path, req.query_string()) {
+                        debug!("Refused '{}': {}", path, reason);
+                        let res = req.into_response(HttpResponse::Forbidden().body("403 - Forbidden"));
+                        return Either::Left(ready(Ok(res)));
                     }
                 }
             }
+            Either::Right(srv.call(req))
+        })
+        .wrap_fn(move |mut req, srv| { // Accept requests whose `--path-prefix` the proxy did not strip
+            if let Some(uri) = strip_path_prefix(&path_prefix, &req.head().uri) {
+                req.match_info_mut().get_mut().update(&uri);
+                req.head_mut().uri = uri;
+            }
+            srv.call(req)
+        })
+        .wrap(Logger::default()) // Access logging middleware
+        .app_data(web::Data::new(config.clone())) // Share config with handlers
+        .app_data(web::Data::new(assets.clone()))
+        .route("/", web::get().to(root_index)) // Root index handler
+        .route("/favicon.ico", web::get().to(assets::favicon))
+        .route("/_assets/{name}", web::get().to(assets::asset));
+
+    if let Some(admin) = admin {
+        app = app
+            .app_data(web::Data::new(admin.clone()))
+            .service(web::resource("/admin/regenerate")
+                .wrap(from_fn(admin::limit_request))
+                .route(web::post().to(admin::regenerate)));
+    }
+    if let Some(feed) = feed {
+        app = app
+            .app_data(web::Data::new(feed.clone()))
+            .route("/feed.xml", web::get().to(feed::feed));
+    }
+
+    // Directory routes resolved at request time, so folders created after startup are listable;
+    // requests that are not directories fall through to Files
+    for (name, path) in config.directories.iter().filter(|_| !native_listing) {
+        let (config, name, root) = (config.clone(), name.clone(), path.clone());
+        app = app.service(
+            web::resource([format!("/{}", name), format!("/{}/{{tail:.*}}", name)])
+                .guard(guard::fn_guard(move |ctx| is_directory_request(&config, &name, &root, ctx.head().uri.path())))
+                .route(web::get().to(dir_handler))
+        );
+    }
+
+    // `.json` requests answered from a pre-compressed `.json.gz` sibling when the client accepts gzip
+    for (name, path) in &config.directories {
+        let (guard_name, guard_root) = (name.clone(), path.clone());
+        let (name, root) = (name.clone(), path.clone());
+        app = app.service(
+            web::resource(format!("/{}/{{tail:.*}}", name))
+                .guard(guard::fn_guard(move |ctx| {
+                    let head = ctx.head();
+                    matches!(head.method, actix_web::http::Method::GET | actix_web::http::Method::HEAD)
+                        && accepts_gzip(head)
+                        && precompressed_path(&guard_name, &guard_root, head.uri.path()).is_some()
+                }))
+                .route(web::route().to(move |req: HttpRequest| precompressed_json(req, name.clone(), root.clone())))
+        );
+    }
+
+    // File serving with actix-files after specific routes
+    for (name, path) in &config.directories {
+        let mut files = Files::new(&format!("/{}", name), path)
+            .prefer_utf8(true) // Ensure proper encoding handling
+            .use_last_modified(true) // Last-Modified header
+            .use_etag(true) // ETag support
+            .default_handler(gzip_service(format!("/{}/", name), path.clone()));
+        if native_listing {
+            files = files.show_files_listing().redirect_to_slash_directory();
+        }
+        app = app.service(files);
+    }
+
+    app
+}
+
+pub async fn run_server(config: ServerConfig, args: ServerArgs, admin: Option<AdminApi>, feed: Option<Feed>) -> Result<()> {
+    display_directories(&config);
+
+    let mut config_clone = config.clone();
+    if let Some(template_path) = &args.template {
+        config_clone.template = Some(fs::read_to_string(template_path)
+            .with_context(|| format!("Failed to read template {}", template_path.display()))?);
+    }
+    config_clone.path_prefix = args.path_prefix.clone().unwrap_or_default();
+    config_clone.hidden_dirs = args.hidden_dirs.clone();
+    let tls_config = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::load_server_config(cert, key)
+            .context(CommandError::InvalidConfig("Invalid --tls-cert/--tls-key".to_string()))?),
+        _ => None,
+    };
+    let download_limiter = args.max_concurrent_downloads.map(DownloadLimiter::new);
+    let assets = Assets::new(args.assets_dir.clone());
+    let native_listing = args.native_listing;
+    let app_factory = move || app(&config_clone, download_limiter.clone(), &assets, native_listing, admin.as_ref(), feed.as_ref());
+    let keep_alive = if args.keep_alive == 0 { KeepAlive::Disabled } else { KeepAlive::Timeout(Duration::from_secs(args.keep_alive)) };
+    let client_timeout = Duration::from_secs(args.client_timeout);
+    let server = HttpServer::new(app_factory.clone())
+        .keep_alive(keep_alive)
+        .client_request_timeout(client_timeout);
+    log::debug!("Keep-alive: {}s, client request timeout: {}s", args.keep_alive, args.client_timeout);
+
+    #[cfg(unix)]
+    if let Some(socket_path) = &args.unix_socket {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+        // Clear a stale socket left by an unclean shutdown, but never clobber a regular file
+        if fs::symlink_metadata(socket_path).is_ok_and(|meta| meta.file_type().is_socket()) {
+            fs::remove_file(socket_path)?;
+        }
+        let server = server.bind_uds(socket_path)
+            .with_context(|| CommandError::BindFailed(format!("unix:{}", socket_path.display())))?;
+        fs::set_permissions(socket_path, fs::Permissions::from_mode(0o660))?;
+        log::info!("Listening on unix:{}", socket_path.display());
+        if args.print_listen_info {
+            print_listen_info(&config, json!({"unix_socket": socket_path}))?;
         }
 
-        // File serving with actix-files after specific routes
-        for (name, path) in &config_clone.directories {
-            app = app.service(
-                Files::new(&format!("/{}", name), path)
-                    .prefer_utf8(true) // Ensure proper encoding handling
-                    .use_last_modified(true) // Last-Modified header
-                    .use_etag(true) // ETag support
-            );
+        let result = server.run().await;
+        if let Err(e) = fs::remove_file(socket_path) {
+            log::warn!("Failed to remove socket {}: {}", socket_path.display(), e);
         }
+        return result.map_err(|e| anyhow::anyhow!("Server error: {}", e));
+    }
 
-        app
-    })
-    .bind(&addr)?
-    .run()
-    .await
-    .map_err(|e| anyhow::anyhow!("Server error: {}", e))?;
+    // With --tls-port, plain HTTP stays on --port; otherwise a configured certificate moves --port to HTTPS
+    let (plain_port, tls_port) = match (&tls_config, args.tls_port) {
+        (Some(_), Some(tls_port)) => (Some(args.port), Some(tls_port)),
+        (Some(_), None) => (None, Some(args.port)),
+        (None, _) => (Some(args.port), None),
+    };
+
+    let mut server = server;
+    if let Some(port) = plain_port {
+        let addr = format!("0.0.0.0:{}", port);
+        server = server.bind(&addr).with_context(|| CommandError::BindFailed(addr.clone()))?;
+    }
+
+    let mut http1_tls_server = None;
+    if let (Some(port), Some(mut tls_config)) = (tls_port, tls_config) {
+        let addr = format!("0.0.0.0:{}", port);
+        if args.no_http2 {
+            // actix-web's TLS listeners always offer h2, so serve HTTP/1.1 through actix-http directly
+            tls_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+            let listener = TcpListener::bind(&addr).with_context(|| CommandError::BindFailed(addr.clone()))?;
+            // With port 0 the OS picks a free port, only known once bound
+            let bound = listener.local_addr()?;
+            log::info!("Listening on https://{} (HTTP/1.1 only)", bound);
+            if args.print_listen_info {
+                print_listen_info(&config, json!({"protocol": "https", "address": bound.ip(), "port": bound.port()}))?;
+            }
+            http1_tls_server = Some(actix_server::Server::build()
+                .listen("fpkgi-https", listener, move || {
+                    HttpService::build()
+                        .keep_alive(keep_alive)
+                        .client_request_timeout(client_timeout)
+                        .h1(map_config(app_factory(), move |_| AppConfig::default()))
+                        .rustls_0_23(tls_config.clone())
+                })?
+                .run());
+        } else {
+            server = server.bind_rustls_0_23(&addr, tls_config).with_context(|| CommandError::BindFailed(addr.clone()))?;
+        }
+    }
+
+    // Logged once bound, so `--port 0` shows the port the OS picked
+    for (bound, scheme) in server.addrs_with_scheme() {
+        log::info!("Listening on {}://{}", scheme, bound);
+        if args.print_listen_info {
+            print_listen_info(&config, json!({"protocol": scheme, "address": bound.ip(), "port": bound.port()}))?;
+        }
+    }
+
+    let result = match http1_tls_server {
+        Some(tls_server) if plain_port.is_some() => try_join(server.run(), tls_server).await.map(|_| ()),
+        Some(tls_server) => tls_server.await,
+        None => server.run().await,
+    };
+    result.map_err(|e| anyhow::anyhow!("Server error: {}", e))?;
+
+    Ok(())
+}
+
+/// Returns `uri` without the leading `prefix`, or `None` when there is no prefix or `uri` does not start with it.
+fn strip_path_prefix(prefix: &str, uri: &Uri) -> Option<Uri> {
+    if prefix.is_empty() {
+        return None;
+    }
+    let rest = uri.path().strip_prefix(prefix).filter(|rest| rest.is_empty() || rest.starts_with('/'))?;
+    let path = if rest.is_empty() { "/" } else { rest };
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().ok()?);
+    Uri::from_parts(parts).ok()
+}
+
+fn mime_for_path(path: &str) -> Option<&'static str> {
+    let (_, ext) = path.rsplit_once('.')?;
+    MIME_TYPES.iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(ext))
+        .map(|&(_, mime)| mime)
+}
+
+fn gzip_service(prefix: String, root: PathBuf)
+    -> impl actix_web::dev::ServiceFactory<ServiceRequest, Config = (), Response = ServiceResponse, Error = actix_web::Error, InitError = ()> {
+    fn_service(move |req: ServiceRequest| gzip_package_fallback(req, prefix.clone(), root.clone()))
+}
 
+/// Prints a single machine-readable line for wrapper scripts, merging `listener` into the common fields.
+fn print_listen_info(config: &ServerConfig, listener: JsonValue) -> Result<()> {
+    let mut directories: Vec<&String> = config.directories.keys().collect();
+    directories.sort();
+    let mut info = json!({"protocol": "http", "directories": directories});
+    if let (Some(info), JsonValue::Object(listener)) = (info.as_object_mut(), listener) {
+        info.extend(listener);
+    }
+    let mut stdout = io::stdout().lock();
+    writeln!(stdout, "{}", info)?;
+    stdout.flush()?;
     Ok(())
 }
 
@@ -192,3 +740,93 @@ fn display_directories(config: &ServerConfig) {
         log::info!("  /{name} -> {}", path.display());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test;
+
+    use super::*;
+
+    /// Serves a `packages` directory holding `game.pkg`, requiring links signed by `signer`.
+    async fn signed_service(dir: &Path, signer: UrlSigner)
+        -> impl Service<actix_http::Request, Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error> {
+        fs::write(dir.join("game.pkg"), b"package data").unwrap();
+        let config = ServerConfig::new(HashMap::from([("packages".to_string(), dir.to_path_buf())]))
+            .with_signed_dir("packages".to_string(), signer);
+        test::init_service(app(&config, None, &Assets::new(None), false, None, None)).await
+    }
+
+    /// Serves a `packages` directory holding `game.pkg`, with downloads limited by `limiter`.
+    async fn limited_service(dir: &Path, limiter: DownloadLimiter)
+        -> impl Service<actix_http::Request, Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error> {
+        fs::write(dir.join("game.pkg"), b"package data").unwrap();
+        let config = ServerConfig::new(HashMap::from([("packages".to_string(), dir.to_path_buf())]));
+        test::init_service(app(&config, Some(limiter), &Assets::new(None), false, None, None)).await
+    }
+
+    fn request(link: &str) -> actix_http::Request {
+        test::TestRequest::get().uri(link.trim_start_matches("http://localhost:8000")).to_request()
+    }
+
+    #[actix_web::test]
+    async fn serves_validly_signed_packages() {
+        let dir = tempfile::tempdir().unwrap();
+        let signer = UrlSigner::new("secret", 3600);
+        let link = signer.sign("http://localhost:8000/packages/game.pkg", "/packages/game.pkg");
+        let service = signed_service(dir.path(), signer).await;
+
+        let res = test::call_service(&service, request(&link)).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(test::read_body(res).await, "package data");
+    }
+
+    #[actix_web::test]
+    async fn refuses_expired_unsigned_and_tampered_links() {
+        let dir = tempfile::tempdir().unwrap();
+        let signer = UrlSigner::new("secret", 3600);
+        let expired = signer.sign_until("/packages/game.pkg", "/packages/game.pkg", 1);
+        let valid = signer.sign("/packages/game.pkg", "/packages/game.pkg");
+        let service = signed_service(dir.path(), signer).await;
+
+        for link in [expired.as_str(), "/packages/game.pkg", &valid.replace("game.pkg", "other.pkg"), "/packages/"] {
+            let res = test::call_service(&service, request(link)).await;
+            assert_eq!(res.status(), 403, "{}", link);
+        }
+    }
+
+    #[actix_web::test]
+    async fn refuses_unsigned_links_with_an_encoded_directory_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = signed_service(dir.path(), UrlSigner::new("secret", 3600)).await;
+
+        for link in ["/%70ackages/game.pkg", "/%70%61ckages/game.pkg", "/packages%2Fgame.pkg"] {
+            let res = test::call_service(&service, request(link)).await;
+            assert_eq!(res.status(), 403, "{}", link);
+        }
+    }
+
+    #[actix_web::test]
+    async fn limits_downloads_with_an_encoded_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        let limiter = DownloadLimiter::new(1);
+        let service = limited_service(dir.path(), limiter.clone()).await;
+        let peer: std::net::SocketAddr = "192.168.1.20:50000".parse().unwrap();
+        let _download = limiter.try_acquire(peer.ip()).unwrap();
+
+        for link in ["/packages/game.pkg", "/packages/game.p%6Bg", "/packages/game.P%4BG"] {
+            let req = test::TestRequest::get().uri(link).peer_addr(peer).to_request();
+            let res = test::call_service(&service, req).await;
+            assert_eq!(res.status(), 429, "{}", link);
+        }
+    }
+
+    #[actix_web::test]
+    async fn pins_the_content_type_of_encoded_extensions() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = limited_service(dir.path(), DownloadLimiter::new(1)).await;
+
+        let res = test::call_service(&service, request("/packages/game.p%6Bg")).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "application/octet-stream");
+    }
+}
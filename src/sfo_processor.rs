@@ -2,8 +2,9 @@ use std::collections::HashMap;
 use std::io::{Seek, SeekFrom, Cursor};
 
 use anyhow::Result;
-use log::{debug, error};
+use log::{debug, error, warn};
 
+use crate::ps4_package::PS4Package;
 use crate::utils::{read_u16_le, read_u32_le, extract_string};
 
 #[derive(Debug)]
@@ -13,11 +14,18 @@ impl SFOProcessor {
     const MAGIC_BYTES: &'static [u8] = b"\x00PSF";
     const HEADER_SIZE: usize = 20;
     const ENTRY_SIZE: usize = 16;
+    // Real param.sfo files are a few kilobytes; anything larger is corrupt or crafted
+    pub const MAX_SIZE: u64 = 1024 * 1024;
 
     pub fn new() -> Self {
         SFOProcessor
     }
 
+    /// Reads and parses the SFO entry `identifier` of `pkg`, refusing entries over `MAX_SIZE` before reading them.
+    pub fn read(&self, pkg: &mut PS4Package, identifier: &str) -> Result<HashMap<String, String>> {
+        self.process(pkg.get_file_limited(identifier, Self::MAX_SIZE)?)
+    }
+
     pub fn process(&self, buffer: Vec<u8>) -> Result<HashMap<String, String>> {
         debug!("SFO buffer size: {} bytes", buffer.len());
 
@@ -26,6 +34,11 @@ impl SFOProcessor {
             return Err(anyhow::anyhow!("Invalid SFO file: Magic bytes missing"));
         }
 
+        if buffer.len() as u64 > Self::MAX_SIZE {
+            error!("SFO buffer too large: {} bytes > {} bytes", buffer.len(), Self::MAX_SIZE);
+            return Err(anyhow::anyhow!("SFO buffer too large: {} bytes exceeds {} byte limit", buffer.len(), Self::MAX_SIZE));
+        }
+
         if buffer.len() < Self::HEADER_SIZE {
             error!("SFO buffer too small for header: {} bytes < {} bytes", buffer.len(), Self::HEADER_SIZE);
             return Err(anyhow::anyhow!("SFO buffer too small for header"));
@@ -43,13 +56,13 @@ impl SFOProcessor {
                version, key_table_start, data_table_start, entry_count);
 
         let entry_table_pos = Self::HEADER_SIZE;
-        if buffer.len() < entry_table_pos + entry_count * Self::ENTRY_SIZE {
-            error!("SFO buffer too small for {} entries: {} bytes < {} bytes",
-                   entry_count, buffer.len(), entry_table_pos + entry_count * Self::ENTRY_SIZE);
+        let entry_table_end = entry_count.checked_mul(Self::ENTRY_SIZE).and_then(|size| size.checked_add(entry_table_pos));
+        if entry_table_end.is_none_or(|end| buffer.len() < end) {
+            error!("SFO buffer too small for {} entries: {} bytes", entry_count, buffer.len());
             return Err(anyhow::anyhow!("SFO buffer too small for entries"));
         }
 
-        let mut entries = Vec::new();
+        let mut entries = Vec::with_capacity(entry_count);
         for i in 0..entry_count {
             let offset = entry_table_pos + i * Self::ENTRY_SIZE;
             debug!("Reading SFO entry {} at offset {}", i, offset);
@@ -57,28 +70,34 @@ impl SFOProcessor {
             let key_pos = read_u16_le(&mut cursor)? as usize;
             let data_type = read_u16_le(&mut cursor)?;
             let data_size = read_u32_le(&mut cursor)? as usize;
-            let _max_size = read_u32_le(&mut cursor)?;
+            let max_size = read_u32_le(&mut cursor)? as usize;
             let data_pos = read_u32_le(&mut cursor)? as usize;
-            entries.push((key_pos, data_type, data_size, data_pos));
+            entries.push((key_pos, data_type, data_size, max_size, data_pos));
         }
 
+        let entry_table_end = entry_table_end.unwrap_or_default();
+        let key_table_start = Self::locate_key_table(&buffer, &entries, entry_table_end, key_table_start, data_table_start);
+        let data_table_start = Self::locate_data_table(&buffer, &entries, entry_table_end, key_table_start, data_table_start);
+
         let mut output = HashMap::new();
-        for (i, (key_pos, data_type, data_size, data_pos)) in entries.into_iter().enumerate() {
-            if key_table_start + key_pos >= buffer.len() {
-                error!("Entry {} key offset out of bounds: {} >= {}", i, key_table_start + key_pos, buffer.len());
+        for (i, (key_pos, data_type, data_size, _, data_pos)) in entries.into_iter().enumerate() {
+            let key_offset = key_table_start.saturating_add(key_pos);
+            if key_offset >= buffer.len() {
+                error!("Entry {} key offset out of bounds: {} >= {}", i, key_offset, buffer.len());
                 continue;
             }
-            let key = extract_string(&buffer, key_table_start + key_pos);
+            let key = extract_string(&buffer, key_offset);
 
-            if data_table_start + data_pos + data_size > buffer.len() {
+            let data_offset = data_table_start.saturating_add(data_pos);
+            if data_offset.saturating_add(data_size) > buffer.len() {
                 error!("Entry {} data offset out of bounds: {} + {} > {}",
-                       i, data_table_start + data_pos, data_size, buffer.len());
+                       i, data_offset, data_size, buffer.len());
                 continue;
             }
-            let raw_value = &buffer[data_table_start + data_pos..data_table_start + data_pos + data_size];
+            let raw_value = &buffer[data_offset..data_offset + data_size];
 
             debug!("Entry {} - Key: {}, Type: {:04x}, Size: {}, Data Offset: {}",
-                   i, key, data_type, data_size, data_table_start + data_pos);
+                   i, key, data_type, data_size, data_offset);
 
             let value = match data_type {
                 0x0204 => String::from_utf8_lossy(raw_value).trim_end_matches('\x00').to_string(),
@@ -99,4 +118,141 @@ impl SFOProcessor {
         }
         Ok(output)
     }
+
+    /// Start of the key table: the header's value when every key it yields looks like an SFO key, else the
+    /// data table start (headers with the two swapped), else right after the entry table where it normally is.
+    fn locate_key_table(buffer: &[u8], entries: &[SfoEntry], entry_table_end: usize,
+                        key_table_start: usize, data_table_start: usize) -> usize {
+        let plausible = |start: usize| start >= entry_table_end && entries.iter().all(|&(key_pos, ..)| {
+            let offset = start.saturating_add(key_pos);
+            offset < buffer.len() && {
+                let key = extract_string(buffer, offset);
+                !key.is_empty() && key.bytes().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'_')
+            }
+        });
+        let Some(start) = [key_table_start, data_table_start, entry_table_end].into_iter().find(|&start| plausible(start)) else {
+            return key_table_start;
+        };
+        if start != key_table_start {
+            warn!("SFO key table start {} is invalid; recovered it as {}", key_table_start, start);
+        }
+        start
+    }
+
+    /// Start of the data table: the header's value when every entry's data lies within the buffer and clear of
+    /// the key table, else derived from the entry offsets: the data table normally ends the file, or else
+    /// directly follows the last key.
+    fn locate_data_table(buffer: &[u8], entries: &[SfoEntry], entry_table_end: usize,
+                         key_table_start: usize, data_table_start: usize) -> usize {
+        let key_table_end = entries.iter()
+            .map(|&(key_pos, ..)| {
+                let offset = key_table_start.saturating_add(key_pos);
+                if offset < buffer.len() { offset + extract_string(buffer, offset).len() + 1 } else { offset }
+            })
+            .max()
+            .unwrap_or(key_table_start);
+        // Extent of the data actually read, and of the space allocated to it (which a real data table fills up)
+        let extent = |size: fn(&SfoEntry) -> usize| entries.iter()
+            .map(|entry| entry.4.saturating_add(size(entry)))
+            .max()
+            .unwrap_or_default();
+        let (data_extent, allocated_extent) = (extent(|entry| entry.2), extent(|entry| entry.2.max(entry.3)));
+        let plausible = |start: usize| {
+            let end = start.saturating_add(data_extent);
+            start >= entry_table_end && end <= buffer.len() && (start >= key_table_end || end <= key_table_start)
+        };
+        let candidates = [
+            Some(data_table_start),
+            buffer.len().checked_sub(allocated_extent),
+            Some(key_table_end.next_multiple_of(4)),
+        ];
+        let Some(start) = candidates.into_iter().flatten().find(|&start| plausible(start)) else {
+            return data_table_start;
+        };
+        if start != data_table_start {
+            warn!("SFO data table start {} is invalid; recovered it as {}", data_table_start, start);
+        }
+        start
+    }
+}
+
+/// Key offset, data type, data size, allocated size and data offset of one SFO entry.
+type SfoEntry = (usize, u16, usize, usize, usize);
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::ps4_package::PackageError;
+    use crate::test_fixtures::{game_pkg, sfo};
+
+    #[test]
+    fn refuses_an_oversized_sfo_entry_before_reading_it() {
+        let mut data = game_pkg("UP0000-CUSA00001_00-GAME000000000000", &[("TITLE_ID", "CUSA00001")]);
+        // param.sfo is the second entry; declare it 2 MiB, within the padded file and the generic entry limit
+        let declared = 2 * SFOProcessor::MAX_SIZE;
+        data[0x200 + 32 + 20..0x200 + 32 + 24].copy_from_slice(&(declared as u32).to_be_bytes());
+        data.resize(3 * SFOProcessor::MAX_SIZE as usize, 0);
+        let mut pkg = PS4Package::from_reader(PathBuf::from("game.pkg"), Cursor::new(data)).unwrap();
+
+        let error = SFOProcessor::new().read(&mut pkg, "param.sfo").unwrap_err();
+        assert!(matches!(error.downcast_ref::<PackageError>(),
+                         Some(&PackageError::TooLarge { size, limit: SFOProcessor::MAX_SIZE, .. }) if size == declared));
+        assert_eq!(pkg.get_file("param.sfo").unwrap().len() as u64, declared);
+    }
+
+    #[test]
+    fn rejects_absurd_declared_entry_counts() {
+        let mut data = sfo(&[("TITLE_ID", "CUSA00001")]);
+        data[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(SFOProcessor::new().process(data).is_err());
+    }
+
+    #[test]
+    fn skips_entries_with_absurd_declared_data_sizes() {
+        let mut data = sfo(&[("TITLE_ID", "CUSA00001"), ("TITLE", "Game")]);
+        // Data size of the first entry
+        data[24..28].copy_from_slice(&u32::MAX.to_le_bytes());
+        let values = SFOProcessor::new().process(data).unwrap();
+        assert_eq!(values.get("TITLE").map(String::as_str), Some("Game"));
+        assert!(!values.contains_key("TITLE_ID"));
+    }
+
+    const VALUES: &[(&str, &str)] = &[("CATEGORY", "gd"), ("TITLE", "Some Game"), ("TITLE_ID", "CUSA00001")];
+
+    /// `sfo` of `VALUES` with the key and data table starts of its header replaced by `patch`.
+    fn with_table_starts(patch: impl Fn(u32, u32) -> (u32, u32)) -> HashMap<String, String> {
+        let mut data = sfo(VALUES);
+        let field = |data: &[u8], at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+        let (key_table_start, data_table_start) = patch(field(&data, 8), field(&data, 12));
+        data[8..12].copy_from_slice(&key_table_start.to_le_bytes());
+        data[12..16].copy_from_slice(&data_table_start.to_le_bytes());
+        SFOProcessor::new().process(data).unwrap()
+    }
+
+    fn expected() -> HashMap<String, String> {
+        VALUES.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn recovers_a_zero_data_table_start() {
+        assert_eq!(with_table_starts(|key_table_start, _| (key_table_start, 0)), expected());
+    }
+
+    #[test]
+    fn recovers_swapped_table_starts() {
+        assert_eq!(with_table_starts(|key_table_start, data_table_start| (data_table_start, key_table_start)), expected());
+    }
+
+    #[test]
+    fn recovers_zero_table_starts() {
+        assert_eq!(with_table_starts(|_, _| (0, 0)), expected());
+    }
+
+    #[test]
+    fn keeps_valid_table_starts() {
+        assert_eq!(with_table_starts(|key_table_start, data_table_start| (key_table_start, data_table_start)), expected());
+    }
 }
@@ -0,0 +1,156 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use percent_encoding::percent_decode_str;
+use ring::hmac;
+
+// Query parameters carrying the expiry (Unix seconds) and the hex HMAC of a signed link
+const EXPIRES_PARAM: &str = "expires";
+const SIGNATURE_PARAM: &str = "sig";
+
+/// Signs package links with an expiring HMAC-SHA256 and checks them on request (`--sign-secret`).
+///
+/// The signature covers the decoded path below the server root and the expiry, so it stays valid behind a
+/// reverse proxy and whichever way the client encodes the path.
+#[derive(Clone, Debug)]
+pub struct UrlSigner {
+    key: hmac::Key,
+    ttl: u64,
+}
+
+impl UrlSigner {
+    pub fn new(secret: &str, ttl: u64) -> Self {
+        UrlSigner { key: hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()), ttl }
+    }
+
+    /// Appends `expires` and `sig` parameters to `link`, whose percent-encoded path below the server root is `path`.
+    ///
+    /// The expiry is rounded up to a whole number of TTLs, between one and two TTLs away, so regenerating
+    /// within the same period produces the same links.
+    pub fn sign(&self, link: &str, path: &str) -> String {
+        self.sign_until(link, path, (now() / self.ttl + 2) * self.ttl)
+    }
+
+    /// Signs `link` like `sign`, valid until `expires` (Unix seconds).
+    pub(crate) fn sign_until(&self, link: &str, path: &str, expires: u64) -> String {
+        let signature = hex::encode(hmac::sign(&self.key, message(path, expires).as_bytes()));
+        let (link, fragment) = link.split_once('#').map_or((link, None), |(link, fragment)| (link, Some(fragment)));
+        let separator = if link.contains('?') { '&' } else { '?' };
+        let mut signed = format!("{}{}{}={}&{}={}", link, separator, EXPIRES_PARAM, expires, SIGNATURE_PARAM, signature);
+        if let Some(fragment) = fragment {
+            signed.push('#');
+            signed.push_str(fragment);
+        }
+        signed
+    }
+
+    /// Checks the signature in `query` for a request of `path` (below the server root), describing why it is refused.
+    pub fn verify(&self, path: &str, query: &str) -> Result<(), &'static str> {
+        let param = |name: &str| query.split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value);
+        let (Some(expires), Some(signature)) = (param(EXPIRES_PARAM), param(SIGNATURE_PARAM)) else {
+            return Err("missing signature");
+        };
+        let expires: u64 = expires.parse().map_err(|_| "malformed expiry")?;
+        let signature = hex::decode(signature).map_err(|_| "malformed signature")?;
+        hmac::verify(&self.key, message(path, expires).as_bytes(), &signature).map_err(|_| "invalid signature")?;
+        if now() > expires {
+            return Err("expired link");
+        }
+        Ok(())
+    }
+}
+
+/// `link` without the `expires` and `sig` parameters added by `UrlSigner::sign`.
+pub fn strip_signature(link: &str) -> String {
+    let (link, fragment) = link.split_once('#').map_or((link, None), |(link, fragment)| (link, Some(fragment)));
+    let (base, query) = link.split_once('?').unwrap_or((link, ""));
+    let kept: Vec<&str> = query.split('&')
+        .filter(|pair| !pair.is_empty() && !matches!(pair.split_once('='), Some((EXPIRES_PARAM | SIGNATURE_PARAM, _))))
+        .collect();
+    let mut stripped = base.to_string();
+    if !kept.is_empty() {
+        stripped.push('?');
+        stripped.push_str(&kept.join("&"));
+    }
+    if let Some(fragment) = fragment {
+        stripped.push('#');
+        stripped.push_str(fragment);
+    }
+    stripped
+}
+
+fn message(path: &str, expires: u64) -> String {
+    let path = percent_decode_str(path.trim_start_matches('/')).decode_utf8_lossy();
+    format!("{}\n{}", path, expires)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINK: &str = "http://localhost:8000/packages/Some%20Game.pkg";
+    const PATH: &str = "/packages/Some%20Game.pkg";
+
+    fn query(link: &str) -> &str {
+        link.split_once('?').map_or("", |(_, query)| query)
+    }
+
+    #[test]
+    fn signed_links_verify() {
+        let signer = UrlSigner::new("secret", 3600);
+        let signed = signer.sign(LINK, PATH);
+        assert!(signed.starts_with(&format!("{}?expires=", LINK)));
+        assert_eq!(signer.verify(PATH, query(&signed)), Ok(()));
+        assert_eq!(strip_signature(&signed), LINK);
+    }
+
+    #[test]
+    fn signatures_cover_the_decoded_path() {
+        let signer = UrlSigner::new("secret", 3600);
+        let signed = signer.sign(LINK, PATH);
+        assert_eq!(signer.verify("/packages/Some Game.pkg", query(&signed)), Ok(()));
+        assert_eq!(signer.verify("packages/Some%20G%61me.pkg", query(&signed)), Ok(()));
+        assert_eq!(signer.verify("/packages/Other%20Game.pkg", query(&signed)), Err("invalid signature"));
+    }
+
+    #[test]
+    fn tampered_or_foreign_signatures_are_refused() {
+        let signer = UrlSigner::new("secret", 3600);
+        let signed = signer.sign(LINK, PATH);
+        let (unsigned, signature) = signed.rsplit_once("sig=").unwrap();
+        let flipped = if signature.starts_with('0') { "1" } else { "0" };
+        let tampered = format!("{}sig={}{}", unsigned, flipped, &signature[1..]);
+        assert_eq!(signer.verify(PATH, query(&tampered)), Err("invalid signature"));
+        assert_eq!(UrlSigner::new("other secret", 3600).verify(PATH, query(&signed)), Err("invalid signature"));
+
+        // Moving the expiry invalidates the signature too
+        let expires = now() + 10 * 3600;
+        let extended = signed.replacen("expires=", &format!("expires={}&old=", expires), 1);
+        assert_eq!(signer.verify(PATH, query(&extended)), Err("invalid signature"));
+        assert_eq!(signer.verify(PATH, ""), Err("missing signature"));
+        assert_eq!(signer.verify(PATH, "expires=soon&sig=00"), Err("malformed expiry"));
+    }
+
+    #[test]
+    fn expired_links_are_refused() {
+        let signer = UrlSigner::new("secret", 3600);
+        let expired = signer.sign_until(LINK, PATH, now() - 1);
+        assert_eq!(signer.verify(PATH, query(&expired)), Err("expired link"));
+    }
+
+    #[test]
+    fn signing_keeps_existing_queries_and_fragments() {
+        let signer = UrlSigner::new("secret", 3600);
+        let link = format!("{}?mirror=1#UP0000-CUSA00001_00-DLC0000000000000", LINK);
+        let signed = signer.sign(&link, PATH);
+        assert!(signed.starts_with(&format!("{}?mirror=1&expires=", LINK)));
+        assert!(signed.ends_with("#UP0000-CUSA00001_00-DLC0000000000000"));
+        assert_eq!(strip_signature(&signed), link);
+    }
+}
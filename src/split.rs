@@ -0,0 +1,77 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+/// Whether `path` is the first part of a package split into numbered parts (`game.pkg.0`, `game.pkg.1`, ...).
+pub fn is_split_package(path: &Path) -> bool {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_lowercase().ends_with(".pkg.0"))
+        .unwrap_or(false)
+}
+
+/// Returns the parts of the split package starting at `first_part`, in order, up to the first missing number.
+pub fn split_parts(first_part: &Path) -> Vec<PathBuf> {
+    let Some(base) = first_part.to_str().and_then(|path| path.strip_suffix(".0")) else {
+        return vec![first_part.to_path_buf()];
+    };
+    (0..)
+        .map(|index| PathBuf::from(format!("{}.{}", base, index)))
+        .take_while(|part| part.is_file())
+        .collect()
+}
+
+/// Reads the parts of a split package as one contiguous file.
+pub struct SplitReader {
+    /// Each part with its offset in the logical file and its length
+    parts: Vec<(File, u64, u64)>,
+    size: u64,
+    pos: u64,
+}
+
+impl SplitReader {
+    pub fn open(first_part: &Path) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut size = 0;
+        for path in split_parts(first_part) {
+            let file = File::open(&path).with_context(|| format!("Failed to open package part '{}'", path.display()))?;
+            let len = fs::metadata(&path)?.len();
+            parts.push((file, size, len));
+            size += len;
+        }
+        Ok(SplitReader { parts, size, pos: 0 })
+    }
+
+    /// Combined size of all parts.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl Read for SplitReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let pos = self.pos;
+        let Some((file, start, len)) = self.parts.iter_mut().find(|(_, start, len)| pos < *start + *len) else {
+            return Ok(0);
+        };
+        let offset = pos - *start;
+        let wanted = buf.len().min((*len - offset) as usize);
+        file.seek(SeekFrom::Start(offset))?;
+        let read = file.read(&mut buf[..wanted])?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for SplitReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = target.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Seek before start of package"))?;
+        Ok(self.pos)
+    }
+}
@@ -0,0 +1,40 @@
+/// One row of a rendered directory or index page.
+pub struct ListingEntry {
+    pub name: String,
+    pub href: String,
+    /// File size in bytes; `None` for directories
+    pub size: Option<u64>,
+}
+
+/// Renders a `--template` page.
+///
+/// Supported placeholders: `{{title}}` and `{{path}}` anywhere, and a `{{#entries}}...{{/entries}}`
+/// block repeated per entry with `{{name}}`, `{{href}}` and `{{size}}` (empty for directories).
+/// Values are HTML-escaped.
+pub fn render(template: &str, title: &str, path: &str, entries: &[ListingEntry]) -> String {
+    const OPEN: &str = "{{#entries}}";
+    const CLOSE: &str = "{{/entries}}";
+
+    let (head, block, tail) = match template.split_once(OPEN).and_then(|(head, rest)| {
+        rest.split_once(CLOSE).map(|(block, tail)| (head, block, tail))
+    }) {
+        Some(parts) => parts,
+        None => (template, "", ""),
+    };
+
+    let mut rows = String::new();
+    for entry in entries {
+        let size = entry.size.map(|size| size.to_string()).unwrap_or_default();
+        rows.push_str(&block
+            .replace("{{name}}", &escape_html(&entry.name))
+            .replace("{{href}}", &escape_html(&entry.href))
+            .replace("{{size}}", &size));
+    }
+
+    let fill = |text: &str| text.replace("{{title}}", &escape_html(title)).replace("{{path}}", &escape_html(path));
+    format!("{}{}{}", fill(head), rows, fill(tail))
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
@@ -0,0 +1,127 @@
+//! Small in-memory packages and argument sets shared by the unit tests.
+
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+
+use crate::args::GenerateArgs;
+
+/// Entry id of param.sfo in the packages built by `pkg`.
+pub const SFO_ID: u32 = 0x1000;
+/// Entry id of icon0.png in the packages built by `pkg`.
+pub const ICON_ID: u32 = 0x1200;
+/// Content type of a game package, as stored in the header.
+pub const GAME: u32 = 0x1A;
+
+const TABLE_POS: usize = 0x200;
+const ENTRY_SIZE: usize = 32;
+
+/// A param.sfo holding `values` as UTF-8 strings, laid out like real ones (entries, keys, then data).
+pub fn sfo(values: &[(&str, &str)]) -> Vec<u8> {
+    let mut keys = Vec::new();
+    let mut data = Vec::new();
+    let mut entries = Vec::new();
+    for (key, value) in values {
+        let value = [value.as_bytes(), b"\0"].concat();
+        entries.extend((keys.len() as u16).to_le_bytes());
+        entries.extend(0x0204u16.to_le_bytes());
+        entries.extend((value.len() as u32).to_le_bytes());
+        entries.extend((value.len() as u32).to_le_bytes());
+        entries.extend((data.len() as u32).to_le_bytes());
+        keys.extend(key.as_bytes());
+        keys.push(0);
+        data.extend(value);
+    }
+    keys.resize(keys.len().next_multiple_of(4), 0);
+    let key_table_start = 20 + entries.len();
+    let data_table_start = key_table_start + keys.len();
+
+    let mut buffer = b"\0PSF".to_vec();
+    for field in [0x101, key_table_start, data_table_start, values.len()] {
+        buffer.extend((field as u32).to_le_bytes());
+    }
+    [buffer, entries, keys, data].concat()
+}
+
+/// A PS4 package of `content_type` whose entries hold `files`, given as (entry id, name, data), after the
+/// name table. Only the fields the parser reads are filled in.
+pub fn pkg(content_id: &str, content_type: u32, files: &[(u32, &str, &[u8])]) -> Vec<u8> {
+    let mut names = vec![0u8];
+    let mut blobs = Vec::new();
+    for &(entry_id, name, data) in files {
+        blobs.push((entry_id, names.len(), data.to_vec()));
+        names.extend(name.as_bytes());
+        names.push(0);
+    }
+    blobs.insert(0, (0x200, 0, names.clone()));
+
+    let mut buffer = vec![0u8; TABLE_POS + blobs.len() * ENTRY_SIZE];
+    buffer[..4].copy_from_slice(&0x7F434E54u32.to_be_bytes());
+    buffer[0x0C..0x10].copy_from_slice(&(files.len() as u32).to_be_bytes());
+    buffer[0x10..0x14].copy_from_slice(&(blobs.len() as u32).to_be_bytes());
+    buffer[0x14..0x16].copy_from_slice(&(blobs.len() as u16).to_be_bytes());
+    buffer[0x18..0x1C].copy_from_slice(&(TABLE_POS as u32).to_be_bytes());
+    buffer[0x1C..0x20].copy_from_slice(&(names.len() as u32).to_be_bytes());
+    buffer[0x40..0x40 + content_id.len()].copy_from_slice(content_id.as_bytes());
+    buffer[0x70..0x74].copy_from_slice(&0xFu32.to_be_bytes());
+    buffer[0x74..0x78].copy_from_slice(&content_type.to_be_bytes());
+
+    for (i, (entry_id, name_pos, data)) in blobs.into_iter().enumerate() {
+        buffer.resize(buffer.len().next_multiple_of(16), 0);
+        let (entry, offset) = (TABLE_POS + i * ENTRY_SIZE, buffer.len() as u32);
+        buffer[entry..entry + 4].copy_from_slice(&entry_id.to_be_bytes());
+        buffer[entry + 4..entry + 8].copy_from_slice(&(name_pos as u32).to_be_bytes());
+        buffer[entry + 16..entry + 20].copy_from_slice(&offset.to_be_bytes());
+        buffer[entry + 20..entry + 24].copy_from_slice(&(data.len() as u32).to_be_bytes());
+        buffer.extend(data);
+    }
+    buffer
+}
+
+/// A game package with a param.sfo of `values` and a small icon0.png.
+pub fn game_pkg(content_id: &str, values: &[(&str, &str)]) -> Vec<u8> {
+    pkg(content_id, GAME, &[(SFO_ID, "param.sfo", &sfo(values)), (ICON_ID, "icon0.png", ICON)])
+}
+
+/// A game package of `title_id` (category `gd`), with the content id `content_id` gives.
+pub fn game(title_id: &str) -> Vec<u8> {
+    game_pkg(&content_id(title_id), &[("CATEGORY", "gd"), ("TITLE_ID", title_id)])
+}
+
+/// Content id of the `game` package of `title_id`.
+pub fn content_id(title_id: &str) -> String {
+    format!("UP0000-{}_00-GAME000000000000", title_id)
+}
+
+/// Contents of the icon0.png entry of `game_pkg` packages (a PNG signature; never decoded).
+pub const ICON: &[u8] = b"\x89PNG\r\n\x1a\nicon";
+
+/// `generate` arguments indexing `packages` (served under `/packages`) for `http://localhost:8000`, writing to
+/// `out`, followed by `extra` options.
+pub fn generate_args(packages: &Path, out: &Path, extra: &[&str]) -> GenerateArgs {
+    let packages = format!("{}:packages", packages.display());
+    let out = format!("{}:out", out.display());
+    let args = ["fpkgi", "--packages", &packages, "--url", "http://localhost:8000", "--out", &out];
+    GenerateArgs::parse_from(args.into_iter().chain(extra.iter().copied()))
+}
+
+/// Writes `packages` (file name and contents) to a new `library` directory in `dir` and returns its path.
+pub fn write_library<N: AsRef<Path>>(dir: &Path, packages: &[(N, Vec<u8>)]) -> PathBuf {
+    let library = dir.join("library");
+    fs::create_dir(&library).unwrap();
+    for (name, data) in packages {
+        fs::write(library.join(name), data).unwrap();
+    }
+    library
+}
+
+/// Flips the bits of the byte at `offset` in the file at `path`, keeping its size and modification time, like
+/// silent corruption on disk.
+pub fn flip_byte(path: &Path, offset: usize) {
+    let modified = fs::metadata(path).unwrap().modified().unwrap();
+    let mut data = fs::read(path).unwrap();
+    data[offset] ^= 0xFF;
+    fs::write(path, data).unwrap();
+    File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+}
@@ -0,0 +1,33 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use rustls::ServerConfig;
+
+/// Builds the rustls configuration for the HTTPS listener from PEM certificate chain and key files.
+///
+/// ALPN is left empty: actix-web offers `h2` and `http/1.1` itself, and the HTTP/1.1-only
+/// listener used with `--no-http2` sets its own.
+pub fn load_server_config(cert_path: &Path, key_path: &Path) -> Result<ServerConfig> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(open(cert_path)?))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to parse certificates in {}", cert_path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in {}", cert_path.display());
+    }
+    let key = rustls_pemfile::private_key(&mut BufReader::new(open(key_path)?))
+        .with_context(|| format!("Failed to parse private key in {}", key_path.display()))?
+        .with_context(|| format!("No private key found in {}", key_path.display()))?;
+
+    ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid TLS certificate or key")
+}
+
+fn open(path: &Path) -> Result<File> {
+    File::open(path).with_context(|| format!("Failed to open {}", path.display()))
+}
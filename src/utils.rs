@@ -1,6 +1,11 @@
+use std::collections::BTreeMap;
+use std::fs;
 use std::io::Read;
+use std::path::Path;
+use std::sync::{mpsc, Condvar, Mutex};
+use std::thread;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
 pub fn extract_string(buffer: &[u8], start: usize) -> String {
     let mut text = Vec::new();
@@ -41,3 +46,119 @@ pub fn read_u64_be<R: Read>(reader: &mut R) -> Result<u64> {
     reader.read_exact(&mut buf)?;
     Ok(u64::from_be_bytes(buf))
 }
+
+/// Writes `data` to a temporary sibling file and renames it over `path`, so readers never see partial output.
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    let result = fs::write(&tmp_path, data).and_then(|_| fs::rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    Ok(result?)
+}
+
+/// Matches `text` against a case-insensitive wildcard `pattern` (`*` any run, `?` any single character).
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_uppercase().chars().collect();
+    let text: Vec<char> = text.to_uppercase().chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            p = star_p + 1;
+            t = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Expresses URL `target` relative to the document at URL `base` (e.g. `../icons/a.png`).
+///
+/// Returns `target` unchanged when the two URLs don't share a scheme and host.
+pub fn relative_url(base: &str, target: &str) -> String {
+    let split = |url: &str| -> Option<(String, Vec<String>)> {
+        let (scheme, rest) = url.split_once("://")?;
+        let (host, path) = rest.split_once('/').unwrap_or((rest, ""));
+        Some((format!("{}://{}", scheme, host), path.split('/').map(str::to_string).collect()))
+    };
+    let (Some((base_origin, mut base_path)), Some((target_origin, target_path))) = (split(base), split(target)) else {
+        return target.to_string();
+    };
+    if base_origin != target_origin {
+        return target.to_string();
+    }
+    // Only the base's directory matters
+    base_path.pop();
+    let common = base_path.iter().zip(&target_path).take_while(|(a, b)| a == b).count();
+    let mut segments: Vec<&str> = vec![".."; base_path.len() - common];
+    segments.extend(target_path[common..].iter().map(String::as_str));
+    segments.join("/")
+}
+
+/// Runs `prepare` over `items` on up to `threads` threads and hands the results to `consume` in item order.
+///
+/// At most `threads` items are prepared or waiting to be consumed at any time, bounding open files and memory.
+/// Stops claiming new items as soon as `consume` fails, returning its error.
+pub fn for_each_prepared<I: Sync, T: Send>(items: &[I], threads: usize, prepare: impl Fn(&I) -> T + Sync,
+                                           mut consume: impl FnMut(&I, T) -> Result<()>) -> Result<()> {
+    if threads <= 1 {
+        return items.iter().try_for_each(|item| consume(item, prepare(item)));
+    }
+
+    // (items claimed by workers, items consumed, stopped)
+    let window = Mutex::new((0, 0, false));
+    let advanced = Condvar::new();
+    let (sender, receiver) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..threads.min(items.len()) {
+            let (sender, window, advanced, prepare) = (sender.clone(), &window, &advanced, &prepare);
+            scope.spawn(move || loop {
+                let index = {
+                    let mut guard = advanced.wait_while(window.lock().unwrap(), |(claimed, consumed, stopped)| {
+                        !*stopped && *claimed < items.len() && *claimed >= *consumed + threads
+                    }).unwrap();
+                    let (claimed, _, stopped) = &mut *guard;
+                    if *stopped || *claimed >= items.len() {
+                        break;
+                    }
+                    *claimed += 1;
+                    *claimed - 1
+                };
+                if sender.send((index, prepare(&items[index]))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        let mut pending = BTreeMap::new();
+        let result = items.iter().enumerate().try_for_each(|(index, item)| {
+            let prepared = loop {
+                if let Some(prepared) = pending.remove(&index) {
+                    break prepared;
+                }
+                let (done, prepared) = receiver.recv().map_err(|_| anyhow!("Worker threads stopped early"))?;
+                pending.insert(done, prepared);
+            };
+            consume(item, prepared)?;
+            window.lock().unwrap().1 += 1;
+            advanced.notify_all();
+            Ok(())
+        });
+        window.lock().unwrap().2 = true;
+        advanced.notify_all();
+        drop(receiver);
+        result
+    })
+}
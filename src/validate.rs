@@ -0,0 +1,117 @@
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use log::debug;
+use serde_json::{json, Value as JsonValue};
+use url::Url;
+
+use crate::diff::load_categories;
+use crate::entry::PackageSize;
+
+// How long one HEAD request may take before the URL is reported as unreachable
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A URL from the generated JSON that did not resolve as expected.
+struct Failure {
+    category: String,
+    field: &'static str,
+    url: String,
+    problem: String,
+}
+
+/// Turns a link from the generated JSON into the URL to request.
+///
+/// With `base`, absolute URLs get its scheme, host and port so a server can be checked before it is reachable
+/// under its public address. Relative links (`--local-paths`, `--relative-icon-urls`) are resolved against
+/// `relative_to`, or `base` when there is none.
+fn resolve(link: &str, base: Option<&Url>, relative_to: Option<&Url>) -> Result<Url, String> {
+    match Url::parse(link) {
+        Ok(mut url) => {
+            if !matches!(url.scheme(), "http" | "https") {
+                return Err("not an HTTP URL".to_string());
+            }
+            if let Some(base) = base {
+                // Both are HTTP URLs, so neither setter can fail
+                let _ = url.set_scheme(base.scheme());
+                let _ = url.set_host(base.host_str());
+                let _ = url.set_port(base.port());
+            }
+            Ok(url)
+        }
+        Err(url::ParseError::RelativeUrlWithoutBase) => {
+            let Some(base) = relative_to.or(base) else {
+                return Err("relative URL, pass --base-url to check it".to_string());
+            };
+            base.join(link).map_err(|e| e.to_string())
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Sends a HEAD request for `url`, checking the reported length against `size` when it is known.
+fn check_url(agent: &ureq::Agent, url: &Url, size: Option<u64>) -> Result<(), String> {
+    let response = match agent.head(url.as_str()).call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(status, _)) => return Err(format!("HTTP {}", status)),
+        Err(e) => return Err(e.to_string()),
+    };
+    let length = response.header("Content-Length").and_then(|length| length.parse::<u64>().ok());
+    match (size, length) {
+        (Some(size), Some(length)) if size != length =>
+            Err(format!("size mismatch: server reports {} bytes, entry has {}", length, size)),
+        (Some(_), None) => {
+            debug!("No Content-Length for {}, size not checked", url);
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Sends a HEAD request for the package `url` and `cover_url` of every entry in the category JSON files in
+/// `json_dir`, and reports those that do not resolve or whose size differs from the entry's `size`.
+pub fn run_validate(json_dir: &Path, base_url: Option<&str>, as_json: bool) -> Result<()> {
+    let base = base_url.map(Url::parse).transpose()?;
+    let agent = ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build();
+    let mut checked = 0;
+    let mut failures = Vec::new();
+
+    for (category, entries) in load_categories(json_dir)? {
+        for (link, entry) in entries {
+            let size = serde_json::from_value::<PackageSize>(entry["size"].clone()).ok().and_then(|size| size.bytes());
+            let package_url = resolve(&link, base.as_ref(), None);
+            let cover_url = entry.get("cover_url").and_then(JsonValue::as_str).map(|cover_url| {
+                (cover_url, resolve(cover_url, base.as_ref(), package_url.as_ref().ok()))
+            });
+
+            for (field, link, url, size) in [("url", link.as_str(), package_url.clone(), size)].into_iter()
+                .chain(cover_url.map(|(link, url)| ("cover_url", link, url, None))) {
+                checked += 1;
+                let result = url.and_then(|url| check_url(&agent, &url, size).map_err(|e| format!("{} ({})", e, url)));
+                match result {
+                    Ok(()) => debug!("OK {}", link),
+                    Err(problem) => failures.push(Failure {
+                        category: category.clone(), field, url: link.to_string(), problem,
+                    }),
+                }
+            }
+        }
+    }
+
+    if as_json {
+        let failed: Vec<JsonValue> = failures.iter().map(|failure| json!({
+            "category": failure.category, "field": failure.field, "url": failure.url, "problem": failure.problem,
+        })).collect();
+        println!("{}", serde_json::to_string_pretty(&json!({"checked": checked, "failures": failed}))?);
+    } else {
+        for failure in &failures {
+            println!("FAIL [{}] {} {}: {}", failure.category, failure.field, failure.url, failure.problem);
+        }
+        println!("Checked {} URLs: {} failed", checked, failures.len());
+    }
+
+    if !failures.is_empty() {
+        bail!("{} of {} URLs failed validation", failures.len(), checked);
+    }
+    Ok(())
+}
@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use log::{debug, warn};
+
+use crate::sfo_processor::SFOProcessor;
+use crate::utils::{extract_string, read_u16_be, read_u32_be};
+
+// "\x7FPKG": the PS3/PSP/Vita package format; PS4 packages use "\x7FCNT"
+const PKG_MAGIC: u32 = 0x7F50_4B47;
+const PKG_TYPE_PSP_VITA: u16 = 2;
+const CONTENT_ID_POS: usize = 0x30;
+const HEADER_SIZE: usize = 0x80;
+// Metadata packet holding the content type
+const METADATA_CONTENT_TYPE: u32 = 0x2;
+const CONTENT_TYPE_VITA_DLC: u32 = 0x16;
+// Upper bound for `sce_sys` entries read from a .vpk; the declared size can't be trusted
+const ENTRY_READ_LIMIT: u64 = 16 * 1024 * 1024;
+
+/// Metadata read from a PS Vita package: an installable `.vpk` archive or a retail `.pkg`.
+pub struct VitaPackage {
+    pub content_id: String,
+    /// Parsed `sce_sys/param.sfo`; retail packages keep it encrypted, so it is only available for `.vpk`
+    pub sfo: Option<HashMap<String, String>>,
+    /// SFO category derived from the package header, when it has one
+    pub category: Option<&'static str>,
+    icon: Option<Vec<u8>>,
+}
+
+impl VitaPackage {
+    pub fn open(path: &Path) -> Result<Self> {
+        if is_vpk(path) {
+            Self::from_vpk(path)
+        } else {
+            Self::from_pkg(path)
+        }
+        .with_context(|| format!("Failed to parse Vita package '{}'", path.display()))
+    }
+
+    fn from_vpk(path: &Path) -> Result<Self> {
+        let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+        let mut read_entry = |name: &str| -> Option<Vec<u8>> {
+            let entry = archive.by_name(name).ok()?;
+            if entry.size() > ENTRY_READ_LIMIT {
+                warn!("Skipping oversized '{}' in '{}': {} bytes", name, path.display(), entry.size());
+                return None;
+            }
+            let mut data = Vec::new();
+            entry.take(ENTRY_READ_LIMIT).read_to_end(&mut data).ok()?;
+            Some(data)
+        };
+        let sfo_data = read_entry("sce_sys/param.sfo");
+        let icon = read_entry("sce_sys/icon0.png");
+
+        let sfo = sfo_data.map(|data| SFOProcessor::new().process(data)).transpose()?;
+        let content_id = sfo.as_ref().and_then(|sfo| sfo.get("CONTENT_ID")).cloned().unwrap_or_default();
+        debug!("Read Vita archive '{}' (content id '{}')", path.display(), content_id);
+        Ok(VitaPackage { content_id, sfo, category: None, icon })
+    }
+
+    fn from_pkg(path: &Path) -> Result<Self> {
+        let mut file = File::open(path)?;
+        let mut header = [0u8; HEADER_SIZE];
+        file.read_exact(&mut header)?;
+        let mut cursor = &header[..];
+        if read_u32_be(&mut cursor)? != PKG_MAGIC {
+            bail!("Not a Vita package");
+        }
+        let _revision = read_u16_be(&mut cursor)?;
+        if read_u16_be(&mut cursor)? != PKG_TYPE_PSP_VITA {
+            bail!("Not a PSP/Vita package");
+        }
+        let metadata_offset = read_u32_be(&mut cursor)?;
+        let metadata_count = read_u32_be(&mut cursor)?;
+        let content_id = extract_string(&header, CONTENT_ID_POS);
+
+        // Metadata packets are stored unencrypted as (id, size, data) records
+        file.seek(SeekFrom::Start(metadata_offset as u64))?;
+        let mut category = None;
+        for _ in 0..metadata_count {
+            let id = read_u32_be(&mut file)?;
+            let size = read_u32_be(&mut file)?;
+            if id == METADATA_CONTENT_TYPE && size >= 4 {
+                let content_type = read_u32_be(&mut file)?;
+                category = Some(if content_type == CONTENT_TYPE_VITA_DLC { "ac" } else { "gd" });
+                break;
+            }
+            file.seek(SeekFrom::Current(size as i64))?;
+        }
+        debug!("Read Vita package header '{}' (content id '{}')", path.display(), content_id);
+        Ok(VitaPackage { content_id, sfo: None, category, icon: None })
+    }
+
+    pub fn icon(&self) -> Result<Vec<u8>> {
+        self.icon.clone().context("File not found: icon0.png")
+    }
+}
+
+fn is_vpk(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("vpk"))
+}
+
+/// Whether `path` is a Vita package: a `.vpk`, or a `.pkg` with the PSP/Vita header.
+pub fn is_vita_package(path: &Path) -> bool {
+    if is_vpk(path) {
+        return true;
+    }
+    let mut header = [0u8; 8];
+    let Ok(mut file) = File::open(path) else { return false };
+    if file.read_exact(&mut header).is_err() {
+        return false;
+    }
+    u32::from_be_bytes([header[0], header[1], header[2], header[3]]) == PKG_MAGIC
+        && u16::from_be_bytes([header[6], header[7]]) == PKG_TYPE_PSP_VITA
+}
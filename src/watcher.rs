@@ -1,8 +1,30 @@
-use anyhow::{Result, Context};
+use anyhow::{bail, Result, Context};
 use log::{info, warn, error, debug}; // Added debug import
 use notify::{Config, RecommendedWatcher, RecursiveMode, Watcher as NotifyWatcher};
-use std::path::PathBuf;
-use std::sync::mpsc::{channel, Receiver};
+use std::future::Future;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use crate::json_builder::{is_gzip_package_file, is_incomplete_package, package_categories};
+use crate::regenerate::Regenerator;
+use crate::webhook::Webhook;
+
+// Consecutive backend errors after which the watcher is considered broken and re-created
+const MAX_CONSECUTIVE_ERRORS: u32 = 5;
+// Delay before the first restart, doubled per failed attempt up to the maximum
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+// A watcher that ran at least this long resets the backoff when it fails
+const HEALTHY_RUN: Duration = Duration::from_secs(60);
+// Interval at which packages found incomplete (still being downloaded or copied) are checked again once
+// their size stopped changing
+const INCOMPLETE_RETRY: Duration = Duration::from_secs(10);
+// Longest a wait for events blocks without yielding to the runtime
+const EVENT_WAIT_SLICE: Duration = Duration::from_millis(500);
 
 /// Watches filesystem changes in specified directories recursively.
 ///
@@ -10,11 +32,15 @@ use std::sync::mpsc::{channel, Receiver};
 pub struct Watcher {
     _watcher: RecommendedWatcher, // Keeps the watcher alive
     receiver: Receiver<notify::Result<notify::Event>>, // Receives filesystem events
+    roots: Vec<PathBuf>, // Watched directories; losing one stops the watcher
+    excluded: Vec<PathBuf>, // Directories inside the roots whose changes don't trigger regeneration
 }
 
 impl Watcher {
     /// Initializes a new watcher for the specified directories.
-    pub fn new(dirs: Vec<PathBuf>) -> Result<Self> {
+    ///
+    /// Changes below `excluded` directories (e.g. generated output inside a watched root) are ignored by `run_with_generate`.
+    pub fn new(dirs: Vec<PathBuf>, excluded: Vec<PathBuf>) -> Result<Self> {
         let (sender, receiver) = channel();
         let mut watcher = RecommendedWatcher::new(
             move |res| { let _ = sender.send(res); },
@@ -23,25 +49,75 @@ impl Watcher {
                 .with_compare_contents(false),
         ).context("Failed to create filesystem watcher")?;
 
+        let mut roots = Vec::new();
         for dir in dirs {
             if dir.exists() && dir.is_dir() {
                 watcher.watch(&dir, RecursiveMode::Recursive)
                     .with_context(|| format!("Failed to watch directory: {:?}", dir))?;
                 info!("Watching directory: {:?}", dir);
+                roots.push(dir);
             } else {
                 warn!("Skipping invalid path: {:?}", dir);
             }
         }
+        if roots.is_empty() {
+            bail!("No directory to watch");
+        }
+
+        Ok(Watcher { _watcher: watcher, receiver, roots, excluded })
+    }
+
+    /// Waits for the next event without tying up a runtime worker, so other tasks keep running.
+    async fn next_event(&mut self) -> Result<notify::Result<notify::Event>, RecvTimeoutError> {
+        self.next_event_timeout(Duration::MAX).await
+    }
+
+    /// Like `next_event`, but gives up after `timeout`.
+    ///
+    /// Blocks in short slices, yielding in between, so an aborted watcher task ends promptly (e.g. on shutdown).
+    async fn next_event_timeout(&mut self, timeout: Duration) -> Result<notify::Result<notify::Event>, RecvTimeoutError> {
+        let deadline = Instant::now().checked_add(timeout);
+        loop {
+            let slice = deadline.map_or(EVENT_WAIT_SLICE, |deadline| deadline.saturating_duration_since(Instant::now()).min(EVENT_WAIT_SLICE));
+            match tokio::task::block_in_place(|| self.receiver.recv_timeout(slice)) {
+                Err(RecvTimeoutError::Timeout) if deadline.is_none_or(|deadline| Instant::now() < deadline) => {
+                    tokio::task::yield_now().await;
+                }
+                result => return result,
+            }
+        }
+    }
 
-        Ok(Watcher { _watcher: watcher, receiver })
+    /// Fails when a watched directory itself disappeared (e.g. an unmounted share), which ends its events.
+    fn check_roots(&self, event_result: &notify::Result<notify::Event>) -> Result<()> {
+        if let Ok(event) = event_result {
+            if event.kind.is_remove() {
+                if let Some(root) = self.roots.iter().find(|root| event.paths.contains(root) || !root.is_dir()) {
+                    bail!("Watched directory {:?} was removed", root);
+                }
+            }
+        }
+        Ok(())
     }
 
-    /// Runs the watcher indefinitely, logging filesystem events.
-    pub async fn run(self) -> Result<()> {
-        while let Ok(event_result) = self.receiver.recv() {
+    /// Runs the watcher, logging filesystem events and posting created packages to `webhook`, if set.
+    ///
+    /// Only returns (with an error) when the event channel closes or the backend keeps failing.
+    pub async fn run(mut self, webhook: Option<Webhook>) -> Result<()> {
+        let mut errors = ErrorCount::default();
+        while let Ok(event_result) = self.next_event().await {
+            errors.record(&event_result)?;
+            self.check_roots(&event_result)?;
             match event_result {
                 Ok(event) => match event.kind {
-                    notify::EventKind::Create(_) => info!("File created: {:?}", event.paths),
+                    notify::EventKind::Create(_) => {
+                        info!("File created: {:?}", event.paths);
+                        if let Some(webhook) = &webhook {
+                            for path in &event.paths {
+                                webhook.package_created(path).await;
+                            }
+                        }
+                    }
                     notify::EventKind::Modify(_) => info!("File modified: {:?}", event.paths),
                     notify::EventKind::Remove(_) => info!("File removed: {:?}", event.paths),
                     notify::EventKind::Access(_) => info!("File accessed: {:?}", event.paths),
@@ -50,24 +126,77 @@ impl Watcher {
                 Err(e) => error!("Watcher error: {:?}", e),
             }
         }
-        error!("Watcher channel closed");
-        Ok(())
+        bail!("Watcher channel closed")
     }
 
     /// Runs the watcher and re-runs generate on filesystem events.
-    pub async fn run_with_generate(self, args: crate::args::GenerateArgs) -> Result<()> {
-        while let Ok(event_result) = self.receiver.recv() {
+    ///
+    /// Events already queued when a regeneration starts are folded into that run.
+    /// Changed packages that are still incomplete (e.g. mid-download) are checked again when their size
+    /// settles or they are closed after writing, and regenerated once complete, since not every copy
+    /// method fires another event when it finishes.
+    /// With `categories` (`--watch-categories`), changes to packages of other categories are ignored and
+    /// regenerations only write the files of those categories.
+    /// Like `run`, only returns with an error.
+    pub async fn run_with_generate(mut self, regenerator: Arc<Regenerator>, categories: Option<BTreeSet<String>>) -> Result<()> {
+        let mut errors = ErrorCount::default();
+        let mut incomplete = Incomplete::new();
+        loop {
+            let event_result = if incomplete.is_empty() {
+                let Ok(event_result) = self.next_event().await else { break };
+                event_result
+            } else {
+                match self.next_event_timeout(INCOMPLETE_RETRY).await {
+                    Ok(event_result) => event_result,
+                    Err(RecvTimeoutError::Timeout) => {
+                        retry_incomplete(&regenerator, categories.as_ref(), &mut incomplete).await;
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            };
+            errors.record(&event_result)?;
+            self.check_roots(&event_result)?;
             match event_result {
                 Ok(event) => {
                     match event.kind {
+                        notify::EventKind::Create(_) | notify::EventKind::Modify(_) | notify::EventKind::Remove(_)
+                            if event.paths.iter().all(|path| self.excluded.iter().any(|dir| path.starts_with(dir))) => {
+                            debug!("Ignoring change in excluded output directory: {:?}", event.paths);
+                        }
                         notify::EventKind::Create(_) | notify::EventKind::Modify(_) | notify::EventKind::Remove(_) => {
                             debug!("Filesystem event triggering regeneration: {:?}", event);
-                            if let Err(e) = crate::run_generate(args.clone()).await {
-                                error!("Failed to regenerate JSON files: {:?}", e);
-                            } else {
-                                info!("Regenerated JSON files due to filesystem change");
+                            let mut changed: BTreeSet<PathBuf> = event.paths.into_iter().collect();
+                            let collapsed: Vec<_> = self.receiver.try_iter().collect();
+                            if !collapsed.is_empty() {
+                                debug!("Collapsed {} queued events into this regeneration", collapsed.len());
+                            }
+                            changed.extend(collapsed.into_iter().filter_map(Result::ok).flat_map(|event| event.paths));
+                            changed.retain(|path| !self.excluded.iter().any(|dir| path.starts_with(dir)));
+                            if let Some(categories) = categories.as_ref().filter(|categories| !affects_categories(&regenerator, &changed, categories)) {
+                                debug!("Ignoring change outside the watched categories {:?}: {:?}", categories, changed);
+                                continue;
+                            }
+                            match regenerate(&regenerator, categories.as_ref()).await {
+                                Some(Err(e)) => error!("Failed to regenerate JSON files: {:?}", e),
+                                Some(Ok(_)) => info!("Regenerated JSON files due to filesystem change"),
+                                None => debug!("Regeneration already in progress; change will be picked up"),
+                            }
+                            for path in changed {
+                                track_changed(&regenerator, &mut incomplete, path);
                             }
                         }
+                        notify::EventKind::Access(notify::event::AccessKind::Close(notify::event::AccessMode::Write))
+                            if event.paths.iter().any(|path| incomplete.contains_key(path)) => {
+                            debug!("Incomplete package closed after writing: {:?}", event.paths);
+                            // Writing is done, so check it without waiting for its size to settle
+                            for path in &event.paths {
+                                if let Some((seen, _)) = incomplete.get_mut(path) {
+                                    *seen = fs::metadata(path).map_or(0, |metadata| metadata.len());
+                                }
+                            }
+                            retry_incomplete(&regenerator, categories.as_ref(), &mut incomplete).await;
+                        }
                         notify::EventKind::Access(_) => {
                             debug!("File accessed event ignored: {:?}", event.paths);
                         }
@@ -77,7 +206,237 @@ impl Watcher {
                 Err(e) => error!("Watcher error: {:?}", e),
             }
         }
-        error!("Watcher channel closed");
+        bail!("Watcher channel closed")
+    }
+}
+
+/// Packages found incomplete, with the size they were last seen at and the size they were last checked at.
+type Incomplete = BTreeMap<PathBuf, (u64, u64)>;
+
+// Checked size of `.pkg.gz` packages not checked yet, which no file has
+const UNCHECKED: u64 = u64::MAX;
+
+/// Adds `path`, changed by a filesystem event, to `incomplete` when it is a package still being written.
+///
+/// A `.pkg.gz` is only told complete by decompressing all of it, which at every event of a long copy would
+/// redo ever more work; it is added unchecked instead, for `retry_incomplete` to check once its size settles.
+fn track_changed(regenerator: &Regenerator, incomplete: &mut Incomplete, path: PathBuf) {
+    let size = || fs::metadata(&path).map_or(0, |metadata| metadata.len());
+    if is_gzip_package_file(regenerator.args(), &path) {
+        if !incomplete.contains_key(&path) {
+            debug!("Checking gzip package {:?} once its size has settled", path);
+            incomplete.insert(path.clone(), (size(), UNCHECKED));
+        }
+    } else if tokio::task::block_in_place(|| is_incomplete_package(regenerator.args(), &path)) {
+        if incomplete.insert(path.clone(), (size(), size())).is_none() {
+            info!("Package {:?} is incomplete, probably still being written; indexing it once complete", path);
+        }
+    } else {
+        incomplete.remove(&path);
+    }
+}
+
+/// Checks those `incomplete` packages again whose size has settled since the last call at a size they
+/// were not checked at yet, regenerating when any of them has been completed.
+///
+/// Packages that disappeared are dropped without regenerating; their removal already triggered a run.
+/// With `categories`, completed packages of other categories are dropped without regenerating as well.
+async fn retry_incomplete(regenerator: &Regenerator, categories: Option<&BTreeSet<String>>, incomplete: &mut Incomplete) {
+    let mut completed = Vec::new();
+    incomplete.retain(|path, (seen, checked)| {
+        let Ok(size) = fs::metadata(path).map(|metadata| metadata.len()) else { return false };
+        let settled = size == *seen;
+        *seen = size;
+        // Still growing, or stalled at a size already found incomplete
+        if !settled || size == *checked {
+            return true;
+        }
+        *checked = size;
+        if tokio::task::block_in_place(|| is_incomplete_package(regenerator.args(), path)) {
+            return true;
+        }
+        completed.push(path.clone());
+        false
+    });
+    if completed.is_empty() {
+        return;
+    }
+    if let Some(categories) = categories.filter(|categories| !affects_categories(regenerator, &completed, categories)) {
+        debug!("Ignoring completed packages outside the watched categories {:?}: {:?}", categories, completed);
+        return;
+    }
+    info!("Indexing completed packages: {:?}", completed);
+    match regenerate(regenerator, categories).await {
+        Some(Err(e)) => error!("Failed to regenerate JSON files: {:?}", e),
+        Some(Ok(_)) => info!("Regenerated JSON files for completed packages"),
+        None => debug!("Regeneration already in progress; completed packages will be picked up"),
+    }
+}
+
+/// Whether changes to `paths` can affect the files of the watched `categories`: one of them is a package of a
+/// watched category, or a path whose category cannot be told (a removed package, a directory, a sidecar file).
+fn affects_categories<'a>(regenerator: &Regenerator, paths: impl IntoIterator<Item = &'a PathBuf>,
+                          categories: &BTreeSet<String>) -> bool {
+    paths.into_iter().any(|path| {
+        match tokio::task::block_in_place(|| package_categories(regenerator.args(), path)) {
+            Some(found) => !found.is_disjoint(categories),
+            None => true,
+        }
+    })
+}
+
+/// Triggers a regeneration, writing only the files of `categories` when set.
+async fn regenerate(regenerator: &Regenerator, categories: Option<&BTreeSet<String>>) -> Option<Result<crate::CategoryCounts>> {
+    match categories {
+        Some(categories) => regenerator.trigger_categories(categories.clone()).await,
+        None => regenerator.trigger().await,
+    }
+}
+
+/// Counts consecutive backend errors, failing once `MAX_CONSECUTIVE_ERRORS` is reached.
+#[derive(Default)]
+struct ErrorCount(u32);
+
+impl ErrorCount {
+    fn record(&mut self, event_result: &notify::Result<notify::Event>) -> Result<()> {
+        match event_result {
+            Ok(_) => self.0 = 0,
+            Err(e) => {
+                self.0 += 1;
+                if self.0 >= MAX_CONSECUTIVE_ERRORS {
+                    bail!("{} consecutive watcher errors, last: {}", self.0, e);
+                }
+            }
+        }
         Ok(())
     }
 }
+
+/// Keeps a watcher over `dirs` running: `run` is called with a fresh `Watcher` (and whether this is a restart),
+/// and whenever creating or running it fails the watcher is re-created after an exponential backoff.
+///
+/// This keeps watching across transient failures such as a hit inotify watch limit or a network mount blip.
+pub async fn supervise<F, Fut>(dirs: Vec<PathBuf>, excluded: Vec<PathBuf>, mut run: F) -> Result<()>
+where
+    F: FnMut(Watcher, bool) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let mut backoff = INITIAL_BACKOFF;
+    let mut restarted = false;
+    loop {
+        let started = Instant::now();
+        let result = match Watcher::new(dirs.clone(), excluded.clone()) {
+            Ok(watcher) => run(watcher, restarted).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            error!("Watcher stopped: {:#}", e);
+        }
+        if started.elapsed() >= HEALTHY_RUN {
+            backoff = INITIAL_BACKOFF;
+        }
+        warn!("Restarting watcher in {:?}", backoff);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+        restarted = true;
+    }
+}
+
+/// Returns those of `dirs` that lie inside `root`, warning about each.
+///
+/// Output written there (icons, JSON) would otherwise trigger regeneration, which writes it again, in a loop.
+pub fn nested_dirs(root: &Path, dirs: &[&Path]) -> Vec<PathBuf> {
+    let Ok(root) = root.canonicalize() else { return Vec::new() };
+    dirs.iter()
+        .filter_map(|dir| dir.canonicalize().ok())
+        .filter(|dir| dir.starts_with(&root))
+        .inspect(|dir| warn!("Output directory {:?} is inside the watched packages directory {:?}; ignoring its changes", dir, root))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{game, generate_args, write_library};
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn indexed_links(out: &Path) -> Vec<String> {
+        let Ok(data) = fs::read(out.join("games.json")) else { return Vec::new() };
+        let document: serde_json::Value = serde_json::from_slice(&data).unwrap();
+        document["DATA"].as_object().unwrap().keys().cloned().collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn indexes_an_incomplete_package_once_it_has_grown_complete() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = game("CUSA00001");
+        let library = write_library(dir.path(), &[("game.pkg", data[..0x180].to_vec())]);
+        let (path, out) = (library.join("game.pkg"), dir.path().join("out"));
+        let regenerator = Regenerator::new(generate_args(&library, &out, &[]));
+        assert!(is_incomplete_package(regenerator.args(), &path));
+        let mut incomplete = Incomplete::from([(path.clone(), (0x180, 0x180))]);
+
+        // Unchanged since it was found incomplete
+        retry_incomplete(&regenerator, None, &mut incomplete).await;
+        assert!(incomplete.contains_key(&path));
+
+        // Grown, but its param.sfo still ends past the end of the file once its size settles
+        fs::write(&path, &data[..0x290]).unwrap();
+        retry_incomplete(&regenerator, None, &mut incomplete).await;
+        retry_incomplete(&regenerator, None, &mut incomplete).await;
+        assert!(incomplete.contains_key(&path));
+        assert!(indexed_links(&out).is_empty());
+
+        // Complete: indexed once its size has settled
+        fs::write(&path, &data).unwrap();
+        retry_incomplete(&regenerator, None, &mut incomplete).await;
+        assert!(incomplete.contains_key(&path));
+        retry_incomplete(&regenerator, None, &mut incomplete).await;
+        assert!(incomplete.is_empty());
+        assert_eq!(indexed_links(&out), ["http://localhost:8000/packages/game.pkg"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn drops_incomplete_packages_that_disappear() {
+        let dir = tempfile::tempdir().unwrap();
+        let library = write_library(dir.path(), &[("game.pkg", game("CUSA00001")[..0x180].to_vec())]);
+        let (path, out) = (library.join("game.pkg"), dir.path().join("out"));
+        let regenerator = Regenerator::new(generate_args(&library, &out, &[]));
+        let mut incomplete = Incomplete::from([(path.clone(), (0x180, 0x180))]);
+
+        fs::remove_file(&path).unwrap();
+        retry_incomplete(&regenerator, None, &mut incomplete).await;
+        assert!(incomplete.is_empty());
+        assert!(!out.exists());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn checks_gzip_packages_once_their_size_has_settled() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = gzip(&game("CUSA00001"));
+        let library = write_library(dir.path(), &[("game.pkg.gz", data[..data.len() / 2].to_vec())]);
+        let (path, out) = (library.join("game.pkg.gz"), dir.path().join("out"));
+        let regenerator = Regenerator::new(generate_args(&library, &out, &[]));
+        assert!(is_incomplete_package(regenerator.args(), &path));
+
+        // Tracked unchecked at every event while it is written, and checked at the first retry that finds it settled
+        let mut incomplete = Incomplete::new();
+        track_changed(&regenerator, &mut incomplete, path.clone());
+        assert_eq!(incomplete[&path], (data.len() as u64 / 2, UNCHECKED));
+        retry_incomplete(&regenerator, None, &mut incomplete).await;
+        assert_eq!(incomplete[&path], (data.len() as u64 / 2, data.len() as u64 / 2));
+
+        fs::write(&path, &data).unwrap();
+        track_changed(&regenerator, &mut incomplete, path.clone());
+        retry_incomplete(&regenerator, None, &mut incomplete).await;
+        assert!(incomplete.contains_key(&path));
+        retry_incomplete(&regenerator, None, &mut incomplete).await;
+        assert!(incomplete.is_empty());
+        assert_eq!(indexed_links(&out), ["http://localhost:8000/packages/game.pkg"]);
+    }
+}
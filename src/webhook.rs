@@ -0,0 +1,148 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use tokio::sync::Mutex;
+use tokio::task;
+use walkdir::WalkDir;
+
+use crate::args::GenerateArgs;
+use crate::entry::document_entries;
+use crate::json_builder::local_package_path;
+use crate::ps4_package::{is_gzip_package, PS4Package};
+use crate::sfo_processor::SFOProcessor;
+use crate::signing::strip_signature;
+use crate::split::is_split_package;
+
+// How long one notification may take before it is given up on
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// JSON body POSTed to `--notify-webhook` for each new package.
+#[derive(Serialize)]
+struct Payload {
+    event: &'static str,
+    path: String,
+    title: Option<String>,
+    /// Download URL, known once the package is indexed (`host` mode)
+    url: Option<String>,
+}
+
+/// Posts `--notify-webhook` notifications about newly added packages.
+#[derive(Clone)]
+pub struct Webhook {
+    url: String,
+    agent: ureq::Agent,
+}
+
+impl Webhook {
+    pub fn new(url: String) -> Self {
+        let agent = ureq::AgentBuilder::new().timeout(WEBHOOK_TIMEOUT).build();
+        Webhook { url, agent }
+    }
+
+    /// Notifies about a package file created under a watched directory (`watch` mode); other files are ignored.
+    ///
+    /// The title is read from the package's param.sfo when it already parses.
+    pub async fn package_created(&self, path: &Path) {
+        if !is_package_path(path) {
+            debug!("Not notifying about non-package file '{}'", path.display());
+            return;
+        }
+        let package = path.to_path_buf();
+        let title = task::spawn_blocking(move || package_title(&package)).await.ok().flatten();
+        self.post(Payload { event: "package_added", path: path.display().to_string(), title, url: None }).await;
+    }
+
+    /// Posts `payload`, only logging failures so a broken webhook never stops the watcher.
+    async fn post(&self, payload: Payload) {
+        let (agent, url) = (self.agent.clone(), self.url.clone());
+        let path = payload.path.clone();
+        let sent = task::spawn_blocking(move || {
+            let body = serde_json::to_string(&payload).map_err(|e| e.to_string())?;
+            agent.post(&url).set("Content-Type", "application/json").send_string(&body).map_err(|e| e.to_string())
+        }).await;
+        match sent {
+            Ok(Ok(_)) => info!("Sent webhook notification for '{}'", path),
+            Ok(Err(e)) => warn!("Webhook notification for '{}' failed: {}", path, e),
+            Err(e) => warn!("Webhook notification for '{}' failed: {}", path, e),
+        }
+    }
+}
+
+/// Notifies a `Webhook` of entries that appear in the generated JSON files from one regeneration to the next.
+pub struct EntryNotifier {
+    args: GenerateArgs,
+    webhook: Webhook,
+    /// Package URLs seen after the previous regeneration; `None` until the first one
+    known: Mutex<Option<HashSet<String>>>,
+}
+
+impl EntryNotifier {
+    pub fn new(args: GenerateArgs, webhook: Webhook) -> Self {
+        EntryNotifier { args, webhook, known: Mutex::new(None) }
+    }
+
+    /// Posts each entry that was not generated by the previous call.
+    ///
+    /// The first call only records the entries present, so existing packages are not announced on startup.
+    pub async fn regenerated(&self) {
+        let out_dir = self.args.out.0.clone();
+        let entries = match task::spawn_blocking(move || generated_entries(&out_dir)).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Failed to read generated entries for webhook notifications: {}", e);
+                return;
+            }
+        };
+
+        let mut known = self.known.lock().await;
+        let links: HashSet<String> = entries.iter().map(|(link, _)| link.clone()).collect();
+        let Some(previous) = known.replace(links) else {
+            debug!("Recorded {} entries as the webhook notification baseline", entries.len());
+            return;
+        };
+        for (link, title) in entries.into_iter().filter(|(link, _)| !previous.contains(link)) {
+            let path = local_package_path(&self.args, &link).map_or_else(|| link.clone(), |path| path.display().to_string());
+            self.webhook.post(Payload { event: "package_added", path, title, url: Some(link) }).await;
+        }
+    }
+}
+
+/// Package URLs (without `--sign-secret` signatures, which change over time) and names of every entry in the generated category JSON files under `out_dir`.
+fn generated_entries(out_dir: &Path) -> Vec<(String, Option<String>)> {
+    let mut entries = Vec::new();
+    for entry in WalkDir::new(out_dir).into_iter().filter_map(Result::ok) {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let Ok(json @ JsonValue::Object(_)) = fs::read(path).map_err(anyhow::Error::from)
+            .and_then(|data| serde_json::from_slice(&data).map_err(anyhow::Error::from)) else {
+            debug!("Skipping unreadable JSON for webhook notifications: {}", path.display());
+            continue;
+        };
+        let Some(data) = document_entries(&json) else { continue };
+        entries.extend(data.iter().map(|(link, package)| {
+            (strip_signature(link), package.get("name").and_then(JsonValue::as_str).map(str::to_string))
+        }));
+    }
+    entries
+}
+
+/// Whether `path` is a file named like a package (`.pkg`, `.fpkg`, `.vpk`, `.pkg.gz` or a first `.pkg.0` part).
+fn is_package_path(path: &Path) -> bool {
+    let is_package_ext = path.extension()
+        .is_some_and(|ext| ["pkg", "fpkg", "vpk"].iter().any(|known| ext.eq_ignore_ascii_case(known)));
+    path.is_file() && (is_package_ext || is_gzip_package(path) || is_split_package(path))
+}
+
+/// Title from the param.sfo of the PS4 package at `path`, if it can be read.
+fn package_title(path: &Path) -> Option<String> {
+    let mut pkg = PS4Package::new(PathBuf::from(path)).ok()?;
+    let sfo_data = SFOProcessor::new().read(&mut pkg, "param.sfo").ok()?;
+    sfo_data.get("TITLE").cloned()
+}